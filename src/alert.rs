@@ -0,0 +1,415 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use crate::config::{AlertMetric, AlertRule, AlertsConfig, IpVersion};
+use crate::probe::{ProbeData, ProbeOutcome};
+
+/// Time after which a webhook request is given up.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies a probe: probe type, target, label and the IP version it is pinned to.
+type ProbeKey = (&'static str, String, String, Option<IpVersion>);
+
+/// Results of a probe, as far back as the longest rule looks.
+struct History {
+    first_seen: DateTime<Utc>,
+    outcomes: VecDeque<ProbeOutcome>,
+}
+
+/// An alert that is currently firing.
+struct Firing {
+    since: DateTime<Utc>,
+    last_notified: DateTime<Utc>,
+}
+
+/// Evaluates alert rules over the recent results of every probe and sends a
+/// notification when an alert starts firing, is still firing after the
+/// re-notify interval, or resolves.
+pub struct Alerter {
+    rules: Vec<AlertRule>,
+    renotify_interval: chrono::Duration,
+    history: HashMap<ProbeKey, History>,
+    /// Firing alerts by rule index and probe.
+    firing: HashMap<(usize, ProbeKey), Firing>,
+    /// Read by the webhook task, which runs as long as the process, so sends can't fail.
+    notifications: mpsc::UnboundedSender<serde_json::Value>,
+}
+
+impl Alerter {
+    /// Starts delivering notifications to the webhook in the background.
+    pub fn spawn(config: &AlertsConfig, http_client: &reqwest::Client) -> Alerter {
+        if config.rules.is_empty() {
+            warn!("An alert webhook is set but there are no alert rules");
+        }
+        let (notifications, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(deliver(http_client.clone(), config.webhook_url.clone(), receiver));
+        Alerter {
+            rules: config.rules.clone(),
+            renotify_interval: chrono::Duration::from_std(config.renotify_interval).unwrap_or(chrono::Duration::MAX),
+            history: HashMap::new(),
+            firing: HashMap::new(),
+            notifications,
+        }
+    }
+
+    /// Records a probe outcome and evaluates the rules that apply to the probe.
+    pub fn observe(&mut self, outcome: &ProbeOutcome) {
+        let key = (outcome.kind, outcome.target.clone(), outcome.label.clone(), outcome.ip_version);
+        let now = outcome.time;
+        let longest = self.rules.iter().map(|rule| rule.for_secs).max().unwrap_or(0);
+
+        let history = self.history.entry(key.clone()).or_insert_with(|| History {
+            first_seen: now,
+            outcomes: VecDeque::new(),
+        });
+        history.outcomes.push_back(outcome.clone());
+        let cutoff = now - chrono::Duration::seconds(longest as i64);
+        while history.outcomes.len() > 1 && history.outcomes.front().is_some_and(|oldest| oldest.time < cutoff) {
+            history.outcomes.pop_front();
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.probe.as_deref().is_some_and(|probe| probe != outcome.kind)
+                || rule.label.as_deref().is_some_and(|label| label != outcome.label) {
+                continue;
+            }
+
+            // The condition has to hold for the whole period, wait until we've seen all of it
+            let period = chrono::Duration::seconds(rule.for_secs as i64);
+            if now - history.first_seen < period {
+                continue;
+            }
+            let recent: Vec<_> = history.outcomes
+                .iter()
+                .filter(|outcome| outcome.time >= now - period)
+                .collect();
+            // Without data, e.g. no replies to measure latency on during an outage, there is
+            // nothing to judge and the alert keeps its state. `probe_down` covers total loss.
+            let Some(value) = evaluate(rule.metric, &recent) else { continue };
+            let breached = match rule.metric {
+                AlertMetric::ProbeDown => value > 0.0,
+                _ => value > rule.threshold.unwrap_or(f64::INFINITY),
+            };
+
+            let state_key = (index, key.clone());
+            match (breached, self.firing.get_mut(&state_key)) {
+                (true, None) => {
+                    warn!("Alert {} is firing for {} probe {}", rule.name, outcome.kind, outcome.display_label());
+                    self.firing.insert(state_key, Firing { since: now, last_notified: now });
+                    let _ = self.notifications.send(notification(rule, outcome, "firing", now, value));
+                }
+                (true, Some(firing)) if now - firing.last_notified >= self.renotify_interval => {
+                    firing.last_notified = now;
+                    let since = firing.since;
+                    let _ = self.notifications.send(notification(rule, outcome, "firing", since, value));
+                }
+                (false, Some(_)) => {
+                    let firing = self.firing.remove(&state_key).expect("firing alert");
+                    info!("Alert {} resolved for {} probe {}", rule.name, outcome.kind, outcome.display_label());
+                    let _ = self.notifications.send(notification(rule, outcome, "resolved", firing.since, value));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The JSON document POSTed to the webhook.
+fn notification(rule: &AlertRule, outcome: &ProbeOutcome, status: &str, since: DateTime<Utc>, value: f64) -> serde_json::Value {
+    let summary = match (rule.metric, status) {
+        (AlertMetric::ProbeDown, "firing") => format!("{} probe {} is down", outcome.kind, outcome.display_label()),
+        (AlertMetric::ProbeDown, _) => format!("{} probe {} is up again", outcome.kind, outcome.display_label()),
+        (metric, _) => format!("{} of {} probe {} is {:.2}, threshold {}",
+                               metric_name(metric), outcome.kind, outcome.display_label(), value,
+                               rule.threshold.unwrap_or_default()),
+    };
+    json!({
+        "status": status,
+        "rule": rule.name,
+        "metric": metric_name(rule.metric),
+        "threshold": rule.threshold,
+        "value": value,
+        "probe": outcome.kind,
+        "target": outcome.target,
+        "label": outcome.label,
+        "ip_version": outcome.ip_version.map(|version| version.to_string()),
+        "since": since.to_rfc3339(),
+        "time": outcome.time.to_rfc3339(),
+        "summary": summary,
+    })
+}
+
+fn metric_name(metric: AlertMetric) -> &'static str {
+    match metric {
+        AlertMetric::LatencyP95Ms => "latency_p95_ms",
+        AlertMetric::PacketLossPct => "packet_loss_pct",
+        AlertMetric::ProbeDown => "probe_down",
+    }
+}
+
+/// Computes `metric` over `outcomes`, `None` when they don't provide it.
+fn evaluate(metric: AlertMetric, outcomes: &[&ProbeOutcome]) -> Option<f64> {
+    let pings = outcomes.iter().filter_map(|outcome| match &outcome.result {
+        Ok(ProbeData::Ping(result)) => Some(result),
+        _ => None,
+    });
+    match metric {
+        AlertMetric::LatencyP95Ms => {
+            let mut rtts: Vec<f64> = pings
+                .flat_map(|result| result.replies.iter().map(|reply| reply.rtt.as_secs_f64() * 1000.0))
+                .collect();
+            if rtts.is_empty() {
+                return None;
+            }
+            rtts.sort_by(f64::total_cmp);
+            // Nearest rank
+            let rank = (rtts.len() as f64 * 0.95).ceil() as usize;
+            Some(rtts[rank.clamp(1, rtts.len()) - 1])
+        }
+        AlertMetric::PacketLossPct => {
+            let (transmitted, received) = pings.fold((0, 0), |(transmitted, received), result| {
+                (transmitted + result.transmitted as usize, received + result.received() as usize)
+            });
+            (transmitted > 0).then(|| (transmitted - received) as f64 / transmitted as f64 * 100.0)
+        }
+        AlertMetric::ProbeDown => {
+            if outcomes.is_empty() {
+                return None;
+            }
+            Some(if outcomes.iter().any(|outcome| outcome.is_success()) { 0.0 } else { 1.0 })
+        }
+    }
+}
+
+/// POSTs notifications to the webhook one after another, so they arrive in order.
+async fn deliver(http_client: reqwest::Client, url: String, mut receiver: mpsc::UnboundedReceiver<serde_json::Value>) {
+    while let Some(notification) = receiver.recv().await {
+        if let Err(e) = post(&http_client, &url, &notification).await {
+            warn!("Failed to send alert {} to the webhook: {:#}", notification["rule"], e);
+        }
+    }
+}
+
+async fn post(http_client: &reqwest::Client, url: &str, notification: &serde_json::Value) -> Result<()> {
+    let response = http_client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(notification.to_string())
+        .timeout(WEBHOOK_TIMEOUT)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("webhook returned HTTP status {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use chrono::TimeZone;
+    use crate::icmp::{EchoReply, PingResult};
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    /// A ping run with one reply per round-trip time in `rtts_ms`, out of 4 requests.
+    fn ping(secs: i64, rtts_ms: &[u64]) -> ProbeOutcome {
+        ProbeOutcome {
+            time: at(secs),
+            kind: "ping",
+            target: "1.1.1.1".to_string(),
+            label: "cloudflare".to_string(),
+            ip_version: None,
+            result: Ok(ProbeData::Ping(PingResult {
+                addr: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+                transmitted: 4,
+                replies: rtts_ms
+                    .iter()
+                    .enumerate()
+                    .map(|(seq, rtt)| EchoReply { seq: seq as u16, rtt: Duration::from_millis(*rtt), ttl: None })
+                    .collect(),
+            })),
+        }
+    }
+
+    fn failed(secs: i64) -> ProbeOutcome {
+        ProbeOutcome { result: Err("timed out".to_string()), ..ping(secs, &[]) }
+    }
+
+    fn rule(metric: AlertMetric, threshold: Option<f64>, for_secs: u64) -> AlertRule {
+        AlertRule {
+            name: "test".to_string(),
+            metric,
+            threshold,
+            for_secs,
+            probe: None,
+            label: None,
+        }
+    }
+
+    /// An alerter without a webhook, notifications end up in the returned receiver.
+    fn alerter(rules: Vec<AlertRule>, renotify_secs: i64) -> (Alerter, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let (notifications, receiver) = mpsc::unbounded_channel();
+        let alerter = Alerter {
+            rules,
+            renotify_interval: chrono::Duration::seconds(renotify_secs),
+            history: HashMap::new(),
+            firing: HashMap::new(),
+            notifications,
+        };
+        (alerter, receiver)
+    }
+
+    fn sent(receiver: &mut mpsc::UnboundedReceiver<serde_json::Value>) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn evaluates_metrics() {
+        let runs = [ping(0, &[10, 20, 30, 40]), ping(10, &[50, 60]), failed(20)];
+        let outcomes: Vec<_> = runs.iter().collect();
+        assert_eq!(evaluate(AlertMetric::LatencyP95Ms, &outcomes), Some(60.0));
+        // 12 requests, 6 replies, the failed run sent none
+        assert_eq!(evaluate(AlertMetric::PacketLossPct, &outcomes), Some(25.0));
+        assert_eq!(evaluate(AlertMetric::ProbeDown, &outcomes), Some(0.0));
+
+        let runs = [ping(0, &[]), failed(10)];
+        let outcomes: Vec<_> = runs.iter().collect();
+        assert_eq!(evaluate(AlertMetric::LatencyP95Ms, &outcomes), None);
+        assert_eq!(evaluate(AlertMetric::PacketLossPct, &outcomes), Some(100.0));
+        assert_eq!(evaluate(AlertMetric::ProbeDown, &outcomes), Some(1.0));
+
+        let runs = [failed(0)];
+        let outcomes: Vec<_> = runs.iter().collect();
+        assert_eq!(evaluate(AlertMetric::PacketLossPct, &outcomes), None);
+        assert_eq!(evaluate(AlertMetric::ProbeDown, &[]), None);
+    }
+
+    #[test]
+    fn p95_uses_nearest_rank() {
+        let rtts: Vec<u64> = (1..=40).collect();
+        let run = ping(0, &rtts);
+        assert_eq!(evaluate(AlertMetric::LatencyP95Ms, &[&run]), Some(38.0));
+    }
+
+    #[test]
+    fn fires_renotifies_and_resolves() {
+        let (mut alerter, mut receiver) = alerter(vec![rule(AlertMetric::LatencyP95Ms, Some(50.0), 0)], 600);
+
+        alerter.observe(&ping(0, &[10, 20]));
+        assert!(sent(&mut receiver).is_empty());
+
+        alerter.observe(&ping(10, &[80, 90]));
+        let firing = sent(&mut receiver);
+        assert_eq!(firing.len(), 1);
+        assert_eq!(firing[0]["status"], "firing");
+        assert_eq!(firing[0]["value"], 90.0);
+        assert_eq!(firing[0]["since"], at(10).to_rfc3339());
+
+        // Still firing, but the re-notify interval hasn't passed yet
+        alerter.observe(&ping(300, &[80]));
+        assert!(sent(&mut receiver).is_empty());
+
+        alerter.observe(&ping(610, &[70]));
+        let renotified = sent(&mut receiver);
+        assert_eq!(renotified.len(), 1);
+        assert_eq!(renotified[0]["status"], "firing");
+        assert_eq!(renotified[0]["since"], at(10).to_rfc3339());
+        assert_eq!(renotified[0]["time"], at(610).to_rfc3339());
+
+        alerter.observe(&ping(620, &[20]));
+        let resolved = sent(&mut receiver);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0]["status"], "resolved");
+        assert_eq!(resolved[0]["value"], 20.0);
+        assert!(alerter.firing.is_empty());
+
+        alerter.observe(&ping(630, &[20]));
+        assert!(sent(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn condition_has_to_hold_for_the_whole_period() {
+        let (mut alerter, mut receiver) = alerter(vec![rule(AlertMetric::ProbeDown, None, 60)], 3600);
+
+        // Not seen for 60 seconds yet
+        alerter.observe(&failed(0));
+        alerter.observe(&failed(30));
+        assert!(sent(&mut receiver).is_empty());
+
+        // A success within the period resets the streak
+        alerter.observe(&ping(40, &[10]));
+        alerter.observe(&failed(60));
+        alerter.observe(&failed(90));
+        assert!(sent(&mut receiver).is_empty());
+
+        // The window starts at 40 and still includes the success
+        alerter.observe(&failed(100));
+        assert!(sent(&mut receiver).is_empty());
+
+        alerter.observe(&failed(110));
+        let firing = sent(&mut receiver);
+        assert_eq!(firing.len(), 1);
+        assert_eq!(firing[0]["status"], "firing");
+        assert_eq!(firing[0]["summary"], "ping probe cloudflare is down");
+
+        alerter.observe(&ping(120, &[10]));
+        let resolved = sent(&mut receiver);
+        assert_eq!(resolved[0]["status"], "resolved");
+        assert_eq!(resolved[0]["summary"], "ping probe cloudflare is up again");
+    }
+
+    #[test]
+    fn window_without_data_keeps_state() {
+        let (mut alerter, mut receiver) = alerter(vec![rule(AlertMetric::LatencyP95Ms, Some(50.0), 0)], 3600);
+
+        // Nothing to measure, nothing fires
+        alerter.observe(&ping(0, &[]));
+        assert!(sent(&mut receiver).is_empty());
+
+        alerter.observe(&ping(10, &[200]));
+        assert_eq!(sent(&mut receiver)[0]["status"], "firing");
+
+        // A total outage neither resolves the alert nor fires it again afterwards
+        alerter.observe(&ping(20, &[]));
+        alerter.observe(&failed(30));
+        assert!(sent(&mut receiver).is_empty());
+        assert_eq!(alerter.firing.len(), 1);
+        alerter.observe(&ping(40, &[150]));
+        assert!(sent(&mut receiver).is_empty());
+
+        // Resolves once there are replies below the threshold again
+        alerter.observe(&ping(50, &[20]));
+        let resolved = sent(&mut receiver);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0]["status"], "resolved");
+        assert_eq!(resolved[0]["since"], at(10).to_rfc3339());
+        assert_eq!(resolved[0]["summary"], "latency_p95_ms of ping probe cloudflare is 20.00, threshold 50");
+        assert!(alerter.firing.is_empty());
+    }
+
+    #[test]
+    fn rules_only_apply_to_matching_probes() {
+        let mut other_probe = rule(AlertMetric::ProbeDown, None, 0);
+        other_probe.probe = Some("tcp".to_string());
+        let mut other_label = rule(AlertMetric::ProbeDown, None, 0);
+        other_label.label = Some("google".to_string());
+        let mut matching = rule(AlertMetric::ProbeDown, None, 0);
+        matching.probe = Some("ping".to_string());
+        matching.label = Some("cloudflare".to_string());
+        matching.name = "matching".to_string();
+        let (mut alerter, mut receiver) = alerter(vec![other_probe, other_label, matching], 3600);
+
+        alerter.observe(&failed(0));
+        let firing = sent(&mut receiver);
+        assert_eq!(firing.len(), 1);
+        assert_eq!(firing[0]["rule"], "matching");
+    }
+}
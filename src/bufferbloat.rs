@@ -0,0 +1,266 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::body::Bytes;
+use reqwest::Client;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use crate::icmp::{self, PingOptions, PingResult};
+use crate::throughput;
+
+/// Delay between echo requests, frequent enough to catch queues building up.
+const PING_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Echo requests sent to measure the idle latency.
+const IDLE_PINGS: u16 = 10;
+
+/// Time the load gets to fill the buffers before latency is measured.
+const RAMP_UP: Duration = Duration::from_secs(1);
+
+/// Size of the chunks the upload load is sent in.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+/// Upper bounds of the latency increase in milliseconds for each grade, the same
+/// scale as the Waveform bufferbloat test. Anything above the last one is an F.
+const GRADES: [(f64, &str); 5] = [(5.0, "A+"), (30.0, "A"), (60.0, "B"), (200.0, "C"), (400.0, "D")];
+
+/// Settings for a bufferbloat test.
+#[derive(Debug, Clone)]
+pub struct BufferbloatOptions {
+    /// How long latency is measured under load, per direction.
+    pub duration: Duration,
+    /// Concurrent transfers that make up the load.
+    pub streams: u16,
+    /// How long to wait for the last echo reply.
+    pub timeout: Duration,
+}
+
+/// Latency while the link is saturated in one direction.
+#[derive(Debug, Clone)]
+pub struct LoadedLatency {
+    pub ping: PingResult,
+    /// Throughput of the load while latency was measured.
+    pub mbps: f64,
+}
+
+impl LoadedLatency {
+    /// How much the average latency rose over `idle`, `None` without replies under load.
+    pub fn increase_ms(&self, idle: &PingResult) -> Option<f64> {
+        let loaded = self.ping.stats()?;
+        let idle = idle.stats()?;
+        Some((loaded.avg_ms - idle.avg_ms).max(0.0))
+    }
+
+    /// Grade from A+ to F, an F when nothing came back under load.
+    pub fn grade(&self, idle: &PingResult) -> &'static str {
+        let Some(increase) = self.increase_ms(idle) else { return "F" };
+        GRADES
+            .iter()
+            .find(|(limit, _)| increase < *limit)
+            .map_or("F", |(_, grade)| grade)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BufferbloatResult {
+    pub idle: PingResult,
+    /// `None` when there is no download URL.
+    pub download: Option<LoadedLatency>,
+    /// `None` when there is no upload URL.
+    pub upload: Option<LoadedLatency>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Download,
+    Upload,
+}
+
+/// Measures the latency to `addr` while idle, then while downloading from
+/// `download_url` and then while uploading to `upload_url`.
+pub async fn measure(
+    http_client: &Client,
+    addr: IpAddr,
+    download_url: Option<&str>,
+    upload_url: Option<&str>,
+    options: &BufferbloatOptions,
+) -> Result<BufferbloatResult> {
+    let idle_options = PingOptions {
+        count: IDLE_PINGS,
+        interval: PING_INTERVAL,
+        timeout: options.timeout,
+    };
+    let idle = icmp::ping(addr, &idle_options).await?;
+    if idle.received() == 0 {
+        bail!("No echo replies from {} while idle", addr);
+    }
+
+    let download = match download_url {
+        Some(url) => Some(under_load(http_client, addr, Direction::Download, url, options).await?),
+        None => None,
+    };
+    let upload = match upload_url {
+        Some(url) => Some(under_load(http_client, addr, Direction::Upload, url, options).await?),
+        None => None,
+    };
+    Ok(BufferbloatResult { idle, download, upload })
+}
+
+async fn under_load(
+    http_client: &Client,
+    addr: IpAddr,
+    direction: Direction,
+    url: &str,
+    options: &BufferbloatOptions,
+) -> Result<LoadedLatency> {
+    let bytes = Arc::new(AtomicU64::new(0));
+    // Dropping the set at the end stops the load
+    let mut load = JoinSet::new();
+    for _ in 0..options.streams.max(1) {
+        let (http_client, url, bytes) = (http_client.clone(), url.to_string(), bytes.clone());
+        match direction {
+            Direction::Download => load.spawn(download_load(http_client, url, bytes)),
+            Direction::Upload => load.spawn(upload_load(http_client, url, bytes)),
+        };
+    }
+
+    let ping_options = PingOptions {
+        count: (options.duration.as_millis() / PING_INTERVAL.as_millis()).clamp(1, u16::MAX as u128) as u16,
+        interval: PING_INTERVAL,
+        timeout: options.timeout,
+    };
+    let measurement = async {
+        time::sleep(RAMP_UP).await;
+        let started = Instant::now();
+        let before = bytes.load(Ordering::Relaxed);
+        let ping = icmp::ping(addr, &ping_options).await?;
+        let mbps = throughput::mbps(bytes.load(Ordering::Relaxed) - before, started.elapsed());
+        Ok(LoadedLatency { ping, mbps })
+    };
+
+    // The load only ends early when it fails, e.g. for a broken URL
+    tokio::select! {
+        loaded = measurement => loaded,
+        Some(stopped) = load.join_next() => {
+            let error = match stopped {
+                Ok(Err(e)) => e,
+                Ok(Ok(())) => anyhow!("transfer ended"),
+                Err(e) => anyhow!(e),
+            };
+            Err(error.context(format!("{:?} load to saturate the link failed", direction)))
+        }
+    }
+}
+
+/// Downloads `url` over and over, counting the bytes received.
+async fn download_load(http_client: Client, url: String, bytes: Arc<AtomicU64>) -> Result<()> {
+    loop {
+        let mut response = http_client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Download from {} failed", url))?;
+        while let Some(chunk) = response.chunk().await.context("Failed to read download body")? {
+            bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// POSTs an endless body to `url`, counting the bytes sent.
+async fn upload_load(http_client: Client, url: String, bytes: Arc<AtomicU64>) -> Result<()> {
+    let chunk = Bytes::from(throughput::upload_payload(UPLOAD_CHUNK));
+    loop {
+        let (mut sender, body) = hyper::Body::channel();
+        let request = http_client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(body)
+            .send();
+        tokio::pin!(request);
+        let feed = async {
+            while sender.send_data(chunk.clone()).await.is_ok() {
+                bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        };
+
+        // The request only finishes when the server gives up on the body or the
+        // connection breaks, which also ends the body
+        let response = tokio::select! {
+            response = &mut request => response,
+            _ = feed => request.await,
+        };
+        let response = response.with_context(|| format!("Upload to {} failed", url))?;
+        if !response.status().is_success() {
+            bail!("Upload to {} failed with HTTP status {}", url, response.status());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::icmp::EchoReply;
+    use super::*;
+
+    fn ping(rtts_ms: &[u64]) -> PingResult {
+        PingResult {
+            addr: "192.0.2.1".parse().unwrap(),
+            transmitted: 4,
+            replies: rtts_ms
+                .iter()
+                .enumerate()
+                .map(|(i, rtt)| EchoReply { seq: i as u16 + 1, rtt: Duration::from_millis(*rtt), ttl: None })
+                .collect(),
+        }
+    }
+
+    fn loaded(rtts_ms: &[u64]) -> LoadedLatency {
+        LoadedLatency { ping: ping(rtts_ms), mbps: 100.0 }
+    }
+
+    #[test]
+    fn grades_at_boundaries() {
+        let idle = ping(&[0]);
+        for (increase, grade) in [
+            (0, "A+"), (4, "A+"), (5, "A"),
+            (29, "A"), (30, "B"),
+            (59, "B"), (60, "C"),
+            (199, "C"), (200, "D"),
+            (399, "D"), (400, "F"),
+            (2000, "F"),
+        ] {
+            let loaded = loaded(&[increase]);
+            assert_eq!(loaded.increase_ms(&idle), Some(increase as f64));
+            assert_eq!(loaded.grade(&idle), grade, "{} ms increase", increase);
+        }
+    }
+
+    #[test]
+    fn increase_compares_averages() {
+        let idle = ping(&[10, 20]);
+        let loaded = loaded(&[40, 60, 80]);
+        assert_eq!(loaded.increase_ms(&idle), Some(45.0));
+        assert_eq!(loaded.grade(&idle), "B");
+    }
+
+    #[test]
+    fn lower_latency_under_load_is_no_increase() {
+        let idle = ping(&[50]);
+        let loaded = loaded(&[20]);
+        assert_eq!(loaded.increase_ms(&idle), Some(0.0));
+        assert_eq!(loaded.grade(&idle), "A+");
+    }
+
+    #[test]
+    fn no_replies_grade_f() {
+        // Nothing came back under load
+        let idle = ping(&[10]);
+        assert_eq!(loaded(&[]).increase_ms(&idle), None);
+        assert_eq!(loaded(&[]).grade(&idle), "F");
+        // Nothing to compare with
+        assert_eq!(loaded(&[10]).increase_ms(&ping(&[])), None);
+        assert_eq!(loaded(&[10]).grade(&ping(&[])), "F");
+    }
+}
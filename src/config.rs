@@ -0,0 +1,1734 @@
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{de, Deserialize, Deserializer};
+use crate::dns::{self, RecordType};
+use crate::gateway;
+use crate::public_ip;
+use crate::tcp;
+
+const DEFAULT_INTERVAL: u64 = 5;
+const DEFAULT_RUN_TIMEOUT: u64 = 60;
+const DEFAULT_INFLUXDB_URL: &str = "http://influxdb:8086";
+const DEFAULT_INFLUXDB_DB: &str = "internet_metrics";
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_FLUSH_INTERVAL: u64 = 10;
+const DEFAULT_SPOOL_MAX_BYTES: u64 = 50_000_000;
+const DEFAULT_OUTAGE_THRESHOLD: u32 = 3;
+const DEFAULT_RENOTIFY_INTERVAL: u64 = 3600;
+const DEFAULT_LATENCY_TARGET: &str = "google.com";
+/// Target of gateway probes, the address is only known once they run.
+const GATEWAY_TARGET: &str = "gateway";
+/// Target of public IP probes, which ask several endpoints.
+const PUBLIC_IP_TARGET: &str = "public_ip";
+/// Target of interface probes, which read local counters.
+const INTERFACES_TARGET: &str = "interfaces";
+/// Target of Wi-Fi probes, which ask the local Wi-Fi stack.
+const WIFI_TARGET: &str = "wifi";
+
+/// Command line flags, each of which can also be set through an
+/// `INTERNET_MONITOR_*` environment variable. Every value is optional so we can
+/// tell which ones were given explicitly and should win over the configuration file.
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML configuration file, command line flags override values from it
+    #[clap(short, long, env = "INTERNET_MONITOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Time between runs of a probe in seconds, unless the probe sets its own [default: 5]
+    #[clap(short, long, env = "INTERNET_MONITOR_INTERVAL")]
+    pub interval: Option<u64>,
+
+    /// Maximum random delay in seconds added to each run, spreads out probes with the
+    /// same interval [default: 0]
+    #[clap(long, env = "INTERNET_MONITOR_JITTER")]
+    pub jitter: Option<u64>,
+
+    /// Seconds after which a probe run is aborted and counted as failed [default: 60]
+    #[clap(long, env = "INTERNET_MONITOR_RUN_TIMEOUT")]
+    pub run_timeout: Option<u64>,
+
+    /// Don't write to InfluxDB, e.g. when only the Prometheus exporter is used
+    #[clap(long, env = "INTERNET_MONITOR_DISABLE_INFLUXDB")]
+    pub disable_influxdb: bool,
+
+    /// InfluxDB API version to write with [default: 1]
+    #[clap(long, value_enum, env = "INTERNET_MONITOR_INFLUXDB_VERSION")]
+    pub influxdb_version: Option<InfluxDbVersion>,
+
+    /// InfluxDB URL [default: http://influxdb:8086]
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_URL")]
+    pub influxdb_url: Option<String>,
+
+    /// InfluxDB 1.x database [default: internet_metrics]
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_DB")]
+    pub influxdb_db: Option<String>,
+
+    /// InfluxDB 1.x username (optional)
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_USERNAME")]
+    pub influxdb_username: Option<String>,
+
+    /// InfluxDB 1.x password (optional)
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_PASSWORD", hide_env_values = true)]
+    pub influxdb_password: Option<String>,
+
+    /// File containing the InfluxDB 1.x password, e.g. a Docker secret (optional)
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_PASSWORD_FILE", conflicts_with = "influxdb_password")]
+    pub influxdb_password_file: Option<PathBuf>,
+
+    /// InfluxDB 2.x/3.x API token
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_TOKEN", hide_env_values = true)]
+    pub influxdb_token: Option<String>,
+
+    /// File containing the InfluxDB 2.x/3.x API token, e.g. a Docker secret
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_TOKEN_FILE", conflicts_with = "influxdb_token")]
+    pub influxdb_token_file: Option<PathBuf>,
+
+    /// InfluxDB 2.x/3.x organization
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_ORG")]
+    pub influxdb_org: Option<String>,
+
+    /// InfluxDB 2.x/3.x bucket [default: the database name]
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_BUCKET")]
+    pub influxdb_bucket: Option<String>,
+
+    /// Number of buffered points that triggers a write to InfluxDB [default: 1000]
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_BATCH_SIZE")]
+    pub influxdb_batch_size: Option<usize>,
+
+    /// Maximum time in seconds points are buffered before they are written to InfluxDB
+    /// [default: 10]
+    #[clap(long, env = "INTERNET_MONITOR_INFLUXDB_FLUSH_INTERVAL")]
+    pub influxdb_flush_interval: Option<u64>,
+
+    /// File to keep points in while InfluxDB is unreachable, replayed once it is back (optional)
+    #[clap(long, env = "INTERNET_MONITOR_SPOOL_PATH")]
+    pub spool_path: Option<PathBuf>,
+
+    /// Maximum size of the spool file in bytes, the oldest points are dropped beyond it
+    /// [default: 50000000]
+    #[clap(long, env = "INTERNET_MONITOR_SPOOL_MAX_BYTES")]
+    pub spool_max_bytes: Option<u64>,
+
+    /// Consecutive failed pings and TCP connects, counted across all targets, after which
+    /// an outage is recorded [default: 3]
+    #[clap(long, env = "INTERNET_MONITOR_OUTAGE_THRESHOLD")]
+    pub outage_threshold: Option<u32>,
+
+    /// URL alert notifications are POSTed to as JSON, rules are set in the configuration
+    /// file (optional)
+    #[clap(long, env = "INTERNET_MONITOR_ALERT_WEBHOOK_URL", hide_env_values = true)]
+    pub alert_webhook_url: Option<String>,
+
+    /// Seconds after which a still firing alert is sent again [default: 3600]
+    #[clap(long, env = "INTERNET_MONITOR_ALERT_RENOTIFY_INTERVAL")]
+    pub alert_renotify_interval: Option<u64>,
+
+    /// Address to serve Prometheus metrics on at /metrics, e.g. 0.0.0.0:9100 (optional)
+    #[clap(long, env = "INTERNET_MONITOR_PROMETHEUS_LISTEN")]
+    pub prometheus_listen: Option<SocketAddr>,
+
+    /// Latency test target as `host` or `label=host`, may be repeated or comma separated.
+    /// Replaces the ping probes from the configuration file [default: google.com]
+    #[clap(long = "latency-target", alias = "latency-url", value_delimiter = ',',
+           env = "INTERNET_MONITOR_LATENCY_TARGETS")]
+    pub latency_targets: Vec<PingProbe>,
+
+    /// IP version to ping latency targets over, unless the probe sets its own. `both` pings
+    /// host names over IPv4 and IPv6 separately, as far as there is a default route for
+    /// them [default: both]
+    #[clap(long, value_enum, env = "INTERNET_MONITOR_IP_VERSION")]
+    pub ip_version: Option<IpVersion>,
+
+    /// Don't ping the default gateway, which otherwise runs alongside the latency targets
+    /// to tell LAN problems from ISP problems
+    #[clap(long, env = "INTERNET_MONITOR_DISABLE_GATEWAY_PROBE")]
+    pub disable_gateway_probe: bool,
+
+    /// TCP connect target as `host:port` or `label=host:port`, may be repeated or comma
+    /// separated. Replaces the TCP probes from the configuration file
+    #[clap(long = "tcp-target", value_delimiter = ',', env = "INTERNET_MONITOR_TCP_TARGETS")]
+    pub tcp_targets: Vec<TcpProbe>,
+
+    /// Trace the path to every latency target every this many seconds, replaces the path
+    /// probes from the configuration file (optional)
+    #[clap(long, env = "INTERNET_MONITOR_PATH_INTERVAL")]
+    pub path_interval: Option<u64>,
+
+    /// Look up the public IP address and its ISP every this many seconds, replaces the
+    /// public IP probes from the configuration file (optional)
+    #[clap(long, env = "INTERNET_MONITOR_PUBLIC_IP_INTERVAL")]
+    pub public_ip_interval: Option<u64>,
+
+    /// URL answering with the client address as plain text, or STUN server as
+    /// `stun:host:port`, to learn the public IP address from. Tried in order, may be
+    /// repeated or comma separated [default: api64.ipify.org, icanhazip.com, stun.cloudflare.com]
+    #[clap(long = "public-ip-endpoint", value_delimiter = ',', env = "INTERNET_MONITOR_PUBLIC_IP_ENDPOINTS")]
+    pub public_ip_endpoints: Vec<String>,
+
+    /// DNS query as `name`, with optional record type, resolver and label:
+    /// `label=name/type@server`, e.g. `example.com/AAAA@1.1.1.1`. May be repeated or comma
+    /// separated, replaces the DNS probes from the configuration file
+    #[clap(long = "dns-query", value_delimiter = ',', env = "INTERNET_MONITOR_DNS_QUERIES")]
+    pub dns_queries: Vec<DnsProbe>,
+
+    /// URL to time an HTTP GET request to as `url` or `label=url`, may be repeated or comma
+    /// separated. Replaces the HTTP probes from the configuration file
+    #[clap(long = "http-url", value_delimiter = ',', env = "INTERNET_MONITOR_HTTP_URLS")]
+    pub http_urls: Vec<HttpProbe>,
+
+    /// Download test URL, replaces the download probes from the configuration file
+    #[clap(long, env = "INTERNET_MONITOR_DOWNLOAD_URL")]
+    pub download_url: Option<String>,
+
+    /// Maximum number of bytes read per download test [default: 10000000]
+    #[clap(long, env = "INTERNET_MONITOR_DOWNLOAD_MAX_BYTES")]
+    pub download_max_bytes: Option<u64>,
+
+    /// Maximum duration of a download test in seconds [default: 10]
+    #[clap(long, env = "INTERNET_MONITOR_DOWNLOAD_MAX_DURATION")]
+    pub download_max_duration: Option<u64>,
+
+    /// Upload test URL receiving the POSTed payload, replaces the upload probes from the
+    /// configuration file
+    #[clap(long, env = "INTERNET_MONITOR_UPLOAD_URL")]
+    pub upload_url: Option<String>,
+
+    /// Size of the upload test payload in bytes [default: 2000000]
+    #[clap(long, env = "INTERNET_MONITOR_UPLOAD_BYTES")]
+    pub upload_bytes: Option<usize>,
+
+    /// Maximum duration of an upload test in seconds [default: 10]
+    #[clap(long, env = "INTERNET_MONITOR_UPLOAD_MAX_DURATION")]
+    pub upload_max_duration: Option<u64>,
+
+    /// Measure bufferbloat every this many seconds, pinging the first latency target while
+    /// saturating the download and upload test URLs. Replaces the bufferbloat probes from
+    /// the configuration file (optional)
+    #[clap(long, env = "INTERNET_MONITOR_BUFFERBLOAT_INTERVAL")]
+    pub bufferbloat_interval: Option<u64>,
+
+    /// Write the traffic, error and drop rates of the network interfaces on every interval,
+    /// to tell a throughput dip caused by local traffic from one caused by the ISP
+    #[clap(long, env = "INTERNET_MONITOR_INTERFACE_STATS")]
+    pub interface_stats: bool,
+
+    /// Network interface to collect counters for, may be repeated or comma separated.
+    /// Implies --interface-stats [default: every interface but lo]
+    #[clap(long = "interface", value_delimiter = ',', env = "INTERNET_MONITOR_INTERFACES")]
+    pub interfaces: Vec<String>,
+
+    /// Write the signal, noise, bitrate, SSID and BSSID of the Wi-Fi link on every interval,
+    /// to tell bad latency caused by a weak signal from bad latency caused by the ISP
+    #[clap(long, env = "INTERNET_MONITOR_WIFI_STATS")]
+    pub wifi_stats: bool,
+
+    /// Wi-Fi interface to collect link quality for, may be repeated or comma separated.
+    /// Implies --wifi-stats [default: every Wi-Fi interface]
+    #[clap(long = "wifi-interface", value_delimiter = ',', env = "INTERNET_MONITOR_WIFI_INTERFACES")]
+    pub wifi_interfaces: Vec<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run every configured probe once, print the results and exit. The exit code is 1
+    /// when any probe failed. Nothing is written to InfluxDB.
+    Once {
+        /// How to print the results
+        #[clap(long, value_enum, default_value_t = OutputFormat::Json)]
+        format: OutputFormat,
+    },
+}
+
+/// Output of the `once` subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// A JSON document for scripts
+    Json,
+    /// An aligned table for humans
+    Table,
+}
+
+/// Layout of the TOML configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    interval: Option<u64>,
+    jitter: Option<u64>,
+    run_timeout: Option<u64>,
+    #[serde(default)]
+    influxdb: InfluxDbFileConfig,
+    #[serde(default)]
+    spool: SpoolFileConfig,
+    #[serde(default)]
+    outages: OutagesFileConfig,
+    #[serde(default)]
+    alerts: AlertsFileConfig,
+    #[serde(default)]
+    prometheus: PrometheusFileConfig,
+    #[serde(default)]
+    probes: Vec<ProbeEntry>,
+}
+
+/// A `[[probes]]` table, the probe settings plus when to run it.
+#[derive(Debug)]
+struct ProbeEntry {
+    schedule: ScheduleFileConfig,
+    probe: ProbeConfig,
+}
+
+/// Scheduling keys shared by every probe type, defaulting to the top level values.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFileConfig {
+    interval: Option<u64>,
+    jitter: Option<u64>,
+    run_timeout: Option<u64>,
+}
+
+impl<'de> Deserialize<'de> for ProbeEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Split off the scheduling keys so the probe types don't have to repeat them,
+        // `#[serde(flatten)]` doesn't work together with `deny_unknown_fields`
+        let mut table = toml::Table::deserialize(deserializer)?;
+        let mut schedule = toml::Table::new();
+        for key in ["interval", "jitter", "run_timeout"] {
+            if let Some(value) = table.remove(key) {
+                schedule.insert(key.to_string(), value);
+            }
+        }
+        Ok(ProbeEntry {
+            schedule: schedule.try_into().map_err(de::Error::custom)?,
+            probe: table.try_into().map_err(de::Error::custom)?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InfluxDbFileConfig {
+    enabled: Option<bool>,
+    version: Option<InfluxDbVersion>,
+    url: Option<String>,
+    db: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    org: Option<String>,
+    bucket: Option<String>,
+    batch_size: Option<usize>,
+    flush_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpoolFileConfig {
+    path: Option<PathBuf>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutagesFileConfig {
+    threshold: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlertsFileConfig {
+    webhook_url: Option<String>,
+    renotify_interval: Option<u64>,
+    #[serde(default)]
+    rules: Vec<AlertRule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrometheusFileConfig {
+    listen: Option<SocketAddr>,
+}
+
+/// Which InfluxDB write API to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+pub enum InfluxDbVersion {
+    /// InfluxDB 1.x, database with optional username and password
+    #[default]
+    #[serde(rename = "1")]
+    #[value(name = "1")]
+    V1,
+    /// InfluxDB 2.x or 3.x, `/api/v2/write` with token, organization and bucket
+    #[serde(rename = "2")]
+    #[value(name = "2")]
+    V2,
+}
+
+/// Address family a latency target is pinged over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, ValueEnum)]
+pub enum IpVersion {
+    /// The first address the system resolver returns
+    #[serde(rename = "auto")]
+    Auto,
+    /// IPv4 only
+    #[serde(rename = "4")]
+    #[value(name = "4")]
+    V4,
+    /// IPv6 only
+    #[serde(rename = "6")]
+    #[value(name = "6")]
+    V6,
+    /// IPv4 and IPv6 as separate probes, IP addresses only over their own version
+    #[default]
+    #[serde(rename = "both")]
+    Both,
+}
+
+impl IpVersion {
+    /// Whether `addr` is of this version.
+    pub fn matches(self, addr: &IpAddr) -> bool {
+        match self {
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+            IpVersion::Auto | IpVersion::Both => true,
+        }
+    }
+}
+
+impl fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IpVersion::Auto => "auto",
+            IpVersion::V4 => "4",
+            IpVersion::V6 => "6",
+            IpVersion::Both => "both",
+        })
+    }
+}
+
+/// What an alert rule looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// 95th percentile of all echo reply or TCP handshake round-trip times in milliseconds
+    LatencyP95Ms,
+    /// Share of echo requests without a reply, or connections not established, in percent
+    PacketLossPct,
+    /// Every run of the probe failed
+    ProbeDown,
+}
+
+/// Fires when `metric` is above `threshold` across all results of the last `for` seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: AlertMetric,
+    /// Not used by `probe_down`.
+    pub threshold: Option<f64>,
+    /// Seconds the condition has to hold, 0 looks at the latest run only.
+    #[serde(default, rename = "for")]
+    pub for_secs: u64,
+    /// Only apply to probes of this type.
+    pub probe: Option<String>,
+    /// Only apply to the probe with this label.
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertsConfig {
+    pub webhook_url: String,
+    /// Time after which a still firing alert is sent again.
+    pub renotify_interval: Duration,
+    pub rules: Vec<AlertRule>,
+}
+
+/// A single probe and its settings, `type` in the configuration file selects the variant.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProbeConfig {
+    Ping(PingProbe),
+    Gateway(GatewayProbe),
+    Tcp(TcpProbe),
+    Path(PathProbe),
+    Dns(DnsProbe),
+    Http(HttpProbe),
+    Download(DownloadProbe),
+    Upload(UploadProbe),
+    Bufferbloat(BufferbloatProbe),
+    PublicIp(PublicIpProbe),
+    Interfaces(InterfacesProbe),
+    Wifi(WifiProbe),
+}
+
+impl ProbeConfig {
+    /// Probe type as used in the configuration file.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProbeConfig::Ping(_) => "ping",
+            ProbeConfig::Gateway(_) => "gateway",
+            ProbeConfig::Tcp(_) => "tcp",
+            ProbeConfig::Path(_) => "path",
+            ProbeConfig::Dns(_) => "dns",
+            ProbeConfig::Http(_) => "http",
+            ProbeConfig::Download(_) => "download",
+            ProbeConfig::Upload(_) => "upload",
+            ProbeConfig::Bufferbloat(_) => "bufferbloat",
+            ProbeConfig::PublicIp(_) => "public_ip",
+            ProbeConfig::Interfaces(_) => "interfaces",
+            ProbeConfig::Wifi(_) => "wifi",
+        }
+    }
+
+    /// Host or URL the probe measures against.
+    pub fn target(&self) -> &str {
+        match self {
+            ProbeConfig::Ping(ping) => &ping.host,
+            ProbeConfig::Gateway(_) => GATEWAY_TARGET,
+            ProbeConfig::Tcp(tcp) => &tcp.address,
+            ProbeConfig::Path(path) => &path.host,
+            ProbeConfig::Dns(dns) => &dns.name,
+            ProbeConfig::Http(http) => &http.url,
+            ProbeConfig::Download(download) => &download.url,
+            ProbeConfig::Upload(upload) => &upload.url,
+            ProbeConfig::Bufferbloat(bufferbloat) => &bufferbloat.host,
+            ProbeConfig::PublicIp(_) => PUBLIC_IP_TARGET,
+            ProbeConfig::Interfaces(_) => INTERFACES_TARGET,
+            ProbeConfig::Wifi(_) => WIFI_TARGET,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            ProbeConfig::Ping(ping) => ping.label(),
+            ProbeConfig::Gateway(gateway) => gateway.label(),
+            ProbeConfig::Tcp(tcp) => tcp.label(),
+            ProbeConfig::Path(path) => path.label(),
+            ProbeConfig::Dns(dns) => dns.label(),
+            ProbeConfig::Http(http) => http.label(),
+            ProbeConfig::Download(download) => download.label(),
+            ProbeConfig::Upload(upload) => upload.label(),
+            ProbeConfig::Bufferbloat(bufferbloat) => bufferbloat.label(),
+            ProbeConfig::PublicIp(public_ip) => public_ip.label(),
+            ProbeConfig::Interfaces(interfaces) => interfaces.label(),
+            ProbeConfig::Wifi(wifi) => wifi.label(),
+        }
+    }
+
+    /// IP version the probe is pinned to, `None` when the resolver picks.
+    pub fn ip_version(&self) -> Option<IpVersion> {
+        match self {
+            ProbeConfig::Ping(ping) => ping.ip_version.filter(|version| matches!(version, IpVersion::V4 | IpVersion::V6)),
+            ProbeConfig::Gateway(gateway) => {
+                gateway.ip_version.filter(|version| matches!(version, IpVersion::V4 | IpVersion::V6))
+            }
+            ProbeConfig::PublicIp(public_ip) => {
+                public_ip.ip_version.filter(|version| matches!(version, IpVersion::V4 | IpVersion::V6))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the probe saturates the link, such probes take turns.
+    pub fn uses_bandwidth(&self) -> bool {
+        matches!(self, ProbeConfig::Download(_) | ProbeConfig::Upload(_) | ProbeConfig::Bufferbloat(_))
+    }
+}
+
+/// ICMP echo latency to a host.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PingProbe {
+    pub host: String,
+    /// Tag value for the points of this probe, defaults to the host.
+    pub label: Option<String>,
+    /// Echo requests sent per run.
+    #[serde(default = "default_ping_count")]
+    pub count: u16,
+    /// Seconds to wait for the last reply.
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u64,
+    /// Defaults to `--ip-version`, never `both` once the settings are loaded.
+    pub ip_version: Option<IpVersion>,
+}
+
+impl PingProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.host)
+    }
+
+    /// One probe per IP version to ping over, each pinned to its version. `both` pings
+    /// host names over each of the `routed` versions.
+    fn split_versions(self, default: IpVersion, routed: &[IpVersion]) -> Vec<PingProbe> {
+        let version = self.ip_version.unwrap_or(default);
+        // An address can only be pinged over its own version
+        if version == IpVersion::Both && self.host.parse::<IpAddr>().is_ok() {
+            return vec![PingProbe { ip_version: Some(IpVersion::Auto), ..self }];
+        }
+        pinned_versions(version, routed)
+            .into_iter()
+            .map(|version| PingProbe { ip_version: Some(version), ..self.clone() })
+            .collect()
+    }
+}
+
+impl FromStr for PingProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, host) = match s.split_once('=') {
+            Some((label, host)) => (Some(label.trim()), host.trim()),
+            None => (None, s.trim()),
+        };
+        if host.is_empty() || label.is_some_and(str::is_empty) {
+            return Err(format!("invalid latency target '{}', expected host or label=host", s));
+        }
+        Ok(PingProbe {
+            host: host.to_string(),
+            label: label.map(str::to_string),
+            count: default_ping_count(),
+            timeout: default_ping_timeout(),
+            ip_version: None,
+        })
+    }
+}
+
+/// ICMP echo latency to the default gateway, which is looked up again on every run.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayProbe {
+    /// Tag value for the points of this probe, defaults to `gateway`.
+    pub label: Option<String>,
+    /// Echo requests sent per run.
+    #[serde(default = "default_ping_count")]
+    pub count: u16,
+    /// Seconds to wait for the last reply.
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u64,
+    /// Defaults to `--ip-version`, never `both` once the settings are loaded.
+    pub ip_version: Option<IpVersion>,
+}
+
+impl Default for GatewayProbe {
+    fn default() -> Self {
+        GatewayProbe {
+            label: None,
+            count: default_ping_count(),
+            timeout: default_ping_timeout(),
+            ip_version: None,
+        }
+    }
+}
+
+impl GatewayProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(GATEWAY_TARGET)
+    }
+
+    /// One probe per IP version to run over, each pinned to its version. `both` runs
+    /// over each of the `routed` versions.
+    fn split_versions(self, default: IpVersion, routed: &[IpVersion]) -> Vec<GatewayProbe> {
+        pinned_versions(self.ip_version.unwrap_or(default), routed)
+            .into_iter()
+            .map(|version| GatewayProbe { ip_version: Some(version), ..self.clone() })
+            .collect()
+    }
+}
+
+/// The public IP address, learned from echo services or STUN servers, and the ISP it
+/// belongs to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublicIpProbe {
+    /// Tag value for the points of this probe, defaults to `public_ip`.
+    pub label: Option<String>,
+    /// URLs answering with the client address as plain text, or STUN servers as
+    /// `stun:host:port`, tried in order.
+    #[serde(default = "default_public_ip_endpoints")]
+    pub endpoints: Vec<String>,
+    /// Whether to look up the AS number and name of the address.
+    #[serde(default = "default_asn_lookup")]
+    pub asn_lookup: bool,
+    /// Seconds to wait for each endpoint.
+    #[serde(default = "default_public_ip_timeout")]
+    pub timeout: u64,
+    /// Defaults to `--ip-version`, never `both` once the settings are loaded.
+    pub ip_version: Option<IpVersion>,
+}
+
+impl PublicIpProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(PUBLIC_IP_TARGET)
+    }
+
+    /// One probe per IP version to run over, each pinned to its version. `both` runs
+    /// over each of the `routed` versions.
+    fn split_versions(self, default: IpVersion, routed: &[IpVersion]) -> Vec<PublicIpProbe> {
+        pinned_versions(self.ip_version.unwrap_or(default), routed)
+            .into_iter()
+            .map(|version| PublicIpProbe { ip_version: Some(version), ..self.clone() })
+            .collect()
+    }
+}
+
+/// Traffic counters of the local network interfaces, turned into rates between runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfacesProbe {
+    /// Tag value for the points of this probe, defaults to `interfaces`.
+    pub label: Option<String>,
+    /// Interfaces to collect counters for, every interface but `lo` when empty.
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+impl InterfacesProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(INTERFACES_TARGET)
+    }
+}
+
+/// Signal, noise and bitrate of the Wi-Fi link, and the access point it is associated with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiProbe {
+    /// Tag value for the points of this probe, defaults to `wifi`.
+    pub label: Option<String>,
+    /// Wi-Fi interfaces to collect link quality for, every one when empty.
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+impl WifiProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(WIFI_TARGET)
+    }
+}
+
+/// Resolves `both` to the `routed` IP versions.
+fn pinned_versions(version: IpVersion, routed: &[IpVersion]) -> Vec<IpVersion> {
+    if version != IpVersion::Both {
+        return vec![version];
+    }
+    routed.to_vec()
+}
+
+/// The IP versions that have a default route, there is no point in probing the others.
+fn routed_versions() -> Vec<IpVersion> {
+    let versions: Vec<_> = [IpVersion::V4, IpVersion::V6]
+        .into_iter()
+        .filter(|version| gateway::has_default_route(*version))
+        .collect();
+    // Without any default route yet, take whichever shows up first
+    if versions.is_empty() {
+        return vec![IpVersion::Auto];
+    }
+    versions
+}
+
+/// Time to establish TCP connections to a port, for hosts that don't answer pings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpProbe {
+    /// `host:port`, IPv6 addresses in brackets.
+    pub address: String,
+    pub label: Option<String>,
+    /// Connections opened per run.
+    #[serde(default = "default_ping_count")]
+    pub count: u16,
+    /// Seconds to wait for each connection.
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u64,
+}
+
+impl TcpProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.address)
+    }
+}
+
+impl FromStr for TcpProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, address) = match s.split_once('=') {
+            Some((label, address)) => (Some(label.trim()), address.trim()),
+            None => (None, s.trim()),
+        };
+        if label.is_some_and(str::is_empty) {
+            return Err(format!("invalid TCP target '{}', expected host:port or label=host:port", s));
+        }
+        tcp::split_host_port(address).map_err(|e| e.to_string())?;
+        Ok(TcpProbe {
+            address: address.to_string(),
+            label: label.map(str::to_string),
+            count: default_ping_count(),
+            timeout: default_ping_timeout(),
+        })
+    }
+}
+
+/// The routers on the way to a host, with the latency and loss at each of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathProbe {
+    pub host: String,
+    pub label: Option<String>,
+    /// Highest TTL probed.
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u8,
+    /// Echo requests sent per hop and run.
+    #[serde(default = "default_path_queries")]
+    pub queries: u16,
+    /// Seconds to wait for the last answer.
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u64,
+}
+
+impl PathProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.host)
+    }
+}
+
+/// Resolution time of a name, queried straight at a resolver.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsProbe {
+    pub name: String,
+    #[serde(default = "default_record_type")]
+    pub record_type: RecordType,
+    /// Resolver as `ip` or `ip:port`, the first nameserver of `/etc/resolv.conf` when not set.
+    pub server: Option<String>,
+    pub label: Option<String>,
+    /// Seconds to wait for the response.
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+}
+
+impl DnsProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl FromStr for DnsProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, query) = match s.split_once('=') {
+            Some((label, query)) => (Some(label.trim()), query.trim()),
+            None => (None, s.trim()),
+        };
+        let (query, server) = match query.split_once('@') {
+            Some((query, server)) => (query, Some(server)),
+            None => (query, None),
+        };
+        let (name, record_type) = match query.split_once('/') {
+            Some((name, record_type)) => (name, record_type.parse()?),
+            None => (query, default_record_type()),
+        };
+        if name.is_empty() || label.is_some_and(str::is_empty) || server.is_some_and(str::is_empty) {
+            return Err(format!("invalid DNS query '{}', expected label=name/type@server", s));
+        }
+        Ok(DnsProbe {
+            name: name.to_string(),
+            record_type,
+            server: server.map(str::to_string),
+            label: label.map(str::to_string),
+            timeout: default_dns_timeout(),
+        })
+    }
+}
+
+/// Timing breakdown of a GET request to a URL.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpProbe {
+    pub url: String,
+    pub label: Option<String>,
+    /// Seconds to wait for the connection and again for the response headers.
+    #[serde(default = "default_http_timeout")]
+    pub timeout: u64,
+}
+
+impl HttpProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.url)
+    }
+}
+
+impl FromStr for HttpProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // URLs may contain `=` themselves, only a prefix without URL characters is a label
+        let (label, url) = match s.split_once('=') {
+            Some((label, url)) if !label.contains([':', '/', '?']) => (Some(label.trim()), url.trim()),
+            _ => (None, s.trim()),
+        };
+        if url.is_empty() || label.is_some_and(str::is_empty) {
+            return Err(format!("invalid HTTP URL '{}', expected url or label=url", s));
+        }
+        Ok(HttpProbe {
+            url: url.to_string(),
+            label: label.map(str::to_string),
+            timeout: default_http_timeout(),
+        })
+    }
+}
+
+/// HTTP download throughput from a test file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DownloadProbe {
+    pub url: String,
+    pub label: Option<String>,
+    #[serde(default = "default_download_max_bytes")]
+    pub max_bytes: u64,
+    /// Seconds after which the download is cut short.
+    #[serde(default = "default_max_duration")]
+    pub max_duration: u64,
+}
+
+impl DownloadProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.url)
+    }
+}
+
+/// HTTP upload throughput to an endpoint accepting POST requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadProbe {
+    pub url: String,
+    pub label: Option<String>,
+    #[serde(default = "default_upload_bytes")]
+    pub bytes: usize,
+    /// Seconds after which the upload is aborted.
+    #[serde(default = "default_max_duration")]
+    pub max_duration: u64,
+}
+
+impl UploadProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.url)
+    }
+}
+
+/// Latency to a host while idle and while the link is saturated in each direction.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BufferbloatProbe {
+    pub host: String,
+    pub label: Option<String>,
+    /// File downloaded to saturate the link, no download phase when not set.
+    pub download_url: Option<String>,
+    /// Endpoint uploaded to to saturate the link, no upload phase when not set.
+    pub upload_url: Option<String>,
+    /// Seconds latency is measured under load, per direction.
+    #[serde(default = "default_bufferbloat_duration")]
+    pub duration: u64,
+    /// Concurrent transfers per direction.
+    #[serde(default = "default_bufferbloat_streams")]
+    pub streams: u16,
+    /// Seconds to wait for the last echo reply.
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u64,
+}
+
+impl BufferbloatProbe {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.host)
+    }
+}
+
+fn default_ping_count() -> u16 {
+    4
+}
+
+fn default_ping_timeout() -> u64 {
+    2
+}
+
+fn default_max_hops() -> u8 {
+    30
+}
+
+fn default_path_queries() -> u16 {
+    3
+}
+
+fn default_record_type() -> RecordType {
+    RecordType::A
+}
+
+fn default_dns_timeout() -> u64 {
+    2
+}
+
+fn default_http_timeout() -> u64 {
+    10
+}
+
+fn default_download_max_bytes() -> u64 {
+    10_000_000
+}
+
+fn default_upload_bytes() -> usize {
+    2_000_000
+}
+
+fn default_max_duration() -> u64 {
+    10
+}
+
+fn default_bufferbloat_duration() -> u64 {
+    10
+}
+
+fn default_bufferbloat_streams() -> u16 {
+    4
+}
+
+fn default_public_ip_endpoints() -> Vec<String> {
+    public_ip::DEFAULT_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect()
+}
+
+fn default_asn_lookup() -> bool {
+    true
+}
+
+fn default_public_ip_timeout() -> u64 {
+    5
+}
+
+/// Reads a secret from a file, ignoring the trailing newline most editors add.
+fn read_secret_file(path: &Path) -> Result<String> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file {}", path.display()))?;
+    Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Picks a secret given either directly or as a file, command line first.
+fn resolve_secret(
+    arg: Option<String>,
+    arg_file: Option<PathBuf>,
+    file: Option<String>,
+    file_file: Option<PathBuf>,
+) -> Result<Option<String>> {
+    Ok(match (arg, arg_file) {
+        (Some(secret), _) => Some(secret),
+        (None, Some(path)) => Some(read_secret_file(&path)?),
+        (None, None) => match (file, file_file) {
+            (Some(secret), _) => Some(secret),
+            (None, Some(path)) => Some(read_secret_file(&path)?),
+            (None, None) => None,
+        },
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct InfluxDbConfig {
+    pub version: InfluxDbVersion,
+    pub url: String,
+    pub db: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    /// Points written per request, a full buffer is flushed right away.
+    pub batch_size: usize,
+    /// Maximum time points wait in the buffer.
+    pub flush_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+/// When a probe runs.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub interval: Duration,
+    /// Upper bound of the random delay added to each run.
+    pub jitter: Duration,
+    /// Runs taking longer are aborted.
+    pub run_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledProbe {
+    pub probe: ProbeConfig,
+    pub schedule: Schedule,
+}
+
+/// Final configuration after merging the file, command line flags and defaults.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// `None` when writing to InfluxDB is disabled.
+    pub influxdb: Option<InfluxDbConfig>,
+    /// Where to keep points InfluxDB didn't accept, `None` to drop them.
+    pub spool: Option<SpoolConfig>,
+    /// Consecutive failed checks that make an outage.
+    pub outage_threshold: u32,
+    /// `None` without a webhook to notify.
+    pub alerts: Option<AlertsConfig>,
+    /// Address of the Prometheus exporter, `None` when disabled.
+    pub prometheus_listen: Option<SocketAddr>,
+    pub probes: Vec<ScheduledProbe>,
+}
+
+impl Settings {
+    pub fn load(args: Args) -> Result<Settings> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+                toml::from_str::<FileConfig>(&contents)
+                    .with_context(|| format!("Failed to parse configuration file {}", path.display()))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let influxdb_enabled = !args.disable_influxdb && file.influxdb.enabled.unwrap_or(true);
+        let influxdb = InfluxDbConfig {
+            version: args.influxdb_version.or(file.influxdb.version).unwrap_or_default(),
+            url: args.influxdb_url
+                .or(file.influxdb.url)
+                .unwrap_or_else(|| DEFAULT_INFLUXDB_URL.to_string()),
+            db: args.influxdb_db
+                .or(file.influxdb.db)
+                .unwrap_or_else(|| DEFAULT_INFLUXDB_DB.to_string()),
+            username: args.influxdb_username.or(file.influxdb.username),
+            password: resolve_secret(
+                args.influxdb_password,
+                args.influxdb_password_file,
+                file.influxdb.password,
+                file.influxdb.password_file,
+            )?,
+            token: resolve_secret(
+                args.influxdb_token,
+                args.influxdb_token_file,
+                file.influxdb.token,
+                file.influxdb.token_file,
+            )?,
+            org: args.influxdb_org.or(file.influxdb.org),
+            bucket: args.influxdb_bucket.or(file.influxdb.bucket),
+            batch_size: args.influxdb_batch_size
+                .or(file.influxdb.batch_size)
+                .unwrap_or(DEFAULT_BATCH_SIZE)
+                .max(1),
+            flush_interval: Duration::from_secs(
+                args.influxdb_flush_interval
+                    .or(file.influxdb.flush_interval)
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL)
+                    .max(1),
+            ),
+        };
+
+        let mut probes = file.probes;
+        let cli_probe = |probe| ProbeEntry { schedule: ScheduleFileConfig::default(), probe };
+
+        // Probes given on the command line replace those of the same type from the file
+        if !args.latency_targets.is_empty() {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Ping(_)));
+            probes.extend(args.latency_targets.into_iter().map(|ping| cli_probe(ProbeConfig::Ping(ping))));
+        }
+        if !args.tcp_targets.is_empty() {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Tcp(_)));
+            probes.extend(args.tcp_targets.into_iter().map(|tcp| cli_probe(ProbeConfig::Tcp(tcp))));
+        }
+        if !args.dns_queries.is_empty() {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Dns(_)));
+            probes.extend(args.dns_queries.into_iter().map(|dns| cli_probe(ProbeConfig::Dns(dns))));
+        }
+        if !args.http_urls.is_empty() {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Http(_)));
+            probes.extend(args.http_urls.into_iter().map(|http| cli_probe(ProbeConfig::Http(http))));
+        }
+        if let Some(url) = args.download_url {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Download(_)));
+            probes.push(cli_probe(ProbeConfig::Download(DownloadProbe {
+                url,
+                label: None,
+                max_bytes: default_download_max_bytes(),
+                max_duration: default_max_duration(),
+            })));
+        }
+        if let Some(interval) = args.public_ip_interval {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::PublicIp(_)));
+            probes.push(ProbeEntry {
+                schedule: ScheduleFileConfig { interval: Some(interval), ..ScheduleFileConfig::default() },
+                probe: ProbeConfig::PublicIp(PublicIpProbe {
+                    label: None,
+                    endpoints: default_public_ip_endpoints(),
+                    asn_lookup: default_asn_lookup(),
+                    timeout: default_public_ip_timeout(),
+                    ip_version: None,
+                }),
+            });
+        }
+        if let Some(url) = args.upload_url {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Upload(_)));
+            probes.push(cli_probe(ProbeConfig::Upload(UploadProbe {
+                url,
+                label: None,
+                bytes: default_upload_bytes(),
+                max_duration: default_max_duration(),
+            })));
+        }
+
+        // Limits given on the command line apply to every probe of that type
+        for ProbeEntry { probe, .. } in &mut probes {
+            match probe {
+                ProbeConfig::Download(download) => {
+                    download.max_bytes = args.download_max_bytes.unwrap_or(download.max_bytes);
+                    download.max_duration = args.download_max_duration.unwrap_or(download.max_duration);
+                }
+                ProbeConfig::Upload(upload) => {
+                    upload.bytes = args.upload_bytes.unwrap_or(upload.bytes);
+                    upload.max_duration = args.upload_max_duration.unwrap_or(upload.max_duration);
+                }
+                ProbeConfig::Dns(dns) => {
+                    if let Some(server) = &dns.server {
+                        dns::parse_server(server)?;
+                    }
+                }
+                ProbeConfig::Tcp(tcp) => {
+                    tcp::split_host_port(&tcp.address)?;
+                }
+                ProbeConfig::PublicIp(public_ip) => {
+                    if !args.public_ip_endpoints.is_empty() {
+                        public_ip.endpoints = args.public_ip_endpoints.clone();
+                    }
+                    if public_ip.endpoints.is_empty() {
+                        bail!("Public IP probe {} has no endpoints", public_ip.label());
+                    }
+                    for endpoint in &public_ip.endpoints {
+                        public_ip::Endpoint::parse(endpoint)?;
+                    }
+                }
+                ProbeConfig::Bufferbloat(bufferbloat) => {
+                    if bufferbloat.download_url.is_none() && bufferbloat.upload_url.is_none() {
+                        bail!("Bufferbloat probe {} needs a download_url or upload_url to load the link with",
+                              bufferbloat.label());
+                    }
+                }
+                ProbeConfig::Interfaces(interfaces) => {
+                    if !args.interfaces.is_empty() {
+                        interfaces.interfaces = args.interfaces.clone();
+                    }
+                }
+                ProbeConfig::Wifi(wifi) => {
+                    if !args.wifi_interfaces.is_empty() {
+                        wifi.interfaces = args.wifi_interfaces.clone();
+                    }
+                }
+                ProbeConfig::Ping(_) | ProbeConfig::Gateway(_) | ProbeConfig::Path(_) | ProbeConfig::Http(_) => {}
+            }
+        }
+
+        if (args.interface_stats || !args.interfaces.is_empty())
+            && !probes.iter().any(|entry| matches!(entry.probe, ProbeConfig::Interfaces(_)))
+        {
+            probes.push(cli_probe(ProbeConfig::Interfaces(InterfacesProbe {
+                label: None,
+                interfaces: args.interfaces.clone(),
+            })));
+        }
+        if (args.wifi_stats || !args.wifi_interfaces.is_empty())
+            && !probes.iter().any(|entry| matches!(entry.probe, ProbeConfig::Wifi(_)))
+        {
+            probes.push(cli_probe(ProbeConfig::Wifi(WifiProbe {
+                label: None,
+                interfaces: args.wifi_interfaces.clone(),
+            })));
+        }
+        if !probes.iter().any(|entry| matches!(entry.probe, ProbeConfig::Ping(_))) {
+            probes.push(cli_probe(ProbeConfig::Ping(DEFAULT_LATENCY_TARGET.parse().expect("valid default target"))));
+        }
+        if args.disable_gateway_probe {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Gateway(_)));
+        } else if !probes.iter().any(|entry| matches!(entry.probe, ProbeConfig::Gateway(_))) {
+            probes.push(cli_probe(ProbeConfig::Gateway(GatewayProbe::default())));
+        }
+        if let Some(interval) = args.path_interval {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Path(_)));
+            let paths: Vec<_> = probes
+                .iter()
+                .filter_map(|entry| match &entry.probe {
+                    ProbeConfig::Ping(ping) => Some(ProbeEntry {
+                        schedule: ScheduleFileConfig { interval: Some(interval), ..entry.schedule },
+                        probe: ProbeConfig::Path(PathProbe {
+                            host: ping.host.clone(),
+                            label: ping.label.clone(),
+                            max_hops: default_max_hops(),
+                            queries: default_path_queries(),
+                            timeout: default_ping_timeout(),
+                        }),
+                    }),
+                    _ => None,
+                })
+                .collect();
+            probes.extend(paths);
+        }
+        if let Some(interval) = args.bufferbloat_interval {
+            probes.retain(|entry| !matches!(entry.probe, ProbeConfig::Bufferbloat(_)));
+            let ping = probes.iter().find_map(|entry| match &entry.probe {
+                ProbeConfig::Ping(ping) => Some(ping),
+                _ => None,
+            });
+            let download_url = probes.iter().find_map(|entry| match &entry.probe {
+                ProbeConfig::Download(download) => Some(download.url.clone()),
+                _ => None,
+            });
+            let upload_url = probes.iter().find_map(|entry| match &entry.probe {
+                ProbeConfig::Upload(upload) => Some(upload.url.clone()),
+                _ => None,
+            });
+            if download_url.is_none() && upload_url.is_none() {
+                bail!("--bufferbloat-interval needs a download or upload test URL to load the link with");
+            }
+            let ping = ping.expect("a ping probe is always configured");
+            let bufferbloat = BufferbloatProbe {
+                host: ping.host.clone(),
+                label: ping.label.clone(),
+                download_url,
+                upload_url,
+                duration: default_bufferbloat_duration(),
+                streams: default_bufferbloat_streams(),
+                timeout: default_ping_timeout(),
+            };
+            probes.push(ProbeEntry {
+                schedule: ScheduleFileConfig { interval: Some(interval), ..ScheduleFileConfig::default() },
+                probe: ProbeConfig::Bufferbloat(bufferbloat),
+            });
+        }
+
+        let ip_version = args.ip_version.unwrap_or_default();
+        let routed = routed_versions();
+        let probes: Vec<_> = probes
+            .into_iter()
+            .flat_map(|ProbeEntry { schedule, probe }| match probe {
+                ProbeConfig::Ping(ping) => ping
+                    .split_versions(ip_version, &routed)
+                    .into_iter()
+                    .map(|ping| ProbeEntry { schedule, probe: ProbeConfig::Ping(ping) })
+                    .collect(),
+                ProbeConfig::Gateway(gateway) => gateway
+                    .split_versions(ip_version, &routed)
+                    .into_iter()
+                    .map(|gateway| ProbeEntry { schedule, probe: ProbeConfig::Gateway(gateway) })
+                    .collect(),
+                ProbeConfig::PublicIp(public_ip) => public_ip
+                    .split_versions(ip_version, &routed)
+                    .into_iter()
+                    .map(|public_ip| ProbeEntry { schedule, probe: ProbeConfig::PublicIp(public_ip) })
+                    .collect(),
+                probe => vec![ProbeEntry { schedule, probe }],
+            })
+            .collect();
+
+        // Command line flags set the defaults, a probe's own values from the file win
+        let interval = args.interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL);
+        let jitter = args.jitter.or(file.jitter).unwrap_or(0);
+        let run_timeout = args.run_timeout.or(file.run_timeout).unwrap_or(DEFAULT_RUN_TIMEOUT);
+        let probes = probes
+            .into_iter()
+            .map(|ProbeEntry { schedule, probe }| ScheduledProbe {
+                probe,
+                schedule: Schedule {
+                    interval: Duration::from_secs(schedule.interval.unwrap_or(interval).max(1)),
+                    jitter: Duration::from_secs(schedule.jitter.unwrap_or(jitter)),
+                    run_timeout: Duration::from_secs(schedule.run_timeout.unwrap_or(run_timeout).max(1)),
+                },
+            })
+            .collect();
+
+        for rule in &file.alerts.rules {
+            if rule.threshold.is_none() && rule.metric != AlertMetric::ProbeDown {
+                bail!("Alert rule {} requires a threshold", rule.name);
+            }
+        }
+        let renotify_interval = args.alert_renotify_interval
+            .or(file.alerts.renotify_interval)
+            .unwrap_or(DEFAULT_RENOTIFY_INTERVAL);
+        let alerts = args.alert_webhook_url.or(file.alerts.webhook_url).map(|webhook_url| AlertsConfig {
+            webhook_url,
+            renotify_interval: Duration::from_secs(renotify_interval),
+            rules: file.alerts.rules,
+        });
+
+        Ok(Settings {
+            influxdb: influxdb_enabled.then_some(influxdb),
+            spool: args.spool_path.or(file.spool.path).map(|path| SpoolConfig {
+                path,
+                max_bytes: args.spool_max_bytes.or(file.spool.max_bytes).unwrap_or(DEFAULT_SPOOL_MAX_BYTES),
+            }),
+            outage_threshold: args.outage_threshold
+                .or(file.outages.threshold)
+                .unwrap_or(DEFAULT_OUTAGE_THRESHOLD),
+            alerts,
+            prometheus_listen: args.prometheus_listen.or(file.prometheus.listen),
+            probes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+
+    /// Held while parsing flags, some tests change the `INTERNET_MONITOR_*` environment.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Parses `args` with the environment variables `env` set.
+    fn try_parse_with_env(env: &[(&str, &str)], args: &[&str]) -> Result<Args, clap::Error> {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, value) in env {
+            std::env::set_var(name, value);
+        }
+        let args = Args::try_parse_from(std::iter::once("internet-monitor").chain(args.iter().copied()));
+        for (name, _) in env {
+            std::env::remove_var(name);
+        }
+        args
+    }
+
+    /// Loads the settings from `toml`, written to a file named after the test, `env` and `args`.
+    fn load_with_env(test: &str, toml: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Settings> {
+        let path = temp_file(test, toml);
+        let mut argv = vec!["--config", path.to_str().unwrap()];
+        argv.extend(args);
+        let settings = Settings::load(try_parse_with_env(env, &argv).unwrap());
+        fs::remove_file(&path).unwrap();
+        settings
+    }
+
+    fn load(test: &str, toml: &str, args: &[&str]) -> Result<Settings> {
+        load_with_env(test, toml, &[], args)
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("config-test-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Type, label and schedule in seconds of every probe, in order.
+    fn summary(settings: &Settings) -> Vec<(&'static str, &str, u64, u64, u64)> {
+        settings.probes
+            .iter()
+            .map(|scheduled| (
+                scheduled.probe.kind(),
+                scheduled.probe.label(),
+                scheduled.schedule.interval.as_secs(),
+                scheduled.schedule.jitter.as_secs(),
+                scheduled.schedule.run_timeout.as_secs(),
+            ))
+            .collect()
+    }
+
+    const SCHEDULES: &str = r#"
+        interval = 30
+        jitter = 2
+
+        [[probes]]
+        type = "ping"
+        host = "example.com"
+
+        [[probes]]
+        type = "download"
+        url = "http://example.com/file"
+        interval = 900
+        run_timeout = 20
+    "#;
+
+    #[test]
+    fn probes_override_file_schedule() {
+        let settings = load("file-schedule", SCHEDULES, &["--ip-version", "4", "--disable-gateway-probe"]).unwrap();
+        assert_eq!(summary(&settings), [
+            ("ping", "example.com", 30, 2, DEFAULT_RUN_TIMEOUT),
+            ("download", "http://example.com/file", 900, 2, 20),
+        ]);
+    }
+
+    #[test]
+    fn flags_override_file_schedule() {
+        let args = ["--ip-version", "4", "--disable-gateway-probe", "--interval", "10", "--run-timeout", "5"];
+        let settings = load("flag-schedule", SCHEDULES, &args).unwrap();
+        // Only the file's defaults, the download keeps its own interval and timeout
+        assert_eq!(summary(&settings), [
+            ("ping", "example.com", 10, 2, 5),
+            ("download", "http://example.com/file", 900, 2, 20),
+        ]);
+    }
+
+    #[test]
+    fn probe_flags_set_their_own_interval() {
+        let args = ["--ip-version", "4", "--disable-gateway-probe", "--interval", "10", "--path-interval", "300"];
+        let settings = load("path-interval", SCHEDULES, &args).unwrap();
+        assert_eq!(summary(&settings)[2], ("path", "example.com", 300, 2, DEFAULT_RUN_TIMEOUT));
+    }
+
+    #[test]
+    fn splits_schedule_from_probe_keys() {
+        let file: FileConfig = toml::from_str(r#"
+            [[probes]]
+            type = "tcp"
+            address = "example.com:443"
+            count = 2
+            interval = 60
+            jitter = 5
+        "#).unwrap();
+        let ProbeEntry { schedule, probe } = &file.probes[0];
+        assert_eq!((schedule.interval, schedule.jitter, schedule.run_timeout), (Some(60), Some(5), None));
+        let ProbeConfig::Tcp(tcp) = probe else { panic!("not a TCP probe: {:?}", probe) };
+        assert_eq!((tcp.address.as_str(), tcp.count, tcp.timeout), ("example.com:443", 2, default_ping_timeout()));
+    }
+
+    fn ping_versions(host: &str, version: Option<IpVersion>, default: IpVersion, routed: &[IpVersion]) -> Vec<IpVersion> {
+        let ping = PingProbe { ip_version: version, ..host.parse::<PingProbe>().unwrap() };
+        ping.split_versions(default, routed).into_iter().map(|ping| ping.ip_version.unwrap()).collect()
+    }
+
+    #[test]
+    fn splits_host_names_over_routed_versions() {
+        use IpVersion::*;
+        let dual_stack = [V4, V6];
+        assert_eq!(ping_versions("example.com", None, Both, &dual_stack), [V4, V6]);
+        // No IPv6 route, no IPv6 probe that could only ever fail
+        assert_eq!(ping_versions("example.com", None, Both, &[V4]), [V4]);
+        // No default route at all yet
+        assert_eq!(ping_versions("example.com", None, Both, &[Auto]), [Auto]);
+        assert_eq!(ping_versions("example.com", None, Auto, &dual_stack), [Auto]);
+    }
+
+    #[test]
+    fn pings_addresses_over_their_own_version() {
+        use IpVersion::*;
+        assert_eq!(ping_versions("192.0.2.1", None, Both, &[V4, V6]), [Auto]);
+        assert_eq!(ping_versions("2001:db8::1", None, Both, &[V4]), [Auto]);
+        assert_eq!(ping_versions("2001:db8::1", Some(Both), V4, &[V4, V6]), [Auto]);
+    }
+
+    #[test]
+    fn probe_version_wins_over_default() {
+        use IpVersion::*;
+        // Pinned probes run even without a route, their failures are the point
+        assert_eq!(ping_versions("example.com", Some(V6), Both, &[V4]), [V6]);
+        assert_eq!(ping_versions("example.com", Some(Both), V4, &[V4, V6]), [V4, V6]);
+        assert_eq!(ping_versions("example.com", Some(Auto), Both, &[V4, V6]), [Auto]);
+
+        let gateway = GatewayProbe::default().split_versions(Both, &[V4]);
+        assert_eq!(gateway.iter().map(|gateway| gateway.ip_version).collect::<Vec<_>>(), [Some(V4)]);
+        let gateway = GatewayProbe { ip_version: Some(V6), ..GatewayProbe::default() }.split_versions(Both, &[V4]);
+        assert_eq!(gateway.iter().map(|gateway| gateway.ip_version).collect::<Vec<_>>(), [Some(V6)]);
+    }
+
+    #[test]
+    fn ip_version_flag_applies_to_targets() {
+        let file = r#"
+            [[probes]]
+            type = "ping"
+            host = "192.0.2.1"
+
+            [[probes]]
+            type = "ping"
+            host = "example.com"
+            ip_version = "6"
+        "#;
+        let settings = load("ip-version", file, &["--ip-version", "both", "--disable-gateway-probe"]).unwrap();
+        let versions: Vec<_> = settings.probes.iter().map(|scheduled| match &scheduled.probe {
+            ProbeConfig::Ping(ping) => (ping.host.as_str(), ping.ip_version),
+            probe => panic!("unexpected probe {:?}", probe),
+        }).collect();
+        assert_eq!(versions, [("192.0.2.1", Some(IpVersion::Auto)), ("example.com", Some(IpVersion::V6))]);
+        // Only pinned probes get an ip_version tag
+        assert_eq!(settings.probes[0].probe.ip_version(), None);
+        assert_eq!(settings.probes[1].probe.ip_version(), Some(IpVersion::V6));
+    }
+
+    #[test]
+    fn http_timeout_defaults_unless_set() {
+        let file: FileConfig = toml::from_str(r#"
+            [[probes]]
+            type = "http"
+            url = "https://example.com/"
+            timeout = 3
+        "#).unwrap();
+        let ProbeConfig::Http(http) = &file.probes[0].probe else { panic!("not an HTTP probe: {:?}", file.probes[0]) };
+        assert_eq!(http.timeout, 3);
+
+        let http: HttpProbe = "example=https://example.com/?a=b".parse().unwrap();
+        assert_eq!((http.label(), http.url.as_str(), http.timeout), ("example", "https://example.com/?a=b", default_http_timeout()));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let unknown_probe_key = toml::from_str::<FileConfig>(r#"
+            [[probes]]
+            type = "ping"
+            host = "example.com"
+            intervall = 60
+        "#).unwrap_err();
+        assert!(unknown_probe_key.to_string().contains("unknown field `intervall`"), "{}", unknown_probe_key);
+
+        let bad_schedule = toml::from_str::<FileConfig>(r#"
+            [[probes]]
+            type = "ping"
+            host = "example.com"
+            interval = "1m"
+        "#).unwrap_err();
+        assert!(bad_schedule.to_string().contains("invalid type"), "{}", bad_schedule);
+
+        let unknown_type = toml::from_str::<FileConfig>("[[probes]]\ntype = \"smoke\"\n").unwrap_err();
+        assert!(unknown_type.to_string().contains("unknown variant `smoke`"), "{}", unknown_type);
+
+        assert!(toml::from_str::<FileConfig>("[influxdb]\ndatabase = \"metrics\"\n").is_err());
+    }
+
+    #[test]
+    fn flag_probes_replace_file_probes_of_their_type() {
+        let toml = r#"
+            [[probes]]
+            type = "ping"
+            host = "a.example.com"
+
+            [[probes]]
+            type = "tcp"
+            address = "example.com:22"
+
+            [[probes]]
+            type = "ping"
+            host = "b.example.com"
+            interval = 60
+        "#;
+        let args = ["--ip-version", "4", "--disable-gateway-probe", "--latency-target", "c=c.example.com"];
+        let settings = load("replace", toml, &args).unwrap();
+        assert_eq!(summary(&settings), [
+            ("tcp", "example.com:22", DEFAULT_INTERVAL, 0, DEFAULT_RUN_TIMEOUT),
+            ("ping", "c", DEFAULT_INTERVAL, 0, DEFAULT_RUN_TIMEOUT),
+        ]);
+    }
+
+    #[test]
+    fn adds_default_probes() {
+        let settings = load("defaults", "", &["--ip-version", "4"]).unwrap();
+        let probes: Vec<_> = settings.probes.iter().map(|scheduled| (scheduled.probe.kind(), scheduled.probe.label())).collect();
+        assert_eq!(probes, [("ping", DEFAULT_LATENCY_TARGET), ("gateway", GATEWAY_TARGET)]);
+
+        let toml = r#"
+            [[probes]]
+            type = "gateway"
+            label = "router"
+        "#;
+        let settings = load("own-gateway", toml, &["--ip-version", "4"]).unwrap();
+        let probes: Vec<_> = settings.probes.iter().map(|scheduled| (scheduled.probe.kind(), scheduled.probe.label())).collect();
+        assert_eq!(probes, [("gateway", "router"), ("ping", DEFAULT_LATENCY_TARGET)]);
+
+        let settings = load("no-gateway", toml, &["--ip-version", "4", "--disable-gateway-probe"]).unwrap();
+        assert_eq!(summary(&settings), [("ping", DEFAULT_LATENCY_TARGET, DEFAULT_INTERVAL, 0, DEFAULT_RUN_TIMEOUT)]);
+    }
+
+    #[test]
+    fn flags_override_file_values() {
+        let toml = r#"
+            [influxdb]
+            url = "http://file:8086"
+            db = "file"
+            batch_size = 10
+
+            [outages]
+            threshold = 5
+        "#;
+        let settings = load("values", toml, &["--influxdb-db", "flag", "--outage-threshold", "2"]).unwrap();
+        let influxdb = settings.influxdb.unwrap();
+        assert_eq!((influxdb.url.as_str(), influxdb.db.as_str(), influxdb.batch_size), ("http://file:8086", "flag", 10));
+        assert_eq!(settings.outage_threshold, 2);
+    }
+
+    #[test]
+    fn reads_environment_variables() {
+        let env = [
+            ("INTERNET_MONITOR_INTERVAL", "7"),
+            ("INTERNET_MONITOR_INFLUXDB_VERSION", "2"),
+            ("INTERNET_MONITOR_DISABLE_GATEWAY_PROBE", "true"),
+            ("INTERNET_MONITOR_LATENCY_TARGETS", "cloudflare=1.1.1.1,example.com"),
+        ];
+        let args = try_parse_with_env(&env, &[]).unwrap();
+        assert_eq!(args.interval, Some(7));
+        assert_eq!(args.influxdb_version, Some(InfluxDbVersion::V2));
+        assert!(args.disable_gateway_probe);
+        let targets: Vec<_> = args.latency_targets.iter().map(|ping| (ping.label(), ping.host.as_str())).collect();
+        assert_eq!(targets, [("cloudflare", "1.1.1.1"), ("example.com", "example.com")]);
+
+        let e = try_parse_with_env(&[("INTERNET_MONITOR_INTERVAL", "soon")], &[]).unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn flags_override_environment_variables() {
+        let env = [("INTERNET_MONITOR_INTERVAL", "7"), ("INTERNET_MONITOR_INFLUXDB_DB", "env")];
+        let args = try_parse_with_env(&env, &["--interval", "3"]).unwrap();
+        assert_eq!(args.interval, Some(3));
+        assert_eq!(args.influxdb_db.as_deref(), Some("env"));
+    }
+
+    #[test]
+    fn environment_variables_override_file() {
+        let toml = r#"
+            interval = 30
+
+            [influxdb]
+            db = "file"
+            org = "file"
+        "#;
+        let env = [("INTERNET_MONITOR_INTERVAL", "7"), ("INTERNET_MONITOR_INFLUXDB_DB", "env")];
+        let settings = load_with_env("env", toml, &env, &["--ip-version", "4", "--disable-gateway-probe"]).unwrap();
+        assert_eq!(summary(&settings), [("ping", DEFAULT_LATENCY_TARGET, 7, 0, DEFAULT_RUN_TIMEOUT)]);
+        let influxdb = settings.influxdb.unwrap();
+        assert_eq!((influxdb.db.as_str(), influxdb.org.as_deref()), ("env", Some("file")));
+    }
+
+    #[test]
+    fn reads_secret_files() {
+        let password = temp_file("password", "s3cret\r\n");
+        let token = temp_file("token", "t0ken\n");
+        let toml = format!("[influxdb]\npassword = \"file\"\ntoken_file = \"{}\"\n", token.display());
+        let settings = load("secrets", &toml, &["--influxdb-password-file", password.to_str().unwrap()]);
+        fs::remove_file(&password).unwrap();
+        fs::remove_file(&token).unwrap();
+
+        let influxdb = settings.unwrap().influxdb.unwrap();
+        assert_eq!(influxdb.password.as_deref(), Some("s3cret"));
+        assert_eq!(influxdb.token.as_deref(), Some("t0ken"));
+
+        let missing = load("missing-secret", "", &["--influxdb-token-file", "/nonexistent/token"]).unwrap_err();
+        assert!(format!("{:#}", missing).contains("Failed to read secret file /nonexistent/token"), "{:#}", missing);
+    }
+
+    #[test]
+    fn secret_files_conflict_with_inline_secrets() {
+        let e = try_parse_with_env(&[], &["--influxdb-password", "a", "--influxdb-password-file", "/run/secrets/a"])
+            .unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let e = try_parse_with_env(&[("INTERNET_MONITOR_INFLUXDB_TOKEN_FILE", "/run/secrets/b")], &["--influxdb-token", "b"])
+            .unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+}
@@ -0,0 +1,490 @@
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{self, Instant};
+use tracing::debug;
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const CLASS_IN: u16 = 1;
+const TYPE_OPT: u16 = 41;
+/// UDP payload size advertised with EDNS, the DNS flag day 2020 recommendation.
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+/// Compression pointers followed while decoding a name before giving up on a loop.
+const MAX_POINTERS: usize = 32;
+
+/// Resource record type of a query, e.g. `A` or `AAAA`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RecordType(u16);
+
+const RECORD_TYPES: &[(&str, u16)] = &[
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", 28),
+    ("SRV", 33),
+    ("HTTPS", 65),
+    ("CAA", 257),
+];
+
+impl RecordType {
+    pub const A: RecordType = RecordType(1);
+    const NS: RecordType = RecordType(2);
+    const CNAME: RecordType = RecordType(5);
+    const PTR: RecordType = RecordType(12);
+    const MX: RecordType = RecordType(15);
+    pub const TXT: RecordType = RecordType(16);
+    const AAAA: RecordType = RecordType(28);
+}
+
+impl FromStr for RecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RECORD_TYPES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|&(_, code)| RecordType(code))
+            .ok_or_else(|| format!("unsupported record type '{}'", s))
+    }
+}
+
+impl TryFrom<String> for RecordType {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match RECORD_TYPES.iter().find(|&&(_, code)| code == self.0) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "TYPE{}", self.0),
+        }
+    }
+}
+
+/// Response to a single query.
+#[derive(Debug, Clone)]
+pub struct DnsResult {
+    pub server: SocketAddr,
+    /// Time from sending the query to the complete response, including a TCP retry.
+    pub rtt: Duration,
+    pub rcode: u8,
+    /// Number of records in the answer section, CNAMEs included.
+    pub answer_count: u16,
+    /// Records of the requested type, formatted and sorted.
+    pub answers: Vec<String>,
+    /// Whether the UDP response was truncated and the query repeated over TCP.
+    pub tcp: bool,
+    /// Whether the answers differ from the previous successful run, `None` without one.
+    pub changed: Option<bool>,
+}
+
+impl DnsResult {
+    /// Name of the response code, e.g. `NXDOMAIN`.
+    pub fn rcode_name(&self) -> String {
+        match self.rcode {
+            0 => "NOERROR".to_string(),
+            1 => "FORMERR".to_string(),
+            2 => "SERVFAIL".to_string(),
+            3 => "NXDOMAIN".to_string(),
+            4 => "NOTIMP".to_string(),
+            5 => "REFUSED".to_string(),
+            rcode => format!("RCODE{}", rcode),
+        }
+    }
+}
+
+/// Parses a resolver address given as `ip` or `ip:port`, IPv6 with brackets when a port is given.
+pub fn parse_server(server: &str) -> Result<SocketAddr> {
+    server.parse::<SocketAddr>()
+        .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+        .map_err(|_| anyhow!("invalid DNS server '{}', expected an IP address with an optional port", server))
+}
+
+/// First `nameserver` of `/etc/resolv.conf`, the resolver the system uses.
+pub fn system_resolver() -> Result<SocketAddr> {
+    let contents = fs::read_to_string("/etc/resolv.conf").context("Failed to read /etc/resolv.conf")?;
+    contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().split('%').next()?.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .next()
+        .ok_or_else(|| anyhow!("No nameserver in /etc/resolv.conf"))
+}
+
+/// Sends a query for `name` straight to `server` over UDP and repeats it over TCP
+/// when the response is truncated.
+pub async fn query(server: SocketAddr, name: &str, record_type: RecordType, timeout: Duration) -> Result<DnsResult> {
+    let id = rand::random::<u16>();
+    let request = build_query(id, name, record_type)?;
+    let start = Instant::now();
+
+    let deadline = start + timeout;
+    let mut response = time::timeout_at(deadline, query_udp(server, id, &request))
+        .await
+        .map_err(|_| anyhow!("No response from {} within {} seconds", server, timeout.as_secs()))??;
+    let mut tcp = false;
+    if u16::from_be_bytes([response[2], response[3]]) & FLAG_TRUNCATED != 0 {
+        debug!("Response from {} truncated, retrying over TCP", server);
+        response = time::timeout_at(deadline, query_tcp(server, id, &request))
+            .await
+            .map_err(|_| anyhow!("No TCP response from {} within {} seconds", server, timeout.as_secs()))??;
+        tcp = true;
+    }
+    let rtt = start.elapsed();
+
+    let (rcode, answer_count, mut answers) = parse_response(&response, record_type)
+        .with_context(|| format!("Invalid response from {}", server))?;
+    answers.sort();
+    Ok(DnsResult {
+        server,
+        rtt,
+        rcode,
+        answer_count,
+        answers,
+        tcp,
+        changed: None,
+    })
+}
+
+async fn query_udp(server: SocketAddr, id: u16, request: &[u8]) -> Result<Vec<u8>> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await.context("Failed to open UDP socket")?;
+    socket.connect(server).await.with_context(|| format!("Failed to connect to {}", server))?;
+    socket.send(request).await.with_context(|| format!("Failed to send query to {}", server))?;
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let len = socket.recv(&mut buf).await.with_context(|| format!("Failed to receive from {}", server))?;
+        // Stray datagrams, e.g. late responses to an earlier query, are skipped
+        if len >= HEADER_LEN && u16::from_be_bytes([buf[0], buf[1]]) == id {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn query_tcp(server: SocketAddr, id: u16, request: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await.with_context(|| format!("Failed to connect to {}", server))?;
+    let mut message = Vec::with_capacity(request.len() + 2);
+    message.extend_from_slice(&(request.len() as u16).to_be_bytes());
+    message.extend_from_slice(request);
+    stream.write_all(&message).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await?;
+    if len < HEADER_LEN || u16::from_be_bytes([response[0], response[1]]) != id {
+        bail!("Unexpected TCP response from {}", server);
+    }
+    Ok(response)
+}
+
+fn build_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(HEADER_LEN + name.len() + 16);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question, no answers or authority records, one additional record for EDNS
+    for count in [1u16, 0, 0, 1] {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            bail!("DNS label '{}' is longer than 63 bytes", label);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.0.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    // OPT pseudo record: root name, type, UDP payload size, extended rcode and flags, no data
+    message.push(0);
+    message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    message.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(message)
+}
+
+/// Returns the rcode, the size of the answer section and the formatted records of `record_type`.
+fn parse_response(message: &[u8], record_type: RecordType) -> Result<(u8, u16, Vec<String>)> {
+    if message.len() < HEADER_LEN {
+        bail!("message is too short");
+    }
+    let flags = u16::from_be_bytes([message[2], message[3]]);
+    if flags & FLAG_RESPONSE == 0 {
+        bail!("message is not a response");
+    }
+    let rcode = (flags & 0x000f) as u8;
+    let question_count = u16::from_be_bytes([message[4], message[5]]);
+    let answer_count = u16::from_be_bytes([message[6], message[7]]);
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..question_count {
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+        offset = skip_name(message, offset)?;
+        let header = message.get(offset..offset + 10).ok_or_else(|| anyhow!("truncated record"))?;
+        let rr_type = u16::from_be_bytes([header[0], header[1]]);
+        let rdata_len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata_start = offset + 10;
+        let rdata = message.get(rdata_start..rdata_start + rdata_len).ok_or_else(|| anyhow!("truncated record"))?;
+        if rr_type == record_type.0 {
+            answers.push(format_rdata(message, RecordType(rr_type), rdata_start, rdata)?);
+        }
+        offset = rdata_start + rdata_len;
+    }
+
+    Ok((rcode, answer_count, answers))
+}
+
+fn format_rdata(message: &[u8], record_type: RecordType, start: usize, rdata: &[u8]) -> Result<String> {
+    Ok(match record_type {
+        RecordType::A if rdata.len() == 4 => Ipv4Addr::from(<[u8; 4]>::try_from(rdata)?).to_string(),
+        RecordType::AAAA if rdata.len() == 16 => Ipv6Addr::from(<[u8; 16]>::try_from(rdata)?).to_string(),
+        RecordType::NS | RecordType::CNAME | RecordType::PTR => read_name(message, start)?,
+        RecordType::MX if rdata.len() > 2 => {
+            format!("{} {}", u16::from_be_bytes([rdata[0], rdata[1]]), read_name(message, start + 2)?)
+        }
+        RecordType::TXT => {
+            let mut strings = Vec::new();
+            let mut rest = rdata;
+            while let Some((&len, tail)) = rest.split_first() {
+                let len = (len as usize).min(tail.len());
+                strings.push(String::from_utf8_lossy(&tail[..len]).into_owned());
+                rest = &tail[len..];
+            }
+            strings.join("")
+        }
+        _ => rdata.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    })
+}
+
+/// Returns the offset right after the name starting at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(offset).ok_or_else(|| anyhow!("truncated name"))?;
+        match len {
+            0 => return Ok(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// Decodes the name starting at `offset`, following compression pointers.
+fn read_name(message: &[u8], mut offset: usize) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(offset).ok_or_else(|| anyhow!("truncated name"))?;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                bail!("compression pointer loop");
+            }
+            let low = *message.get(offset + 1).ok_or_else(|| anyhow!("truncated name"))?;
+            offset = (((len & 0x3f) as usize) << 8) | low as usize;
+            continue;
+        }
+        let label = message.get(offset + 1..offset + 1 + len as usize).ok_or_else(|| anyhow!("truncated name"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len as usize;
+    }
+    Ok(labels.join(".") + ".")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use super::*;
+
+    /// Header of a response to a single question with `answers` records and no others.
+    fn header(id: u16, flags: u16, answers: u16) -> Vec<u8> {
+        let mut message = Vec::new();
+        for value in [id, flags, 1, answers, 0, 0] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message
+    }
+
+    /// Question for `example.com`, its name starts at offset 12.
+    fn question(record_type: RecordType) -> Vec<u8> {
+        let mut question = b"\x07example\x03com\x00".to_vec();
+        question.extend_from_slice(&record_type.0.to_be_bytes());
+        question.extend_from_slice(&CLASS_IN.to_be_bytes());
+        question
+    }
+
+    /// A record of class IN with a TTL of 300 seconds.
+    fn record(name: &[u8], record_type: RecordType, rdata: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&record_type.0.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300u32.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    fn a_response() -> Vec<u8> {
+        let mut message = header(0x1234, 0x8180, 2);
+        message.extend(question(RecordType::A));
+        message.extend(record(b"\xc0\x0c", RecordType::A, &[93, 184, 216, 34]));
+        message.extend(record(b"\xc0\x0c", RecordType::A, &[93, 184, 216, 35]));
+        message
+    }
+
+    #[test]
+    fn parses_answers() {
+        let (rcode, answer_count, answers) = parse_response(&a_response(), RecordType::A).unwrap();
+        assert_eq!(rcode, 0);
+        assert_eq!(answer_count, 2);
+        assert_eq!(answers, ["93.184.216.34", "93.184.216.35"]);
+
+        // Records of other types are counted but not returned
+        let (_, answer_count, answers) = parse_response(&a_response(), RecordType::AAAA).unwrap();
+        assert_eq!(answer_count, 2);
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn parses_rcode_without_answers() {
+        let mut message = header(0x1234, 0x8183, 0);
+        message.extend(question(RecordType::A));
+        let (rcode, answer_count, answers) = parse_response(&message, RecordType::A).unwrap();
+        assert_eq!((rcode, answer_count), (3, 0));
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut message = header(0x1234, 0x8180, 3);
+        message.extend(question(RecordType::A));
+        // www.example.com, the rest of the name points at the question
+        let cname_rdata = message.len() + 12;
+        message.extend(record(b"\xc0\x0c", RecordType::CNAME, b"\x03www\xc0\x0c"));
+        // cdn.www.example.com, a pointer to a name that ends in a pointer itself
+        let mut target = b"\x03cdn".to_vec();
+        target.extend_from_slice(&(0xc000 | cname_rdata as u16).to_be_bytes());
+        message.extend(record(&target, RecordType::A, &[192, 0, 2, 1]));
+        let mut mx = vec![0, 10];
+        mx.extend_from_slice(&(0xc000 | cname_rdata as u16).to_be_bytes());
+        message.extend(record(b"\xc0\x0c", RecordType::MX, &mx));
+
+        let (_, answer_count, answers) = parse_response(&message, RecordType::CNAME).unwrap();
+        assert_eq!(answer_count, 3);
+        assert_eq!(answers, ["www.example.com."]);
+        let (_, _, answers) = parse_response(&message, RecordType::A).unwrap();
+        assert_eq!(answers, ["192.0.2.1"]);
+        let (_, _, answers) = parse_response(&message, RecordType::MX).unwrap();
+        assert_eq!(answers, ["10 www.example.com."]);
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut message = header(0x1234, 0x8180, 1);
+        message.extend(question(RecordType::CNAME));
+        // The CNAME target points at itself
+        let rdata = message.len() + 12;
+        message.extend(record(b"\xc0\x0c", RecordType::CNAME, &(0xc000 | rdata as u16).to_be_bytes()));
+        let error = parse_response(&message, RecordType::CNAME).unwrap_err();
+        assert_eq!(error.to_string(), "compression pointer loop");
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let message = a_response();
+        assert_eq!(parse_response(&message[..8], RecordType::A).unwrap_err().to_string(), "message is too short");
+        assert_eq!(parse_response(&message[..20], RecordType::A).unwrap_err().to_string(), "truncated name");
+        assert_eq!(parse_response(&message[..message.len() - 2], RecordType::A).unwrap_err().to_string(), "truncated record");
+        // A pointer whose second byte is missing
+        let mut cut = header(0x1234, 0x8180, 1);
+        cut.extend(question(RecordType::CNAME));
+        cut.extend(record(b"\xc0\x0c", RecordType::CNAME, b"\x03www\xc0"));
+        assert_eq!(parse_response(&cut, RecordType::CNAME).unwrap_err().to_string(), "truncated name");
+    }
+
+    #[test]
+    fn rejects_queries() {
+        let message = build_query(0x1234, "example.com", RecordType::A).unwrap();
+        assert_eq!(parse_response(&message, RecordType::A).unwrap_err().to_string(), "message is not a response");
+    }
+
+    #[test]
+    fn parses_txt_strings() {
+        let mut message = header(0x1234, 0x8180, 1);
+        message.extend(question(RecordType::TXT));
+        message.extend(record(b"\xc0\x0c", RecordType::TXT, b"\x05hello\x06 world"));
+        let (_, _, answers) = parse_response(&message, RecordType::TXT).unwrap();
+        assert_eq!(answers, ["hello world"]);
+    }
+
+    /// A resolver on a free local port that answers every UDP query with TC=1 and no
+    /// records, and TCP queries with the full answer.
+    async fn spawn_truncating_resolver() -> SocketAddr {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((_, peer)) = udp.recv_from(&mut buf).await {
+                let mut message = header(u16::from_be_bytes([buf[0], buf[1]]), 0x8180 | FLAG_TRUNCATED, 0);
+                message.extend(question(RecordType::A));
+                udp.send_to(&message, peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut request = vec![0u8; len];
+                stream.read_exact(&mut request).await.unwrap();
+                let mut message = a_response();
+                message[..2].copy_from_slice(&request[..2]);
+                stream.write_all(&(message.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&message).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_responses_over_tcp() {
+        let server = spawn_truncating_resolver().await;
+        let result = query(server, "example.com", RecordType::A, Duration::from_secs(5)).await.unwrap();
+        assert!(result.tcp);
+        assert_eq!(result.rcode, 0);
+        assert_eq!(result.answer_count, 2);
+        assert_eq!(result.answers, ["93.184.216.34", "93.184.216.35"]);
+    }
+}
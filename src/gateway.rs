@@ -0,0 +1,205 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use anyhow::{anyhow, Context, Result};
+use crate::config::IpVersion;
+
+const ROUTE_TABLE_V4: &str = "/proc/net/route";
+const ROUTE_TABLE_V6: &str = "/proc/net/ipv6_route";
+const RTF_UP: u32 = 0x0001;
+const RTF_GATEWAY: u32 = 0x0002;
+
+/// The router a default route points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    pub addr: IpAddr,
+    /// Interface the route goes out of.
+    pub interface: String,
+    metric: u32,
+}
+
+impl Gateway {
+    /// Index of the interface, needed to reach link-local IPv6 gateways. 0 when the
+    /// interface is gone.
+    pub fn scope_id(&self) -> u32 {
+        match CString::new(self.interface.as_str()) {
+            // SAFETY: name is a NUL-terminated string that outlives the call.
+            Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) },
+            Err(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} via {}", self.addr, self.interface)
+    }
+}
+
+/// The gateway of the default route with the lowest metric. `Auto` prefers IPv4 and
+/// falls back to IPv6.
+pub fn default_gateway(version: IpVersion) -> Result<Gateway> {
+    let gateway = match version {
+        IpVersion::V4 => gateways_v4()?.into_iter().next(),
+        IpVersion::V6 => gateways_v6()?.into_iter().next(),
+        IpVersion::Auto | IpVersion::Both => match gateways_v4()?.into_iter().next() {
+            Some(gateway) => Some(gateway),
+            None => gateways_v6()?.into_iter().next(),
+        },
+    };
+    gateway.ok_or_else(|| match version {
+        IpVersion::V4 | IpVersion::V6 => anyhow!("No IPv{} default route", version),
+        IpVersion::Auto | IpVersion::Both => anyhow!("No default route"),
+    })
+}
+
+/// Whether there is a default route over `version`, hosts without IPv6 often have none.
+pub fn has_default_route(version: IpVersion) -> bool {
+    default_gateway(version).is_ok()
+}
+
+/// IPv4 default routes through a gateway, lowest metric first.
+fn gateways_v4() -> Result<Vec<Gateway>> {
+    let table = fs::read_to_string(ROUTE_TABLE_V4).with_context(|| format!("Failed to read {}", ROUTE_TABLE_V4))?;
+    Ok(parse_routes_v4(&table))
+}
+
+/// IPv6 default routes through a gateway, lowest metric first.
+fn gateways_v6() -> Result<Vec<Gateway>> {
+    // Missing when IPv6 is disabled, which just means there is no IPv6 gateway
+    let table = fs::read_to_string(ROUTE_TABLE_V6).unwrap_or_default();
+    Ok(parse_routes_v6(&table))
+}
+
+/// Parses `/proc/net/route`: `Iface Destination Gateway Flags RefCnt Use Metric Mask ...`
+/// with the addresses as hex in host byte order.
+fn parse_routes_v4(table: &str) -> Vec<Gateway> {
+    let mut gateways: Vec<Gateway> = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let hex = |index: usize| fields.get(index).and_then(|field| u32::from_str_radix(field, 16).ok());
+            let (destination, gateway, flags, mask) = (hex(1)?, hex(2)?, hex(3)?, hex(7)?);
+            let metric = fields.get(6)?.parse().ok()?;
+            let default_route = destination == 0 && mask == 0 && flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY;
+            default_route.then(|| Gateway {
+                addr: IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())),
+                interface: fields[0].to_string(),
+                metric,
+            })
+        })
+        .collect();
+    gateways.sort_by_key(|gateway| gateway.metric);
+    gateways
+}
+
+/// Parses `/proc/net/ipv6_route`: `destination prefix_len source prefix_len next_hop
+/// metric refcnt use flags iface`, all numbers in hex.
+fn parse_routes_v6(table: &str) -> Vec<Gateway> {
+    let mut gateways: Vec<Gateway> = table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let destination = u128::from_str_radix(fields[0], 16).ok()?;
+            let prefix_len = u8::from_str_radix(fields[1], 16).ok()?;
+            let next_hop = u128::from_str_radix(fields[4], 16).ok()?;
+            let metric = u32::from_str_radix(fields[5], 16).ok()?;
+            let flags = u32::from_str_radix(fields[8], 16).ok()?;
+            let default_route = destination == 0 && prefix_len == 0 && next_hop != 0
+                && flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY;
+            default_route.then(|| Gateway {
+                addr: IpAddr::V6(Ipv6Addr::from(next_hop)),
+                interface: fields[9].to_string(),
+                metric,
+            })
+        })
+        .collect();
+    gateways.sort_by_key(|gateway| gateway.metric);
+    gateways
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES_V4: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+";
+
+    #[test]
+    fn parses_v4_default_routes_by_metric() {
+        let gateways = parse_routes_v4(ROUTES_V4);
+        assert_eq!(gateways.len(), 2);
+        assert_eq!(gateways[0].addr, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(gateways[0].interface, "eth0");
+        assert_eq!(gateways[0].metric, 100);
+        assert_eq!(gateways[1].addr, "192.168.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(gateways[1].to_string(), "192.168.1.1 via wlan0");
+    }
+
+    #[test]
+    fn v4_without_default_route() {
+        let table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+tun0\t00000000\t00000000\t0001\t0\t0\t50\t00000000\t0\t0\t0
+eth1\t00000000\t0101A8C0\t0002\t0\t0\t100\t00000000\t0\t0\t0
+";
+        // A default route without gateway (point-to-point) and one that is down
+        assert!(parse_routes_v4(table).is_empty());
+        assert!(parse_routes_v4("").is_empty());
+        assert!(parse_routes_v4("Iface\tDestination\tGateway\neth0\tgarbage\n").is_empty());
+    }
+
+    const ROUTES_V6: &str = "\
+20010db8000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe80000000000000021122fffe334455 00000400 00000001 00000000 00450003     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 20010db8000000000000000000000001 00000064 00000001 00000000 00000003     wg0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+";
+
+    #[test]
+    fn parses_v6_default_routes_by_metric() {
+        let gateways = parse_routes_v6(ROUTES_V6);
+        assert_eq!(gateways.len(), 2);
+        assert_eq!(gateways[0].addr, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(gateways[0].interface, "wg0");
+        assert_eq!(gateways[0].metric, 100);
+        // Router advertisements usually give a link-local gateway
+        assert_eq!(gateways[1].addr, "fe80::211:22ff:fe33:4455".parse::<IpAddr>().unwrap());
+        assert_eq!(gateways[1].interface, "eth0");
+        assert_eq!(gateways[1].metric, 1024);
+    }
+
+    #[test]
+    fn v6_without_default_route() {
+        // Only on-link prefixes and the unreachable route on lo
+        let table: String = ROUTES_V6.lines().filter(|line| !line.starts_with("0000")).collect::<Vec<_>>().join("\n");
+        assert!(parse_routes_v6(&table).is_empty());
+        let unreachable = ROUTES_V6.lines().last().unwrap();
+        assert!(parse_routes_v6(unreachable).is_empty());
+        assert!(parse_routes_v6("").is_empty());
+    }
+
+    #[test]
+    fn scope_id_of_missing_interface() {
+        let gateway = Gateway {
+            addr: "fe80::1".parse().unwrap(),
+            interface: "does-not-exist0".to_string(),
+            metric: 0,
+        };
+        assert_eq!(gateway.scope_id(), 0);
+        let gateway = Gateway { interface: "bad\0name".to_string(), ..gateway };
+        assert_eq!(gateway.scope_id(), 0);
+    }
+}
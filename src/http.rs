@@ -0,0 +1,237 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::body::HttpBody;
+use hyper::{header, Body, Request};
+use reqwest::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+/// Time spent in each phase of a single HTTP request.
+#[derive(Debug, Clone)]
+pub struct HttpTiming {
+    pub addr: SocketAddr,
+    pub status: u16,
+    /// Resolving the host name, zero for IP addresses.
+    pub dns: Duration,
+    /// Establishing the TCP connection.
+    pub connect: Duration,
+    /// The TLS handshake, `None` for plain HTTP.
+    pub tls: Option<Duration>,
+    /// From sending the request until the response headers arrived.
+    pub ttfb: Duration,
+    /// The whole request, from resolving the name to the end of the body.
+    pub total: Duration,
+    pub body_bytes: u64,
+}
+
+impl HttpTiming {
+    /// Anything but client and server errors, redirects are not followed.
+    pub fn is_success(&self) -> bool {
+        self.status < 400
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    static CONNECTOR: OnceLock<TlsConnector> = OnceLock::new();
+    CONNECTOR
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+            }));
+            let mut config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            TlsConnector::from(Arc::new(config))
+        })
+        .clone()
+}
+
+/// GETs `url` over a fresh connection, timing name resolution, connecting, the TLS
+/// handshake and the response separately. Redirects are not followed. Connecting, the
+/// handshake and waiting for the response headers each give up after `timeout`.
+pub async fn get(url: &str, timeout: Duration) -> Result<HttpTiming> {
+    let url = Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    let https = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        scheme => bail!("Unsupported URL scheme {}", scheme),
+    };
+    let host = url.host_str().ok_or_else(|| anyhow!("URL {} has no host", url))?;
+    let port = url.port_or_known_default().unwrap_or(if https { 443 } else { 80 });
+    let started = Instant::now();
+
+    // Brackets of IPv6 literals are kept by `host_str`
+    let addr = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("Failed to resolve {}", host))?
+            .next()
+            .ok_or_else(|| anyhow!("{} did not resolve to any address", host))?,
+    };
+    let dns = started.elapsed();
+
+    let connect_started = Instant::now();
+    let stream = time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow!("Connecting to {} timed out after {:?}", addr, timeout))?
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    stream.set_nodelay(true)?;
+    let connect = connect_started.elapsed();
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let request = Request::get(path)
+        .header(header::HOST, host_header)
+        .header(header::USER_AGENT, concat!("internet-monitor/", env!("CARGO_PKG_VERSION")))
+        .header(header::ACCEPT, "*/*")
+        .body(Body::empty())?;
+
+    let (tls, (status, ttfb, body_bytes)) = if https {
+        let tls_started = Instant::now();
+        let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+            .map_err(|_| anyhow!("Invalid TLS server name {}", host))?;
+        let stream = time::timeout(timeout, tls_connector().connect(server_name, stream))
+            .await
+            .map_err(|_| anyhow!("TLS handshake with {} timed out after {:?}", host, timeout))?
+            .with_context(|| format!("TLS handshake with {} failed", host))?;
+        (Some(tls_started.elapsed()), exchange(stream, request, timeout).await?)
+    } else {
+        (None, exchange(stream, request, timeout).await?)
+    };
+
+    Ok(HttpTiming {
+        addr,
+        status,
+        dns,
+        connect,
+        tls,
+        ttfb,
+        total: started.elapsed(),
+        body_bytes,
+    })
+}
+
+/// Sends `request` over `io` and reads the whole response, returning the status,
+/// the time until the headers arrived and the body size.
+async fn exchange<S>(io: S, request: Request<Body>, timeout: Duration) -> Result<(u16, Duration, u64)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("HTTP connection closed with error: {}", e);
+        }
+    });
+
+    let sent = Instant::now();
+    let mut response = time::timeout(timeout, sender.send_request(request))
+        .await
+        .map_err(|_| anyhow!("No response headers within {:?}", timeout))?
+        .context("Request failed")?;
+    let ttfb = sent.elapsed();
+
+    let mut body_bytes = 0u64;
+    while let Some(chunk) = response.body_mut().data().await {
+        body_bytes += chunk.context("Failed to read response body")?.len() as u64;
+    }
+    Ok((response.status().as_u16(), ttfb, body_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use tokio::net::TcpListener;
+    use super::*;
+
+    /// Starts an HTTP server on a free local port that answers every request with
+    /// `status` and a short body after `delay`.
+    fn spawn_server(status: StatusCode, delay: Duration) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                time::sleep(delay).await;
+                let mut response = Response::new(Body::from("hello"));
+                *response.status_mut() = status;
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn times_each_phase() {
+        let delay = Duration::from_millis(50);
+        let addr = spawn_server(StatusCode::OK, delay);
+        let timing = get(&format!("http://{}/generate_204", addr), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(timing.addr, addr);
+        assert_eq!((timing.status, timing.body_bytes), (200, 5));
+        assert!(timing.is_success());
+        assert!(timing.tls.is_none());
+        // Waiting for the server counts as time to first byte, and every phase is part of the total
+        assert!(timing.ttfb >= delay, "{:?}", timing);
+        assert!(timing.connect > Duration::ZERO, "{:?}", timing);
+        assert!(timing.dns + timing.connect + timing.ttfb <= timing.total, "{:?}", timing);
+    }
+
+    #[tokio::test]
+    async fn reports_error_status() {
+        let addr = spawn_server(StatusCode::SERVICE_UNAVAILABLE, Duration::ZERO);
+        let timing = get(&format!("http://{}/", addr), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(timing.status, 503);
+        assert!(!timing.is_success());
+
+        let addr = spawn_server(StatusCode::NOT_FOUND, Duration::ZERO);
+        assert!(!get(&format!("http://{}/", addr), Duration::from_secs(5)).await.unwrap().is_success());
+        let addr = spawn_server(StatusCode::FOUND, Duration::ZERO);
+        assert!(get(&format!("http://{}/", addr), Duration::from_secs(5)).await.unwrap().is_success());
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_for_headers() {
+        let addr = spawn_server(StatusCode::OK, Duration::from_secs(30));
+        let error = get(&format!("http://{}/", addr), Duration::from_millis(100)).await.unwrap_err();
+        assert!(error.to_string().contains("No response headers"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_silent_tls_handshake() {
+        // Accepts connections but never answers the client hello
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let error = get(&format!("https://{}/", addr), Duration::from_millis(100)).await.unwrap_err();
+        assert!(error.to_string().contains("TLS handshake"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_urls() {
+        assert!(get("ftp://example.com/", Duration::from_secs(1)).await.is_err());
+        assert!(get("not a url", Duration::from_secs(1)).await.is_err());
+    }
+}
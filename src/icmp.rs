@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::{fmt, io};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::time::{self, Instant};
+use tracing::debug;
+use crate::config::IpVersion;
+
+pub const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Bytes of payload carried by every echo request, same as the iputils default.
+const PAYLOAD_LEN: usize = 56;
+
+/// A single echo reply matched to the request that triggered it.
+#[derive(Debug, Clone)]
+pub struct EchoReply {
+    pub seq: u16,
+    pub rtt: Duration,
+    /// TTL (IPv4) or hop limit (IPv6) of the reply, if the kernel reported it.
+    pub ttl: Option<u8>,
+}
+
+/// Outcome of one run of echo requests against a single address.
+#[derive(Debug, Clone)]
+pub struct PingResult {
+    pub addr: IpAddr,
+    pub transmitted: u16,
+    pub replies: Vec<EchoReply>,
+}
+
+/// A host name without any address of the IP version it was resolved for.
+#[derive(Debug)]
+pub struct NoAddressError {
+    pub host: String,
+    pub version: IpVersion,
+}
+
+impl fmt::Display for NoAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} has no IPv{} address", self.host, self.version)
+    }
+}
+
+impl std::error::Error for NoAddressError {}
+
+/// Settings for a run of echo requests.
+#[derive(Debug, Clone)]
+pub struct PingOptions {
+    /// Number of echo requests to send.
+    pub count: u16,
+    /// Delay between two consecutive requests.
+    pub interval: Duration,
+    /// How long to wait for a reply after the last request was sent.
+    pub timeout: Duration,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        PingOptions {
+            count: 4,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Whether the socket is an unprivileged "ping socket" or a raw socket.
+///
+/// Ping sockets hand us just the ICMP message and the kernel takes care of
+/// the identifier, raw sockets need CAP_NET_RAW and see every ICMP packet
+/// arriving at the host, so replies have to be filtered by identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Datagram,
+    Raw,
+}
+
+pub struct IcmpSocket {
+    fd: AsyncFd<Socket>,
+    pub kind: SocketKind,
+    pub v6: bool,
+}
+
+impl IcmpSocket {
+    pub fn open(addr: IpAddr) -> Result<IcmpSocket> {
+        let (domain, protocol, v6) = match addr {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4, false),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6, true),
+        };
+
+        // Prefer ping sockets, they work without privileges whenever the
+        // group is allowed by net.ipv4.ping_group_range.
+        let (socket, kind) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, SocketKind::Datagram),
+            Err(dgram_err) => {
+                debug!("ICMP datagram socket unavailable ({}), falling back to raw socket", dgram_err);
+                let socket = Socket::new(domain, Type::RAW, Some(protocol))
+                    .with_context(|| format!(
+                        "Failed to open ICMP socket (datagram: {}); allow the group in \
+                         net.ipv4.ping_group_range or grant CAP_NET_RAW", dgram_err))?;
+                (socket, SocketKind::Raw)
+            }
+        };
+
+        socket.set_nonblocking(true)?;
+        // Ask for the TTL/hop limit of incoming packets as ancillary data.
+        if v6 {
+            set_int_opt(&socket, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+        } else {
+            set_int_opt(&socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)?;
+        }
+
+        Ok(IcmpSocket {
+            fd: AsyncFd::new(socket)?,
+            kind,
+            v6,
+        })
+    }
+
+    pub async fn send_to(&self, packet: &[u8], addr: &SockAddr) -> io::Result<usize> {
+        self.fd.async_io(Interest::WRITABLE, |socket| socket.send_to(packet, addr)).await
+    }
+
+    /// Sets the TTL (IPv4) or hop limit (IPv6) of the requests sent from now on.
+    pub fn set_hop_limit(&self, hops: u8) -> io::Result<()> {
+        if self.v6 {
+            set_int_opt(self.fd.get_ref(), libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, hops.into())
+        } else {
+            set_int_opt(self.fd.get_ref(), libc::IPPROTO_IP, libc::IP_TTL, hops.into())
+        }
+    }
+
+    /// Queues ICMP errors about our requests, like time exceeded, for [`Self::recv_error`].
+    /// Only needed on ping sockets, raw sockets receive them like any other packet.
+    pub fn enable_errors(&self) -> io::Result<()> {
+        if self.v6 {
+            set_int_opt(self.fd.get_ref(), libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)
+        } else {
+            set_int_opt(self.fd.get_ref(), libc::IPPROTO_IP, libc::IP_RECVERR, 1)
+        }
+    }
+
+    /// Receives one packet, returning the ICMP message, the TTL/hop limit and the sender.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>, Option<IpAddr>)> {
+        let (len, info) = self.fd.async_io(Interest::READABLE, |socket| recv_msg(socket, buf, 0)).await?;
+
+        // Raw IPv4 sockets deliver the IP header as well, strip it.
+        if self.kind == SocketKind::Raw && !self.v6 && len > 0 {
+            let Some((header_len, header_ttl)) = ipv4_header(&buf[..len]) else {
+                return Ok((0, info.ttl, info.source));
+            };
+            buf.copy_within(header_len..len, 0);
+            return Ok((len - header_len, info.ttl.or(Some(header_ttl)), info.source));
+        }
+
+        Ok((len, info.ttl, info.source))
+    }
+
+    /// Takes one ICMP error off the queue of a ping socket, see [`Self::enable_errors`].
+    /// Returns the request it is about, and the ICMP type and sender of the error,
+    /// which are missing for errors that didn't come in over the network.
+    pub async fn recv_error(&self, buf: &mut [u8]) -> io::Result<(usize, Option<(u8, IpAddr)>)> {
+        let (len, info) = self.fd
+            .async_io(Interest::ERROR, |socket| recv_msg(socket, buf, libc::MSG_ERRQUEUE))
+            .await?;
+        Ok((len, info.icmp_error))
+    }
+}
+
+/// Ancillary data and the sender address of a received message.
+#[derive(Debug, Default)]
+struct MessageInfo {
+    ttl: Option<u8>,
+    source: Option<IpAddr>,
+    /// ICMP type and sender of a queued error.
+    icmp_error: Option<(u8, IpAddr)>,
+}
+
+fn set_int_opt(socket: &Socket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: the pointer and length describe a valid c_int for the duration of the call.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv_msg(socket: &Socket, buf: &mut [u8], flags: libc::c_int) -> io::Result<(usize, MessageInfo)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 256];
+    // SAFETY: all-zero msghdr and sockaddr_storage are valid starting values.
+    let mut name: libc::sockaddr_storage = unsafe { MaybeUninit::zeroed().assume_init() };
+    let mut msg: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    // SAFETY: msg points at buffers that outlive the call.
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut info = MessageInfo::default();
+    if msg.msg_namelen > 0 {
+        // SAFETY: recvmsg filled in a sockaddr of the family it states.
+        info.source = unsafe { sockaddr_ip(&name as *const libc::sockaddr_storage as *const libc::sockaddr) };
+    }
+    // SAFETY: the cmsg macros walk the control buffer filled in by recvmsg.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            let is_ttl = (header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_TTL)
+                || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_HOPLIMIT);
+            let is_error = (header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR)
+                || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+            if is_ttl {
+                let value = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                info.ttl = u8::try_from(value).ok();
+            } else if is_error {
+                let error = libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err;
+                let origin = std::ptr::read_unaligned(error).ee_origin;
+                if origin == libc::SO_EE_ORIGIN_ICMP || origin == libc::SO_EE_ORIGIN_ICMP6 {
+                    let icmp_type = std::ptr::read_unaligned(error).ee_type;
+                    info.icmp_error = sockaddr_ip(libc::SO_EE_OFFENDER(error)).map(|offender| (icmp_type, offender));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len as usize, info))
+}
+
+/// Reads the IP address of a `sockaddr_in` or `sockaddr_in6`.
+///
+/// # Safety
+///
+/// `addr` must point at a socket address that is as large as its family says.
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    match std::ptr::read_unaligned(addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = std::ptr::read_unaligned(addr as *const libc::sockaddr_in);
+            Some(IpAddr::from(addr.sin_addr.s_addr.to_ne_bytes()))
+        }
+        libc::AF_INET6 => {
+            let addr = std::ptr::read_unaligned(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::from(addr.sin6_addr.s6_addr))
+        }
+        _ => None,
+    }
+}
+
+/// Length and TTL of the IPv4 header at the start of `packet`.
+fn ipv4_header(packet: &[u8]) -> Option<(usize, u8)> {
+    let header_len = ((packet.first()? & 0x0f) as usize) * 4;
+    if header_len < 20 || header_len > packet.len() {
+        return None;
+    }
+    Some((header_len, packet[8]))
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn echo_request(v6: bool, ident: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 8 + PAYLOAD_LEN];
+    packet[0] = if v6 { ICMPV6_ECHO_REQUEST } else { ICMPV4_ECHO_REQUEST };
+    packet[4..6].copy_from_slice(&ident.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    for (i, byte) in packet[8..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    // The kernel fills in the ICMPv6 checksum since it covers the pseudo-header.
+    if !v6 {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// Hands out a distinct identifier per run so concurrent runs on raw sockets,
+/// which all see every reply, don't steal each other's packets.
+pub fn next_ident() -> u16 {
+    static NEXT: AtomicU16 = AtomicU16::new(0);
+    (std::process::id() as u16).wrapping_add(NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Parses an echo reply, returning its identifier and sequence number.
+pub fn parse_echo_reply(v6: bool, packet: &[u8]) -> Option<(u16, u16)> {
+    if packet.len() < 8 {
+        return None;
+    }
+    let expected = if v6 { ICMPV6_ECHO_REPLY } else { ICMPV4_ECHO_REPLY };
+    if packet[0] != expected {
+        return None;
+    }
+    let ident = u16::from_be_bytes([packet[4], packet[5]]);
+    let seq = u16::from_be_bytes([packet[6], packet[7]]);
+    Some((ident, seq))
+}
+
+/// The sequence number of `packet` when it answers one of our requests. Ping sockets
+/// only deliver our own replies, raw sockets see all of them and go by the identifier.
+fn reply_seq(kind: SocketKind, v6: bool, ident: u16, packet: &[u8]) -> Option<u16> {
+    let (reply_ident, seq) = parse_echo_reply(v6, packet)?;
+    (kind == SocketKind::Datagram || reply_ident == ident).then_some(seq)
+}
+
+/// Resolves `host` to the first address of `version` returned by the system resolver.
+pub async fn resolve(host: &str, version: IpVersion) -> Result<IpAddr> {
+    if let Ok(addr) = host.parse::<IpAddr>() {
+        if !version.matches(&addr) {
+            bail!("{} is not an IPv{} address", host, version);
+        }
+        return Ok(addr);
+    }
+    tokio::net::lookup_host((host, 0))
+        .await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .map(|addr| addr.ip())
+        .find(|addr| version.matches(addr))
+        .ok_or_else(|| match version {
+            IpVersion::V4 | IpVersion::V6 => NoAddressError { host: host.to_string(), version }.into(),
+            IpVersion::Auto | IpVersion::Both => anyhow!("{} did not resolve to any address", host),
+        })
+}
+
+/// Sends `options.count` ICMP echo requests to `addr` and collects the replies.
+pub async fn ping(addr: IpAddr, options: &PingOptions) -> Result<PingResult> {
+    ping_scoped(addr, 0, options).await
+}
+
+/// Like [`ping`], with the index of the interface to reach a link-local IPv6 `addr` on.
+pub async fn ping_scoped(addr: IpAddr, scope_id: u32, options: &PingOptions) -> Result<PingResult> {
+    let socket = IcmpSocket::open(addr)?;
+    let destination = match addr {
+        IpAddr::V6(v6) => SockAddr::from(SocketAddrV6::new(v6, 0, 0, scope_id)),
+        IpAddr::V4(_) => SockAddr::from(SocketAddr::new(addr, 0)),
+    };
+    // Only used to tell our replies apart on raw sockets, ping sockets
+    // overwrite it with their local port.
+    let ident = next_ident();
+
+    let mut in_flight: HashMap<u16, Instant> = HashMap::new();
+    let mut replies = Vec::new();
+    let mut transmitted = 0u16;
+    let mut buf = [0u8; 1500];
+
+    let mut next_send = Instant::now();
+    let mut deadline = Instant::now() + options.timeout;
+
+    while transmitted < options.count || (!in_flight.is_empty() && Instant::now() < deadline) {
+        let sending = transmitted < options.count;
+        tokio::select! {
+            _ = time::sleep_until(next_send), if sending => {
+                let seq = transmitted + 1;
+                let packet = echo_request(socket.v6, ident, seq);
+                socket.send_to(&packet, &destination).await
+                    .with_context(|| format!("Failed to send echo request to {}", addr))?;
+                in_flight.insert(seq, Instant::now());
+                transmitted += 1;
+                next_send += options.interval;
+                deadline = Instant::now() + options.timeout;
+            }
+            received = socket.recv(&mut buf) => {
+                let (len, ttl, _) = received.context("Failed to receive ICMP packet")?;
+                let Some(seq) = reply_seq(socket.kind, socket.v6, ident, &buf[..len]) else {
+                    continue;
+                };
+                if let Some(sent_at) = in_flight.remove(&seq) {
+                    replies.push(EchoReply { seq, rtt: sent_at.elapsed(), ttl });
+                }
+            }
+            _ = time::sleep_until(deadline), if !sending => {}
+        }
+    }
+
+    Ok(PingResult { addr, transmitted, replies })
+}
+
+/// Summary statistics over the replies of a [`PingResult`], in milliseconds.
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    /// Standard deviation of the round-trip times, what ping reports as `mdev`.
+    pub mdev_ms: f64,
+}
+
+impl PingResult {
+    /// A run whose `count` echo requests were all lost without being sent, because the
+    /// host can't be reached over `version` at all. The address is the unspecified one.
+    pub fn unreachable(version: IpVersion, count: u16) -> PingResult {
+        let addr = match version {
+            IpVersion::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        PingResult { addr, transmitted: count, replies: Vec::new() }
+    }
+
+    pub fn received(&self) -> u16 {
+        self.replies.len() as u16
+    }
+
+    /// Percentage of requests that went unanswered.
+    pub fn loss_pct(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        100.0 * (self.transmitted - self.received()) as f64 / self.transmitted as f64
+    }
+
+    /// Returns `None` when not a single reply came back.
+    pub fn stats(&self) -> Option<LatencyStats> {
+        LatencyStats::from_rtts(self.replies.iter().map(|reply| reply.rtt))
+    }
+}
+
+impl LatencyStats {
+    /// Returns `None` for no round-trip times at all.
+    pub fn from_rtts(rtts: impl IntoIterator<Item = Duration>) -> Option<LatencyStats> {
+        let rtts: Vec<f64> = rtts.into_iter().map(|rtt| rtt.as_secs_f64() * 1000.0).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        let count = rtts.len() as f64;
+        let avg_ms = rtts.iter().sum::<f64>() / count;
+        let variance = rtts.iter().map(|rtt| (rtt - avg_ms).powi(2)).sum::<f64>() / count;
+        Some(LatencyStats {
+            min_ms: rtts.iter().cloned().fold(f64::INFINITY, f64::min),
+            avg_ms,
+            max_ms: rtts.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            mdev_ms: variance.sqrt(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 header of a reply from 1.1.1.1 with a TTL of 57.
+    const V4_HEADER: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x54, 0x00, 0x00, 0x00, 0x00, 0x39, 0x01, 0x7d, 0x5e,
+        0x01, 0x01, 0x01, 0x01, 0xc0, 0xa8, 0x01, 0x0a,
+    ];
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        // The example of section 3 sums to 0xddf2
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        // Odd lengths are padded with a zero byte
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2]), !0xf201);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn builds_echo_requests() {
+        let packet = echo_request(false, 0x1f2e, 7);
+        assert_eq!(packet.len(), 8 + PAYLOAD_LEN);
+        assert_eq!(&packet[..2], &[ICMPV4_ECHO_REQUEST, 0]);
+        assert_eq!(&packet[4..8], &[0x1f, 0x2e, 0x00, 0x07]);
+        assert_eq!(packet[8..12], [0, 1, 2, 3]);
+        // A message including its checksum sums to zero
+        assert_eq!(checksum(&packet), 0);
+
+        let packet = echo_request(true, 0x1f2e, 7);
+        assert_eq!(packet[0], ICMPV6_ECHO_REQUEST);
+        assert_eq!(&packet[2..4], &[0, 0]);
+    }
+
+    #[test]
+    fn parses_v4_reply_behind_ip_header() {
+        let mut reply = echo_request(false, 0x1f2e, 7);
+        reply[0] = ICMPV4_ECHO_REPLY;
+        let packet = [&V4_HEADER[..], &reply].concat();
+
+        let (header_len, ttl) = ipv4_header(&packet).unwrap();
+        assert_eq!((header_len, ttl), (20, 57));
+        assert_eq!(parse_echo_reply(false, &packet[header_len..]), Some((0x1f2e, 7)));
+        assert_eq!(reply_seq(SocketKind::Raw, false, 0x1f2e, &packet[header_len..]), Some(7));
+    }
+
+    #[test]
+    fn rejects_invalid_ipv4_headers() {
+        // IHL below the minimum of 5 words, and longer than the packet
+        assert_eq!(ipv4_header(&[0x44; 20]), None);
+        assert_eq!(ipv4_header(&V4_HEADER[..16]), None);
+        assert_eq!(ipv4_header(&[]), None);
+    }
+
+    #[test]
+    fn parses_v6_reply() {
+        let packet = [ICMPV6_ECHO_REPLY, 0x00, 0x5b, 0x12, 0x1f, 0x2e, 0x00, 0x07, 0x00, 0x01];
+        assert_eq!(parse_echo_reply(true, &packet), Some((0x1f2e, 7)));
+        assert_eq!(parse_echo_reply(false, &packet), None);
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        let reply = [ICMPV4_ECHO_REPLY, 0x00, 0x92, 0x61, 0x1f, 0x2e, 0x00, 0x07];
+        // Requests, errors and truncated replies
+        assert_eq!(parse_echo_reply(false, &echo_request(false, 0x1f2e, 7)), None);
+        assert_eq!(parse_echo_reply(false, &[3, 3, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(parse_echo_reply(false, &reply[..7]), None);
+        assert_eq!(parse_echo_reply(true, &echo_request(true, 0x1f2e, 7)), None);
+
+        // Raw sockets see replies to other processes
+        assert_eq!(reply_seq(SocketKind::Raw, false, 0x1f2f, &reply), None);
+        // Ping sockets rewrite the identifier, every reply is ours
+        assert_eq!(reply_seq(SocketKind::Datagram, false, 0x1f2f, &reply), Some(7));
+    }
+}
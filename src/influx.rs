@@ -0,0 +1,393 @@
+use std::fmt;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use influxdb::{Query, WriteQuery};
+use reqwest::{RequestBuilder, StatusCode};
+use crate::config::{InfluxDbConfig, InfluxDbVersion};
+
+/// How long a single request to InfluxDB may take before it counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Writes points to either an InfluxDB 1.x database or a 2.x/3.x bucket.
+pub enum InfluxDbWriter {
+    V1(V1Writer),
+    V2(V2Writer),
+}
+
+/// Writes line protocol to the `/write` endpoint with optional username and password.
+/// The credentials go in a basic auth header, never the URL, which ends up in errors.
+pub struct V1Writer {
+    http_client: reqwest::Client,
+    url: String,
+    db: String,
+    credentials: Option<(String, String)>,
+}
+
+/// Writes line protocol to the `/api/v2/write` endpoint with token auth.
+pub struct V2Writer {
+    http_client: reqwest::Client,
+    url: String,
+    token: String,
+    org: String,
+    bucket: String,
+}
+
+/// Why InfluxDB didn't take a batch of points.
+#[derive(Debug)]
+pub enum WriteError {
+    /// InfluxDB refused the points themselves, e.g. a field type conflict or a line it
+    /// can't parse. Sending them again fails the same way.
+    Rejected(String),
+    /// InfluxDB couldn't be reached or failed on its side, worth another attempt later.
+    Unavailable(anyhow::Error),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Rejected(message) => write!(f, "points rejected: {}", message),
+            WriteError::Unavailable(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl InfluxDbWriter {
+    pub fn new(config: &InfluxDbConfig, http_client: reqwest::Client) -> Result<InfluxDbWriter> {
+        let url = config.url.trim_end_matches('/').to_string();
+        match config.version {
+            InfluxDbVersion::V1 => Ok(InfluxDbWriter::V1(V1Writer {
+                http_client,
+                url,
+                db: config.db.clone(),
+                credentials: config.username.clone().zip(config.password.clone()),
+            })),
+            InfluxDbVersion::V2 => {
+                let token = config.token.clone().context("InfluxDB 2.x requires a token")?;
+                let org = config.org.clone().context("InfluxDB 2.x requires an organization")?;
+                Ok(InfluxDbWriter::V2(V2Writer {
+                    http_client,
+                    url,
+                    token,
+                    org,
+                    bucket: config.bucket.clone().unwrap_or_else(|| config.db.clone()),
+                }))
+            }
+        }
+    }
+
+    /// Short description of where points end up, for log messages.
+    pub fn destination(&self) -> String {
+        match self {
+            InfluxDbWriter::V1(writer) => format!("database {} at {}", writer.db, writer.url),
+            InfluxDbWriter::V2(writer) => format!("bucket {} of {} at {}", writer.bucket, writer.org, writer.url),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let (http_client, url) = match self {
+            InfluxDbWriter::V1(writer) => (&writer.http_client, &writer.url),
+            InfluxDbWriter::V2(writer) => (&writer.http_client, &writer.url),
+        };
+        let response = http_client.get(format!("{}/ping", url)).timeout(REQUEST_TIMEOUT).send().await?;
+        if !response.status().is_success() {
+            bail!("ping returned HTTP status {}", response.status());
+        }
+        Ok(())
+    }
+
+    /// Renders points as line protocol with the escaping rules of this InfluxDB version.
+    pub fn to_lines(&self, queries: &[WriteQuery]) -> Result<Vec<String>> {
+        let v2 = matches!(self, InfluxDbWriter::V2(_));
+        queries
+            .iter()
+            .map(|query| Ok(query.build_with_opts(v2)?.get()))
+            .collect()
+    }
+
+    /// Writes points that were already rendered with [`InfluxDbWriter::to_lines`].
+    pub async fn write_lines(&self, lines: &[String]) -> Result<(), WriteError> {
+        // Timestamps are always nanoseconds, see `InfluxDbWriteable` for `DateTime`
+        let request = match self {
+            InfluxDbWriter::V1(writer) => {
+                let request = writer.http_client
+                    .post(format!("{}/write", writer.url))
+                    .query(&[("db", writer.db.as_str()), ("precision", "ns")]);
+                match &writer.credentials {
+                    Some((username, password)) => request.basic_auth(username, Some(password)),
+                    None => request,
+                }
+            }
+            InfluxDbWriter::V2(writer) => writer.http_client
+                .post(format!("{}/api/v2/write", writer.url))
+                .query(&[("org", writer.org.as_str()), ("bucket", writer.bucket.as_str()), ("precision", "ns")])
+                .header(reqwest::header::AUTHORIZATION, format!("Token {}", writer.token)),
+        };
+        send_lines(request, lines).await
+    }
+}
+
+async fn send_lines(request: RequestBuilder, lines: &[String]) -> Result<(), WriteError> {
+    let response = request
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .timeout(REQUEST_TIMEOUT)
+        .body(lines.join("\n"))
+        .send()
+        .await
+        .map_err(|e| WriteError::Unavailable(e.into()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let message = response.text().await.unwrap_or_default();
+    let message = format!("write returned HTTP status {}: {}", status, message.trim());
+    if rejects_points(status) {
+        Err(WriteError::Rejected(message))
+    } else {
+        Err(WriteError::Unavailable(anyhow::anyhow!(message)))
+    }
+}
+
+/// Whether `status` means InfluxDB refused the points themselves. Other client errors
+/// come from the setup, wrong credentials or a missing database, and go away once it
+/// is fixed, so the points are kept for then.
+fn rejects_points(status: StatusCode) -> bool {
+    status.is_client_error()
+        && !matches!(
+            status,
+            StatusCode::UNAUTHORIZED
+                | StatusCode::FORBIDDEN
+                | StatusCode::NOT_FOUND
+                | StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+        )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use influxdb::{InfluxDbWriteable, Timestamp};
+    use super::*;
+
+    /// Lines of a write request and the status it was answered with.
+    type Write = (StatusCode, Vec<String>);
+    /// Path with query and `Authorization` header of a request.
+    type Seen = (String, Option<String>);
+
+    /// InfluxDB stand-in on a free local port. Writes are answered with the queued
+    /// statuses, then with 204, and every write is kept with the status it got.
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeInfluxDb {
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+        /// Delay before the next write is answered.
+        stall: Arc<Mutex<Option<Duration>>>,
+        /// Writes that arrived, answered or not.
+        arrived: Arc<AtomicUsize>,
+        writes: Arc<Mutex<Vec<Write>>>,
+        requests: Arc<Mutex<Vec<Seen>>>,
+    }
+
+    impl FakeInfluxDb {
+        /// Starts the server and returns it with a 1.x writer pointed at it.
+        pub(crate) fn spawn() -> (FakeInfluxDb, InfluxDbWriter) {
+            let (fake, url) = FakeInfluxDb::start();
+            (fake, InfluxDbWriter::new(&config(url), reqwest::Client::new()).unwrap())
+        }
+
+        /// Starts the server and returns it with the URL it listens on.
+        fn start() -> (FakeInfluxDb, String) {
+            let fake = FakeInfluxDb::default();
+            let state = fake.clone();
+            let make_service = make_service_fn(move |_| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let state = state.clone();
+                        async move { Ok::<_, Infallible>(state.handle(request).await) }
+                    }))
+                }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+            let url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            (fake, url)
+        }
+
+        async fn handle(&self, request: Request<Body>) -> Response<Body> {
+            let authorization = request
+                .headers()
+                .get(reqwest::header::AUTHORIZATION)
+                .map(|value| value.to_str().unwrap_or_default().to_string());
+            let path = request.uri().path_and_query().map(|path| path.to_string()).unwrap_or_default();
+            self.requests.lock().unwrap().push((path, authorization));
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default();
+            self.arrived.fetch_add(1, Ordering::SeqCst);
+            let stall = self.stall.lock().unwrap().take();
+            if let Some(stall) = stall {
+                tokio::time::sleep(stall).await;
+            }
+            let lines = String::from_utf8_lossy(&body).lines().map(str::to_string).collect();
+            let status = self.statuses.lock().unwrap().pop_front().unwrap_or(StatusCode::NO_CONTENT);
+            self.writes.lock().unwrap().push((status, lines));
+            let mut response = Response::new(Body::from(if status.is_success() { "" } else { "failed" }));
+            *response.status_mut() = status;
+            response
+        }
+
+        /// Answers the next writes with `statuses`, in order.
+        pub(crate) fn respond_with(&self, statuses: &[StatusCode]) {
+            self.statuses.lock().unwrap().extend(statuses);
+        }
+
+        /// Holds up the answer to the next write for `delay`.
+        pub(crate) fn stall_next(&self, delay: Duration) {
+            *self.stall.lock().unwrap() = Some(delay);
+        }
+
+        /// Number of writes that arrived so far, including one that is stalled.
+        pub(crate) fn arrived(&self) -> usize {
+            self.arrived.load(Ordering::SeqCst)
+        }
+
+        /// Lines of every write so far with the status it was answered with.
+        pub(crate) fn writes(&self) -> Vec<Write> {
+            self.writes.lock().unwrap().clone()
+        }
+
+        /// Path with query and `Authorization` header of every request so far.
+        pub(crate) fn requests(&self) -> Vec<Seen> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// Lines of the accepted writes, in order.
+        pub(crate) fn accepted(&self) -> Vec<String> {
+            self.writes()
+                .into_iter()
+                .filter(|(status, _)| status.is_success())
+                .flat_map(|(_, lines)| lines)
+                .collect()
+        }
+    }
+
+    /// Settings of a 1.x database at `url` without credentials.
+    fn config(url: String) -> InfluxDbConfig {
+        InfluxDbConfig {
+            version: InfluxDbVersion::V1,
+            url,
+            db: "internet_metrics".to_string(),
+            username: None,
+            password: None,
+            token: None,
+            org: None,
+            bucket: None,
+            batch_size: 1000,
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn tells_rejected_points_from_unavailable_database() {
+        let cases = [
+            (StatusCode::BAD_REQUEST, true),
+            (StatusCode::UNAUTHORIZED, false),
+            (StatusCode::FORBIDDEN, false),
+            (StatusCode::NOT_FOUND, false),
+            (StatusCode::REQUEST_TIMEOUT, false),
+            (StatusCode::PAYLOAD_TOO_LARGE, true),
+            (StatusCode::UNPROCESSABLE_ENTITY, true),
+            (StatusCode::TOO_MANY_REQUESTS, false),
+            (StatusCode::INTERNAL_SERVER_ERROR, false),
+            (StatusCode::BAD_GATEWAY, false),
+            (StatusCode::SERVICE_UNAVAILABLE, false),
+            (StatusCode::GATEWAY_TIMEOUT, false),
+        ];
+        for (status, rejected) in cases {
+            assert_eq!(rejects_points(status), rejected, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn classifies_write_errors() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let lines = vec!["internet_metrics latency_ms=12.5 1".to_string()];
+        fake.respond_with(&[StatusCode::BAD_REQUEST, StatusCode::SERVICE_UNAVAILABLE]);
+
+        let rejected = writer.write_lines(&lines).await.unwrap_err();
+        assert!(matches!(&rejected, WriteError::Rejected(message) if message.contains("400")), "{}", rejected);
+        let unavailable = writer.write_lines(&lines).await.unwrap_err();
+        assert!(matches!(unavailable, WriteError::Unavailable(_)), "{}", unavailable);
+        writer.write_lines(&lines).await.unwrap();
+        assert_eq!(fake.accepted(), lines);
+    }
+
+    #[tokio::test]
+    async fn keeps_password_out_of_errors() {
+        // A port nothing listens on once the listener is gone
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let config = InfluxDbConfig {
+            username: Some("internetmon".to_string()),
+            password: Some("password123".to_string()),
+            ..config(url)
+        };
+        let writer = InfluxDbWriter::new(&config, reqwest::Client::new()).unwrap();
+        let error = writer.write_lines(&["internet_metrics latency_ms=12.5 1".to_string()]).await.unwrap_err();
+        assert!(matches!(error, WriteError::Unavailable(_)), "{}", error);
+        let message = format!("{} {:?}", error, error);
+        assert!(!message.contains("password123"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn writes_to_v2_bucket() {
+        let (fake, url) = FakeInfluxDb::start();
+        let v2 = InfluxDbConfig {
+            version: InfluxDbVersion::V2,
+            token: Some("my-token".to_string()),
+            org: Some("my org".to_string()),
+            bucket: Some("internet_metrics".to_string()),
+            ..config(format!("{}/", url))
+        };
+        let writer = InfluxDbWriter::new(&v2, reqwest::Client::new()).unwrap();
+        assert_eq!(writer.destination(), format!("bucket internet_metrics of my org at {}", url));
+
+        let query = Timestamp::Nanoseconds(1)
+            .into_query("internet_metrics")
+            .add_tag("label", "home router")
+            .add_field("body_bytes", 512u64);
+        let lines = writer.to_lines(std::slice::from_ref(&query)).unwrap();
+        // 2.x takes unsigned integers, 1.x only signed ones
+        assert_eq!(lines, [r"internet_metrics,label=home\ router body_bytes=512u 1"]);
+        let v1 = InfluxDbWriter::new(&config(url.clone()), reqwest::Client::new()).unwrap();
+        assert_eq!(v1.to_lines(&[query]).unwrap(), [r"internet_metrics,label=home\ router body_bytes=512i 1"]);
+
+        writer.write_lines(&lines).await.unwrap();
+        assert_eq!(fake.accepted(), lines);
+        assert_eq!(fake.requests(), [(
+            "/api/v2/write?org=my+org&bucket=internet_metrics&precision=ns".to_string(),
+            Some("Token my-token".to_string()),
+        )]);
+    }
+
+    #[test]
+    fn v2_requires_token_and_org() {
+        let v2 = InfluxDbConfig { version: InfluxDbVersion::V2, ..config("http://localhost:8086".to_string()) };
+        let no_token = InfluxDbConfig { org: Some("my-org".to_string()), ..v2.clone() };
+        assert!(InfluxDbWriter::new(&no_token, reqwest::Client::new()).is_err());
+        let no_org = InfluxDbConfig { token: Some("my-token".to_string()), ..v2.clone() };
+        assert!(InfluxDbWriter::new(&no_org, reqwest::Client::new()).is_err());
+
+        // The bucket defaults to the database
+        let complete = InfluxDbConfig { token: Some("my-token".to_string()), org: Some("my-org".to_string()), ..v2 };
+        let writer = InfluxDbWriter::new(&complete, reqwest::Client::new()).unwrap();
+        assert_eq!(writer.destination(), "bucket internet_metrics of my-org at http://localhost:8086");
+    }
+}
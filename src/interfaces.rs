@@ -0,0 +1,302 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use tracing::debug;
+
+const NET_DEV: &str = "/proc/net/dev";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Traffic counters of a network interface since it came up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+/// What happened on an interface between two samples.
+#[derive(Debug, Clone)]
+pub struct Rates {
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceStats {
+    pub name: String,
+    pub counters: Counters,
+    /// Operational state as the kernel reports it, e.g. `up`, `down` or `dormant`.
+    pub operstate: Option<String>,
+    /// Negotiated link speed, `None` for Wi-Fi and virtual interfaces.
+    pub speed_mbps: Option<u64>,
+    /// How often the link went down or came up since the interface was created.
+    pub carrier_changes: Option<u64>,
+    /// `None` on the first sample, or when the counters were reset in between.
+    pub rates: Option<Rates>,
+}
+
+impl InterfaceStats {
+    /// Share of the link speed used in the busier direction.
+    pub fn utilization_pct(&self) -> Option<f64> {
+        let rates = self.rates.as_ref()?;
+        let speed = self.speed_mbps.filter(|speed| *speed > 0)?;
+        let busier = rates.rx_bytes_per_sec.max(rates.tx_bytes_per_sec);
+        Some(100.0 * busier * 8.0 / (speed as f64 * 1_000_000.0))
+    }
+
+    /// Computes the rates since `previous`, a sample of the same interface taken `elapsed` ago.
+    pub fn compare_with(&mut self, previous: &InterfaceStats, elapsed: Duration) {
+        let (now, before) = (&self.counters, &previous.counters);
+        let secs = elapsed.as_secs_f64();
+        // Counters go back to zero when the interface is recreated
+        let reset = now.rx_bytes < before.rx_bytes || now.tx_bytes < before.tx_bytes
+            || now.rx_errors < before.rx_errors || now.tx_errors < before.tx_errors
+            || now.rx_dropped < before.rx_dropped || now.tx_dropped < before.tx_dropped;
+        if reset || secs <= 0.0 {
+            return;
+        }
+        self.rates = Some(Rates {
+            rx_bytes_per_sec: (now.rx_bytes - before.rx_bytes) as f64 / secs,
+            tx_bytes_per_sec: (now.tx_bytes - before.tx_bytes) as f64 / secs,
+            rx_errors: now.rx_errors - before.rx_errors,
+            tx_errors: now.tx_errors - before.tx_errors,
+            rx_dropped: now.rx_dropped - before.rx_dropped,
+            tx_dropped: now.tx_dropped - before.tx_dropped,
+        });
+    }
+}
+
+/// Reads the counters of the interfaces named in `only`, or of every interface but
+/// loopback when it is empty.
+pub fn read(only: &[String]) -> Result<Vec<InterfaceStats>> {
+    let counters = match read_statistics(Path::new(SYS_CLASS_NET)) {
+        Ok(counters) => counters,
+        Err(e) => {
+            // Containers don't always mount sysfs
+            debug!("Falling back to {}: {:#}", NET_DEV, e);
+            let table = fs::read_to_string(NET_DEV).with_context(|| format!("Failed to read {}", NET_DEV))?;
+            parse_net_dev(&table)?
+        }
+    };
+    let mut interfaces: Vec<InterfaceStats> = counters
+        .into_iter()
+        .filter(|(name, _)| match only.is_empty() {
+            true => name != "lo",
+            false => only.contains(name),
+        })
+        .map(|(name, counters)| {
+            let link = Path::new(SYS_CLASS_NET).join(&name);
+            InterfaceStats {
+                operstate: fs::read_to_string(link.join("operstate")).ok().map(|state| state.trim().to_string()),
+                // Reads fail or give -1 while the link is down or without a fixed speed
+                speed_mbps: read_number(&link.join("speed")),
+                carrier_changes: read_number(&link.join("carrier_changes")),
+                name,
+                counters,
+                rates: None,
+            }
+        })
+        .collect();
+    if let Some(missing) = only.iter().find(|name| !interfaces.iter().any(|interface| &interface.name == *name)) {
+        return Err(anyhow!("No interface named {}", missing));
+    }
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(interfaces)
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Reads the counters from `<root>/<interface>/statistics`, one file per counter. Unlike
+/// the drop column of `/proc/net/dev`, `rx_dropped` doesn't include missed packets.
+fn read_statistics(root: &Path) -> Result<Vec<(String, Counters)>> {
+    let entries = fs::read_dir(root).with_context(|| format!("Failed to list {}", root.display()))?;
+    let mut interfaces = Vec::new();
+    for entry in entries {
+        let statistics = entry?.path().join("statistics");
+        // Skips files like `bonding_masters`
+        if !statistics.is_dir() {
+            continue;
+        }
+        let counter = |name: &str| {
+            let path = statistics.join(name);
+            read_number(&path).ok_or_else(|| anyhow!("Failed to read {}", path.display()))
+        };
+        let counters = Counters {
+            rx_bytes: counter("rx_bytes")?,
+            rx_packets: counter("rx_packets")?,
+            rx_errors: counter("rx_errors")?,
+            rx_dropped: counter("rx_dropped")?,
+            tx_bytes: counter("tx_bytes")?,
+            tx_packets: counter("tx_packets")?,
+            tx_errors: counter("tx_errors")?,
+            tx_dropped: counter("tx_dropped")?,
+        };
+        let name = statistics.parent().and_then(Path::file_name).unwrap_or_default();
+        interfaces.push((name.to_string_lossy().into_owned(), counters));
+    }
+    Ok(interfaces)
+}
+
+/// Parses `/proc/net/dev`: two header lines, then `name: ` followed by 8 receive and
+/// 8 transmit columns, starting with bytes, packets, errs and drop in each.
+fn parse_net_dev(table: &str) -> Result<Vec<(String, Counters)>> {
+    table
+        .lines()
+        .skip(2)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (name, columns) = line.split_once(':').ok_or_else(|| anyhow!("Invalid line in {}: {}", NET_DEV, line))?;
+            let columns: Vec<u64> = columns
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("Invalid counter in {}: {}", NET_DEV, line))?;
+            if columns.len() < 12 {
+                return Err(anyhow!("Too few columns in {}: {}", NET_DEV, line));
+            }
+            Ok((name.trim().to_string(), Counters {
+                rx_bytes: columns[0],
+                rx_packets: columns[1],
+                rx_errors: columns[2],
+                rx_dropped: columns[3],
+                tx_bytes: columns[8],
+                tx_packets: columns[9],
+                tx_errors: columns[10],
+                tx_dropped: columns[11],
+            }))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NET_DEV_SAMPLE: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  104857     1024    0    0    0     0          0         0   104857     1024    0    0    0     0       0          0
+  eth0: 9876543210 7654321    3   12    0     0          0      1234 123456789  234567    1    2    0     0       0          0
+wlan0: 55 1 0 0 0 0 0 0 66 2 0 0 0 0 0 0
+";
+
+    #[test]
+    fn parses_net_dev() {
+        let interfaces = parse_net_dev(NET_DEV_SAMPLE).unwrap();
+        let names: Vec<_> = interfaces.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["lo", "eth0", "wlan0"]);
+        assert_eq!(interfaces[1].1, Counters {
+            rx_bytes: 9_876_543_210,
+            rx_packets: 7_654_321,
+            rx_errors: 3,
+            rx_dropped: 12,
+            tx_bytes: 123_456_789,
+            tx_packets: 234_567,
+            tx_errors: 1,
+            tx_dropped: 2,
+        });
+        assert_eq!(interfaces[2].1.tx_bytes, 66);
+    }
+
+    #[test]
+    fn rejects_invalid_net_dev_lines() {
+        let header = NET_DEV_SAMPLE.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert!(parse_net_dev(&format!("{}\neth0 1 2 3\n", header)).is_err());
+        assert!(parse_net_dev(&format!("{}\neth0: 1 2 3 4 5 6 7 8 9 10 11\n", header)).is_err());
+        assert!(parse_net_dev(&format!("{}\neth0: 1 2 x 4 5 6 7 8 9 10 11 12\n", header)).is_err());
+        assert!(parse_net_dev(&header).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_sysfs_statistics() {
+        let root = std::env::temp_dir().join(format!("interfaces-test-{}", std::process::id()));
+        let statistics = root.join("eth0").join("statistics");
+        fs::create_dir_all(&statistics).unwrap();
+        for (index, name) in ["rx_bytes", "rx_packets", "rx_errors", "rx_dropped",
+                              "tx_bytes", "tx_packets", "tx_errors", "tx_dropped"].iter().enumerate() {
+            fs::write(statistics.join(name), format!("{}\n", index + 1)).unwrap();
+        }
+        fs::write(root.join("bonding_masters"), "\n").unwrap();
+
+        let interfaces = read_statistics(&root);
+        fs::remove_dir_all(&root).unwrap();
+        let interfaces = interfaces.unwrap();
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].0, "eth0");
+        assert_eq!(interfaces[0].1, Counters {
+            rx_bytes: 1,
+            rx_packets: 2,
+            rx_errors: 3,
+            rx_dropped: 4,
+            tx_bytes: 5,
+            tx_packets: 6,
+            tx_errors: 7,
+            tx_dropped: 8,
+        });
+        assert!(read_statistics(&root).is_err());
+    }
+
+    fn sample(counters: Counters) -> InterfaceStats {
+        InterfaceStats {
+            name: "eth0".to_string(),
+            counters,
+            operstate: Some("up".to_string()),
+            speed_mbps: Some(100),
+            carrier_changes: Some(2),
+            rates: None,
+        }
+    }
+
+    #[test]
+    fn computes_rates() {
+        let previous = sample(Counters { rx_bytes: 1_000, tx_bytes: 500, rx_errors: 1, ..Counters::default() });
+        let mut current = sample(Counters {
+            rx_bytes: 6_251_000,
+            tx_bytes: 2_500,
+            rx_errors: 4,
+            tx_dropped: 2,
+            ..Counters::default()
+        });
+        current.compare_with(&previous, Duration::from_secs(10));
+        let rates = current.rates.as_ref().unwrap();
+        assert_eq!(rates.rx_bytes_per_sec, 625_000.0);
+        assert_eq!(rates.tx_bytes_per_sec, 200.0);
+        assert_eq!((rates.rx_errors, rates.tx_errors, rates.rx_dropped, rates.tx_dropped), (3, 0, 0, 2));
+        // 625 kB/s is 5 Mbit/s of 100
+        assert_eq!(current.utilization_pct(), Some(5.0));
+
+        current.speed_mbps = None;
+        assert_eq!(current.utilization_pct(), None);
+    }
+
+    #[test]
+    fn skips_rates_after_counter_reset() {
+        let previous = sample(Counters { rx_bytes: 9_000, tx_bytes: 9_000, tx_dropped: 5, ..Counters::default() });
+
+        let mut recreated = sample(Counters { rx_bytes: 100, tx_bytes: 100, ..Counters::default() });
+        recreated.compare_with(&previous, Duration::from_secs(10));
+        assert!(recreated.rates.is_none());
+        assert_eq!(recreated.utilization_pct(), None);
+
+        // A single counter going back is enough
+        let mut dropped_reset = sample(Counters { rx_bytes: 10_000, tx_bytes: 10_000, ..Counters::default() });
+        dropped_reset.compare_with(&previous, Duration::from_secs(10));
+        assert!(dropped_reset.rates.is_none());
+
+        let mut no_time = sample(Counters { rx_bytes: 10_000, tx_bytes: 10_000, tx_dropped: 5, ..Counters::default() });
+        no_time.compare_with(&previous, Duration::ZERO);
+        assert!(no_time.rates.is_none());
+    }
+}
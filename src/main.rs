@@ -1,160 +1,157 @@
-use std::error::Error;
-use std::process::Command;
-use anyhow::{Result, Context};
-use chrono::Utc;
+mod alert;
+mod bufferbloat;
+mod config;
+mod dns;
+mod gateway;
+mod http;
+mod icmp;
+mod influx;
+mod interfaces;
+mod once;
+mod outage;
+mod probe;
+mod prometheus;
+mod public_ip;
+mod scheduler;
+mod sink;
+mod spool;
+mod stun;
+mod tcp;
+mod throughput;
+mod traceroute;
+mod wifi;
+
+use alert::Alerter;
+use anyhow::Result;
 use clap::Parser;
-use influxdb::{Client, InfluxDbWriteable};
-use std::time::{Duration};
-use tokio::time;
+use config::{Args, Command, Settings};
+use influx::InfluxDbWriter;
+use outage::OutageTracker;
+use prometheus::Exporter;
+use public_ip::PublicIpTags;
+use sink::Sink;
+use spool::Spool;
+use std::sync::Arc;
+use tokio::signal;
+use tokio::sync::mpsc;
 use tracing::{info, warn, error};
-use regex::Regex;
-
-#[derive(Parser, Debug)]
-#[clap(author, version, about)]
-struct Args {
-    /// Time between runs in seconds
-    #[clap(short, long, default_value = "5")]
-    interval: u64,
-
-    /// InfluxDB URL
-    #[clap(long, default_value = "http://influxdb:8086")]
-    influxdb_url: String,
-
-    /// InfluxDB database
-    #[clap(long, default_value = "internet_metrics")]
-    influxdb_db: String,
-
-    /// InfluxDB username (optional)
-    #[clap(long)]
-    influxdb_username: Option<String>,
-
-    /// InfluxDB password (optional)
-    #[clap(long)]
-    influxdb_password: Option<String>,
-
-    /// Latency test URL
-    #[clap(long, default_value = "google.com")]
-    latency_url: String,
-}
 
-#[derive(Debug, InfluxDbWriteable)]
-struct InternetMetrics {
-    time: chrono::DateTime<Utc>,
-    #[influxdb(tag)]
-    measurement_type: String,
-    latency_ms: Option<f64>,
+/// Resolves once the process is asked to stop, with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
 
-async fn measure_latency(url: &str) -> Result<f64, Box<dyn Error>> {
-    let url_owned = url.to_owned();
-    let output = tokio::task::spawn_blocking(move || {
-        Command::new("ping")
-            .arg("-c")
-            .arg("4")  // perform 4 pings
-            .arg(url_owned)
-            .output()
-    }).await?
-        .context("Failed to spawn ping command")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        info!("{}", stderr.to_string());
-        return Err(format!("Ping command failed: {}", stderr).into());
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = Args::parse();
+    let command = args.command.take();
+
+    // Initialize logging, stdout is reserved for the results in one-shot mode
+    match command {
+        Some(Command::Once { .. }) => tracing_subscriber::fmt().with_writer(std::io::stderr).init(),
+        None => tracing_subscriber::fmt::init(),
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let string = stdout.to_string();
-    info!("{}", string);
-    // This regex handles both Linux ("rtt") and macOS ("round-trip") summary lines.
-    let re = Regex::new(r"(?:rtt|round-trip).* = ([0-9.]+)/([0-9.]+)/([0-9.]+)/([0-9.]+) ?ms")?;
-    if let Some(captures) = re.captures(&stdout) {
-        // The average latency is the second captured group.
-        if let Some(avg_match) = captures.get(2) {
-            let avg = avg_match.as_str().parse::<f64>()
-                .context("Failed to parse average latency time as float")?;
-            return Ok(avg);
+    let settings = Settings::load(args)?;
+
+    // Shared HTTP client for the throughput tests and the InfluxDB writer
+    let http_client = reqwest::Client::builder()
+        .user_agent(concat!("internet-monitor/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    if let Some(Command::Once { format }) = command {
+        if !once::run(&settings, &http_client, format).await? {
+            std::process::exit(1);
         }
+        return Ok(());
     }
 
-    Err("Failed to parse ping output".into())
-}
+    info!("Starting internet-monitor with {} probes", settings.probes.len());
+
+    // Create the InfluxDB writer, points are written in batches by the sink
+    let sink = match &settings.influxdb {
+        Some(influxdb) => {
+            let writer = InfluxDbWriter::new(influxdb, http_client.clone())?;
+            info!("Writing metrics to InfluxDB {}", writer.destination());
 
-async fn run_measurements(args: &Args) -> Result<InternetMetrics> {
-    // Measure latency
-    info!("Measuring latency to {}", args.latency_url);
-    let latency = match measure_latency(&args.latency_url).await {
-        Ok(latency) => {
-            info!("Latency: {:.2} ms", latency);
-            Some(latency)
+            // Attempt to ping InfluxDB
+            match writer.ping().await {
+                Ok(_) => info!("Successfully connected to InfluxDB"),
+                Err(e) => warn!("Could not ping InfluxDB, but will try to write anyway: {}", e),
+            }
+
+            // Open the spool for points InfluxDB doesn't accept
+            let spool = match &settings.spool {
+                Some(spool) => Some(Spool::open(spool.path.clone(), spool.max_bytes, influxdb.flush_interval)?),
+                None => None,
+            };
+            Some(Sink::spawn(writer, spool, influxdb.batch_size, influxdb.flush_interval))
         }
-        Err(e) => {
-            warn!("Failed to measure latency: {}", e);
+        None => {
+            info!("Writing to InfluxDB is disabled");
             None
         }
     };
 
-    Ok(InternetMetrics {
-        time: Utc::now(),
-        measurement_type: "internet_performance".to_string(),
-        latency_ms: latency,
-    })
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
-
-    let args = Args::parse();
-    info!("Starting internet-monitor with interval of {} seconds", args.interval);
-
-    // Clone the strings we need for InfluxDB client to avoid partial move issues
-    let influxdb_url = args.influxdb_url.clone();
-    let influxdb_db = args.influxdb_db.clone();
-    let influxdb_username = args.influxdb_username.clone();
-    let influxdb_password = args.influxdb_password.clone();
-
-    // Create InfluxDB client
-    let mut influx_client = Client::new(influxdb_url, influxdb_db);
-    if let (Some(username), Some(password)) = (&influxdb_username, &influxdb_password) {
-        influx_client = influx_client.with_auth(username, password);
+    // Start the Prometheus exporter
+    let exporter = Arc::new(Exporter::default());
+    if let Some(listen) = settings.prometheus_listen {
+        // Bind before spawning so an address that is taken stops startup
+        let server = prometheus::bind(listen, exporter.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("{:#}", e);
+            }
+        });
+    } else if sink.is_none() {
+        warn!("Neither InfluxDB nor the Prometheus exporter is enabled, results are only logged");
     }
 
-    // Attempt to ping InfluxDB
-    match influx_client.ping().await {
-        Ok(_) => info!("Successfully connected to InfluxDB"),
-        Err(e) => warn!("Could not ping InfluxDB, but will try to write anyway: {}", e),
-    }
+    // Every probe runs as a task of its own, results are collected here
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let tasks = scheduler::spawn(&settings.probes, &http_client, sender);
+    let mut outages = OutageTracker::new(settings.outage_threshold);
+    let mut public_ips = PublicIpTags::default();
+    let mut alerter = settings.alerts.as_ref().map(|alerts| Alerter::spawn(alerts, &http_client));
 
-    // Main measurement loop
-    let mut iteration = 0;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        iteration += 1;
-        info!("Starting measurement iteration {}", iteration);
-
-        match run_measurements(&args).await {
-            Ok(metrics) => {
-                // Write to InfluxDB
-                match influx_client.query(metrics.into_query("internet_metrics")).await {
-                    Ok(_) => info!("Successfully wrote metrics to InfluxDB"),
-                    Err(e) => {
-                        error!("Failed to write metrics to InfluxDB: {}", e);
-                        // Don't exit on InfluxDB errors
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to run measurements: {}", e);
-                // Don't exit on measurement errors
-            }
+        let outcome = tokio::select! {
+            outcome = receiver.recv() => outcome,
+            _ = &mut shutdown => break,
+        };
+        let Some(outcome) = outcome else { break };
+
+        exporter.update(&outcome);
+        let incident = outages.observe(&outcome);
+        public_ips.observe(&outcome);
+        if let Some(alerter) = &mut alerter {
+            alerter.observe(&outcome);
         }
 
-        info!("Completed measurement iteration {}. Sleeping for {} seconds...",
-             iteration, args.interval);
-
-        // Wait for the next interval
-        time::sleep(Duration::from_secs(args.interval)).await;
+        // Queue the points for InfluxDB. Outages aren't tagged with the public address,
+        // it may change while they last and the final point has to replace the first
+        if let Some(sink) = &sink {
+            let queries = outcome.to_queries()
+                .into_iter()
+                .map(|query| public_ips.tag(query))
+                .chain(incident.map(|incident| incident.to_query()));
+            sink.send(queries.collect());
+        }
+    }
 
-        info!("Woke up from sleep after iteration {}", iteration);
+    info!("Shutting down");
+    for task in tasks {
+        task.abort();
+    }
+    if let Some(sink) = sink {
+        sink.close().await;
     }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::io::{self, Write};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use crate::config::{OutputFormat, Settings};
+use crate::probe::{self, ProbeOutcome};
+
+/// Runs every configured probe once and prints the results to stdout in `format`.
+/// Most probes run concurrently, throughput tests one after another so they don't
+/// compete for bandwidth. Returns whether all probes succeeded.
+pub async fn run(settings: &Settings, http_client: &reqwest::Client, format: OutputFormat) -> Result<bool> {
+    let started = Utc::now();
+
+    let handles: Vec<_> = settings.probes
+        .iter()
+        .filter(|scheduled| !scheduled.probe.uses_bandwidth())
+        .cloned()
+        .map(|scheduled| {
+            let http_client = http_client.clone();
+            tokio::spawn(async move {
+                probe::run(&scheduled.probe, &http_client, scheduled.schedule.run_timeout).await
+            })
+        })
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(settings.probes.len());
+    for handle in handles {
+        outcomes.push(handle.await?);
+    }
+    for scheduled in settings.probes.iter().filter(|scheduled| scheduled.probe.uses_bandwidth()) {
+        outcomes.push(probe::run(&scheduled.probe, http_client, scheduled.schedule.run_timeout).await);
+    }
+
+    write_results(&mut io::stdout().lock(), started, &outcomes, format)
+}
+
+/// Prints `outcomes` in `format`. Returns whether all probes succeeded, which decides
+/// the exit code.
+fn write_results(
+    out: &mut impl Write,
+    started: DateTime<Utc>,
+    outcomes: &[ProbeOutcome],
+    format: OutputFormat,
+) -> Result<bool> {
+    let success = outcomes.iter().all(ProbeOutcome::is_success);
+    match format {
+        OutputFormat::Json => {
+            let document = json!({
+                "time": started.to_rfc3339(),
+                "success": success,
+                "results": outcomes.iter().map(ProbeOutcome::to_json).collect::<Vec<_>>(),
+            });
+            serde_json::to_writer_pretty(&mut *out, &document)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Table => write_table(out, outcomes)?,
+    }
+    Ok(success)
+}
+
+fn write_table(out: &mut impl Write, outcomes: &[ProbeOutcome]) -> io::Result<()> {
+    let header = ["PROBE", "LABEL", "TARGET", "STATUS", "RESULT"];
+    let rows: Vec<[String; 5]> = outcomes
+        .iter()
+        .map(|outcome| [
+            outcome.kind.to_string(),
+            outcome.display_label(),
+            outcome.target.clone(),
+            if outcome.is_success() { "ok" } else { "FAILED" }.to_string(),
+            outcome.summary(),
+        ])
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(&rows) {
+        let (result, columns) = row.split_last().expect("table has columns");
+        for (cell, width) in columns.iter().zip(widths) {
+            write!(out, "{:<width$}  ", cell, width = width)?;
+        }
+        writeln!(out, "{}", result)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::config::IpVersion;
+    use crate::icmp::{EchoReply, PingResult};
+    use crate::probe::ProbeData;
+    use crate::throughput::UploadResult;
+    use super::*;
+
+    fn outcomes() -> Vec<ProbeOutcome> {
+        let time: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+        vec![
+            ProbeOutcome {
+                time,
+                kind: "ping",
+                target: "example.com".to_string(),
+                label: "example".to_string(),
+                ip_version: Some(IpVersion::V4),
+                result: Ok(ProbeData::Ping(PingResult {
+                    addr: "192.0.2.1".parse().unwrap(),
+                    transmitted: 4,
+                    replies: vec![EchoReply { seq: 1, rtt: Duration::from_millis(20), ttl: None }],
+                })),
+            },
+            ProbeOutcome {
+                time,
+                kind: "upload",
+                target: "http://example.com/upload".to_string(),
+                label: "upload".to_string(),
+                ip_version: None,
+                result: Ok(ProbeData::Upload(UploadResult {
+                    bytes: 1000,
+                    duration: Duration::from_millis(5),
+                    status: 413,
+                })),
+            },
+        ]
+    }
+
+    fn write(outcomes: &[ProbeOutcome], format: OutputFormat) -> (bool, String) {
+        let mut out = Vec::new();
+        let success = write_results(&mut out, "2024-05-01T12:00:00Z".parse().unwrap(), outcomes, format).unwrap();
+        (success, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn writes_json_document() {
+        let (success, out) = write(&outcomes(), OutputFormat::Json);
+        assert!(!success);
+        let document: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(document["time"], "2024-05-01T12:00:00+00:00");
+        assert_eq!(document["success"], false);
+        let results = document["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!((&results[0]["probe"], &results[0]["success"]), (&json!("ping"), &json!(true)));
+        assert_eq!((&results[1]["probe"], &results[1]["success"]), (&json!("upload"), &json!(false)));
+        assert_eq!(results[1]["error"], "HTTP status 413");
+    }
+
+    #[test]
+    fn writes_aligned_table() {
+        let (success, out) = write(&outcomes(), OutputFormat::Table);
+        assert!(!success);
+        assert_eq!(out, "\
+PROBE   LABEL           TARGET                     STATUS  RESULT
+ping    example (IPv4)  example.com                ok      20.00 ms avg, 0.00 ms jitter, 75.0% loss
+upload  upload          http://example.com/upload  FAILED  HTTP status 413
+");
+    }
+
+    #[test]
+    fn succeeds_only_when_every_probe_did() {
+        let outcomes = outcomes();
+        assert!(write(&outcomes[..1], OutputFormat::Json).0);
+        assert!(write(&outcomes[..1], OutputFormat::Table).0);
+        assert!(!write(&outcomes[1..], OutputFormat::Table).0);
+        // No probes, nothing failed
+        assert!(write(&[], OutputFormat::Json).0);
+    }
+}
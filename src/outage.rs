@@ -0,0 +1,241 @@
+use std::collections::BTreeSet;
+use chrono::{DateTime, Utc};
+use influxdb::{InfluxDbWriteable, WriteQuery};
+use tracing::{info, warn};
+use crate::probe::ProbeOutcome;
+
+/// Name of the InfluxDB measurement incidents are written to.
+pub const MEASUREMENT: &str = "internet_outages";
+
+/// Probe types whose failures mean the internet is unreachable. Throughput tests
+/// are left out, a broken test endpoint is not an outage.
+const CONNECTIVITY_PROBES: &[&str] = &["ping", "tcp"];
+
+#[derive(Debug, InfluxDbWriteable)]
+struct OutageMetrics {
+    /// When the first failed check of the incident finished.
+    time: DateTime<Utc>,
+    // A field rather than a tag, so the final point lands in the same series
+    ongoing: bool,
+    start: String,
+    /// Empty while the outage is ongoing.
+    end: String,
+    duration_s: f64,
+    failed_checks: i64,
+    /// Comma separated labels of every target that failed during the outage.
+    targets: String,
+}
+
+/// A period in which every connectivity check failed.
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub start: DateTime<Utc>,
+    /// `None` while the outage is ongoing.
+    pub end: Option<DateTime<Utc>>,
+    pub failed_checks: u32,
+    pub targets: BTreeSet<String>,
+}
+
+impl Incident {
+    pub fn duration(&self) -> chrono::Duration {
+        self.end.unwrap_or_else(Utc::now) - self.start
+    }
+
+    /// The incident as a point. Both the start and the end of an incident are written
+    /// with the start time, so the final point replaces the ongoing one.
+    pub fn to_query(&self) -> WriteQuery {
+        OutageMetrics {
+            time: self.start,
+            ongoing: self.end.is_none(),
+            start: self.start.to_rfc3339(),
+            end: self.end.map(|end| end.to_rfc3339()).unwrap_or_default(),
+            duration_s: self.duration().num_milliseconds() as f64 / 1000.0,
+            failed_checks: self.failed_checks as i64,
+            targets: self.targets.iter().cloned().collect::<Vec<_>>().join(","),
+        }.into_query(MEASUREMENT)
+    }
+}
+
+/// Declares an outage after `threshold` consecutive failed checks, counted across all
+/// targets, and ends it with the first successful check.
+pub struct OutageTracker {
+    threshold: u32,
+    /// Failed checks since the last successful one, the incident once over the threshold.
+    streak: Option<Incident>,
+    ongoing: bool,
+}
+
+impl OutageTracker {
+    pub fn new(threshold: u32) -> OutageTracker {
+        OutageTracker {
+            threshold: threshold.max(1),
+            streak: None,
+            ongoing: false,
+        }
+    }
+
+    /// Feeds a probe outcome into the tracker. Returns the incident when an outage
+    /// starts or ends, so it can be recorded.
+    pub fn observe(&mut self, outcome: &ProbeOutcome) -> Option<Incident> {
+        if !CONNECTIVITY_PROBES.contains(&outcome.kind) {
+            return None;
+        }
+
+        if outcome.is_success() {
+            let mut incident = self.streak.take()?;
+            if !std::mem::take(&mut self.ongoing) {
+                return None;
+            }
+            incident.end = Some(outcome.time);
+            info!("Connectivity restored after {}, outage from {} to {} with {} failed checks, affected: {}",
+                  format_duration(incident.duration()), incident.start.to_rfc3339(), outcome.time.to_rfc3339(),
+                  incident.failed_checks, incident.targets.iter().cloned().collect::<Vec<_>>().join(", "));
+            return Some(incident);
+        }
+
+        let streak = self.streak.get_or_insert_with(|| Incident {
+            start: outcome.time,
+            end: None,
+            failed_checks: 0,
+            targets: BTreeSet::new(),
+        });
+        streak.failed_checks += 1;
+        streak.targets.insert(outcome.display_label());
+
+        if !self.ongoing && streak.failed_checks >= self.threshold {
+            self.ongoing = true;
+            warn!("Outage detected, {} consecutive checks failed since {} ({})",
+                  streak.failed_checks, streak.start.to_rfc3339(),
+                  streak.targets.iter().cloned().collect::<Vec<_>>().join(", "));
+            return Some(streak.clone());
+        }
+        None
+    }
+}
+
+fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds().max(0);
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use chrono::TimeZone;
+    use influxdb::Query;
+    use crate::config::IpVersion;
+    use crate::icmp::{EchoReply, PingResult};
+    use crate::probe::ProbeData;
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn ping(label: &str, secs: i64, replies: usize) -> ProbeOutcome {
+        let replies = (0..replies)
+            .map(|seq| EchoReply { seq: seq as u16, rtt: Duration::from_millis(10), ttl: Some(57) })
+            .collect();
+        ProbeOutcome {
+            time: at(secs),
+            kind: "ping",
+            target: label.to_string(),
+            label: label.to_string(),
+            ip_version: None,
+            result: Ok(ProbeData::Ping(PingResult {
+                addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                transmitted: 4,
+                replies,
+            })),
+        }
+    }
+
+    fn failed(kind: &'static str, label: &str, secs: i64) -> ProbeOutcome {
+        ProbeOutcome {
+            time: at(secs),
+            kind,
+            target: label.to_string(),
+            label: label.to_string(),
+            ip_version: None,
+            result: Err("timed out".to_string()),
+        }
+    }
+
+    #[test]
+    fn outage_starts_at_threshold_and_ends_with_success() {
+        let mut tracker = OutageTracker::new(3);
+        assert!(tracker.observe(&ping("cloudflare", 0, 0)).is_none());
+        assert!(tracker.observe(&failed("tcp", "google", 10)).is_none());
+
+        let started = tracker.observe(&ping("quad9", 20, 0)).unwrap();
+        assert_eq!(started.start, at(0));
+        assert_eq!(started.end, None);
+        assert_eq!(started.failed_checks, 3);
+        assert_eq!(started.targets.iter().collect::<Vec<_>>(), ["cloudflare", "google", "quad9"]);
+
+        // Reported once, further failures only extend the incident
+        assert!(tracker.observe(&ping("cloudflare", 30, 0)).is_none());
+
+        let ended = tracker.observe(&ping("cloudflare", 45, 4)).unwrap();
+        assert_eq!(ended.start, at(0));
+        assert_eq!(ended.end, Some(at(45)));
+        assert_eq!(ended.failed_checks, 4);
+        assert_eq!(ended.duration(), chrono::Duration::seconds(45));
+        assert!(tracker.observe(&ping("cloudflare", 50, 4)).is_none());
+    }
+
+    #[test]
+    fn success_below_threshold_resets_streak() {
+        let mut tracker = OutageTracker::new(3);
+        assert!(tracker.observe(&ping("cloudflare", 0, 0)).is_none());
+        assert!(tracker.observe(&ping("cloudflare", 10, 0)).is_none());
+        // Partial loss still counts as an answer
+        assert!(tracker.observe(&ping("cloudflare", 20, 1)).is_none());
+        assert!(tracker.observe(&ping("cloudflare", 30, 0)).is_none());
+        assert!(tracker.observe(&ping("cloudflare", 40, 0)).is_none());
+
+        let started = tracker.observe(&ping("cloudflare", 50, 0)).unwrap();
+        assert_eq!(started.start, at(30));
+        assert_eq!(started.failed_checks, 3);
+    }
+
+    #[test]
+    fn ignores_probes_other_than_connectivity() {
+        let mut tracker = OutageTracker::new(1);
+        assert!(tracker.observe(&failed("download", "speedtest", 0)).is_none());
+        assert!(tracker.observe(&failed("http", "example", 5)).is_none());
+
+        assert!(tracker.observe(&failed("ping", "cloudflare", 10)).is_some());
+        assert!(tracker.observe(&failed("dns", "resolver", 20)).is_none());
+        assert!(tracker.observe(&ping("cloudflare", 30, 4)).unwrap().end.is_some());
+    }
+
+    #[test]
+    fn targets_include_ip_version() {
+        let mut tracker = OutageTracker::new(0);
+        let mut outcome = ping("cloudflare", 0, 0);
+        outcome.ip_version = Some(IpVersion::V6);
+        let started = tracker.observe(&outcome).unwrap();
+        assert_eq!(started.targets.iter().collect::<Vec<_>>(), ["cloudflare (IPv6)"]);
+    }
+
+    #[test]
+    fn to_query_marks_ongoing_incidents() {
+        let mut tracker = OutageTracker::new(1);
+        let started = tracker.observe(&ping("cloudflare", 0, 0)).unwrap();
+        let line = started.to_query().build().unwrap().get();
+        assert!(line.contains("ongoing=true"), "{}", line);
+        assert!(line.contains("end=\"\""), "{}", line);
+
+        let ended = tracker.observe(&ping("cloudflare", 90, 4)).unwrap();
+        let line = ended.to_query().build().unwrap().get();
+        assert!(line.contains("ongoing=false"), "{}", line);
+        assert!(line.contains("duration_s=90"), "{}", line);
+        assert_eq!(format_duration(ended.duration()), "1m 30s");
+    }
+}
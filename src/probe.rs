@@ -0,0 +1,1290 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use influxdb::{InfluxDbWriteable, WriteQuery};
+use serde_json::json;
+use tokio::time;
+use tracing::{info, warn};
+use crate::bufferbloat::{self, BufferbloatResult, LoadedLatency};
+use crate::config::{
+    BufferbloatProbe, DnsProbe, DownloadProbe, GatewayProbe, HttpProbe, InterfacesProbe, IpVersion, PathProbe,
+    PingProbe, ProbeConfig, PublicIpProbe, TcpProbe, UploadProbe, WifiProbe,
+};
+use crate::dns::{self, DnsResult};
+use crate::gateway;
+use crate::http::{self, HttpTiming};
+use crate::icmp::{self, PingResult};
+use crate::interfaces::{self, InterfaceStats};
+use crate::public_ip::{self, PublicIpResult};
+use crate::tcp;
+use crate::throughput::{self, DownloadResult, UploadResult};
+use crate::traceroute::{self, TraceResult};
+use crate::wifi::{self, WifiStats};
+
+/// Name of the InfluxDB measurement all probe points are written to.
+pub const MEASUREMENT: &str = "internet_metrics";
+
+#[derive(Debug, InfluxDbWriteable)]
+struct LatencyMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    ip_version: String,
+    /// `lan` for the default gateway, `internet` for everything else
+    #[influxdb(tag)]
+    scope: String,
+    latency_ms: Option<f64>,
+    latency_min_ms: Option<f64>,
+    latency_max_ms: Option<f64>,
+    /// Standard deviation of the round-trip times (ping's `mdev`)
+    jitter_ms: Option<f64>,
+    // Integers are signed throughout, InfluxDB 1.x rejects unsigned fields
+    packets_sent: i64,
+    packets_received: i64,
+    packet_loss_pct: f64,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct PathMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    /// Hop addresses separated by `>`, `*` for hops that didn't answer
+    path: String,
+    path_hops: i64,
+    path_reached: bool,
+    /// Left out when there is no previous path to compare with
+    path_changed: Option<bool>,
+}
+
+/// One point per hop of a path, tagged with the TTL.
+#[derive(Debug, InfluxDbWriteable)]
+struct HopMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    hop: String,
+    /// Left out when no router answered
+    hop_address: Option<String>,
+    hop_latency_ms: Option<f64>,
+    hop_latency_min_ms: Option<f64>,
+    hop_latency_max_ms: Option<f64>,
+    hop_jitter_ms: Option<f64>,
+    hop_sent: i64,
+    hop_received: i64,
+    hop_loss_pct: f64,
+}
+
+/// Written when a path differs from the previous one, for annotations.
+#[derive(Debug, InfluxDbWriteable)]
+struct PathChangeMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    path: String,
+    previous_path: String,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct DnsMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    server: String,
+    #[influxdb(tag)]
+    record_type: String,
+    dns_response_ms: f64,
+    dns_rcode: i64,
+    dns_rcode_name: String,
+    dns_answers: i64,
+    /// Left out when there is no previous answer to compare with
+    dns_answer_changed: Option<bool>,
+    dns_tcp: bool,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct HttpMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    http_status: i64,
+    http_dns_ms: f64,
+    http_connect_ms: f64,
+    /// Left out for plain HTTP
+    http_tls_ms: Option<f64>,
+    http_ttfb_ms: f64,
+    http_total_ms: f64,
+    http_body_bytes: i64,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct DownloadMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    download_bytes: i64,
+    download_duration_ms: f64,
+    download_ttfb_ms: f64,
+    download_mbps: f64,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct UploadMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    upload_bytes: Option<i64>,
+    upload_duration_ms: Option<f64>,
+    upload_mbps: Option<f64>,
+    upload_status: i64,
+}
+
+#[derive(Debug, InfluxDbWriteable)]
+struct PublicIpMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    ip_version: String,
+    public_ip: String,
+    public_ip_endpoint: String,
+    /// Left out when the lookup is disabled or failed
+    asn: Option<i64>,
+    as_name: Option<String>,
+    /// Left out when there is no previous address to compare with
+    public_ip_changed: Option<bool>,
+}
+
+/// Written when the public address differs from the previous one, for annotations.
+#[derive(Debug, InfluxDbWriteable)]
+struct PublicIpChangeMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    ip_version: String,
+    public_ip: String,
+    previous_public_ip: String,
+    asn: Option<i64>,
+    previous_asn: Option<i64>,
+}
+
+/// One point per network interface, tagged with its name. Rates and counter increases
+/// are left out on the first run and after the counters were reset.
+#[derive(Debug, InfluxDbWriteable)]
+struct InterfaceMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    interface: String,
+    interface_operstate: Option<String>,
+    interface_speed_mbps: Option<i64>,
+    interface_carrier_changes: Option<i64>,
+    interface_rx_bytes: i64,
+    interface_tx_bytes: i64,
+    interface_rx_bytes_per_sec: Option<f64>,
+    interface_tx_bytes_per_sec: Option<f64>,
+    /// Of the link speed, in the busier direction
+    interface_utilization_pct: Option<f64>,
+    interface_rx_errors: Option<i64>,
+    interface_tx_errors: Option<i64>,
+    interface_rx_dropped: Option<i64>,
+    interface_tx_dropped: Option<i64>,
+}
+
+/// One point per Wi-Fi interface, tagged with its name. Link fields are left out while
+/// the interface is not associated or the driver doesn't report them.
+#[derive(Debug, InfluxDbWriteable)]
+struct WifiMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    #[influxdb(tag)]
+    interface: String,
+    wifi_associated: bool,
+    wifi_ssid: Option<String>,
+    wifi_bssid: Option<String>,
+    wifi_frequency_mhz: Option<i64>,
+    wifi_link_quality: Option<f64>,
+    wifi_signal_dbm: Option<i64>,
+    wifi_noise_dbm: Option<i64>,
+    wifi_snr_db: Option<i64>,
+    wifi_tx_bitrate_mbps: Option<f64>,
+    wifi_rx_bitrate_mbps: Option<f64>,
+}
+
+/// Loaded latency fields are left out for a direction without a test URL.
+#[derive(Debug, InfluxDbWriteable)]
+struct BufferbloatMetrics {
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    measurement_type: String,
+    #[influxdb(tag)]
+    target: String,
+    #[influxdb(tag)]
+    label: String,
+    bufferbloat_idle_ms: Option<f64>,
+    bufferbloat_download_ms: Option<f64>,
+    bufferbloat_download_increase_ms: Option<f64>,
+    bufferbloat_download_loss_pct: Option<f64>,
+    bufferbloat_download_mbps: Option<f64>,
+    bufferbloat_download_grade: Option<String>,
+    bufferbloat_upload_ms: Option<f64>,
+    bufferbloat_upload_increase_ms: Option<f64>,
+    bufferbloat_upload_loss_pct: Option<f64>,
+    bufferbloat_upload_mbps: Option<f64>,
+    bufferbloat_upload_grade: Option<String>,
+}
+
+/// What a probe measured, by probe type.
+#[derive(Debug, Clone)]
+pub enum ProbeData {
+    /// Echo replies of a `ping` or `gateway` probe, or established connections of a `tcp` probe.
+    Ping(PingResult),
+    Path(TraceResult),
+    /// The record type queried and the response.
+    Dns(String, DnsResult),
+    Http(HttpTiming),
+    Download(DownloadResult),
+    Upload(UploadResult),
+    Bufferbloat(BufferbloatResult),
+    PublicIp(PublicIpResult),
+    Interfaces(Vec<InterfaceStats>),
+    Wifi(Vec<WifiStats>),
+}
+
+/// Result of running a single probe once.
+#[derive(Debug, Clone)]
+pub struct ProbeOutcome {
+    pub time: DateTime<Utc>,
+    /// Probe type, e.g. `ping`.
+    pub kind: &'static str,
+    pub target: String,
+    pub label: String,
+    /// IP version the probe is pinned to, `None` when the resolver picks.
+    pub ip_version: Option<IpVersion>,
+    /// The error message when the probe could not run at all.
+    pub result: Result<ProbeData, String>,
+}
+
+impl ProbeOutcome {
+    /// The label, with the IP version when the probe is pinned to one.
+    pub fn display_label(&self) -> String {
+        match self.ip_version {
+            Some(version) => format!("{} (IPv{})", self.label, version),
+            None => self.label.clone(),
+        }
+    }
+
+    /// Which side of the router the probe measures.
+    fn scope(&self) -> &'static str {
+        if self.kind == "gateway" { "lan" } else { "internet" }
+    }
+
+    /// Whether the probe got an answer, a ping with 100% loss counts as failed.
+    pub fn is_success(&self) -> bool {
+        match &self.result {
+            Ok(ProbeData::Ping(result)) => result.received() > 0,
+            Ok(ProbeData::Path(result)) => result.reached,
+            Ok(ProbeData::Dns(_, result)) => result.rcode == 0,
+            Ok(ProbeData::Http(timing)) => timing.is_success(),
+            Ok(ProbeData::Download(_)) => true,
+            Ok(ProbeData::Upload(result)) => result.is_success(),
+            Ok(ProbeData::Bufferbloat(_)) => true,
+            Ok(ProbeData::PublicIp(_)) => true,
+            Ok(ProbeData::Interfaces(_)) => true,
+            Ok(ProbeData::Wifi(interfaces)) => interfaces.iter().all(WifiStats::is_associated),
+            Err(_) => false,
+        }
+    }
+
+    /// Compares the outcome with the previous successful one of the same probe, for
+    /// probes that keep track of changes.
+    pub fn compare_with(&mut self, previous: &ProbeOutcome) {
+        let previous_time = previous.time;
+        match (&mut self.result, &previous.result) {
+            (Ok(ProbeData::Dns(_, result)), Ok(ProbeData::Dns(_, previous))) => {
+                let changed = result.answers != previous.answers;
+                if changed {
+                    info!("DNS answer for {} changed from {} to {}",
+                          self.label, previous.answers.join(", "), result.answers.join(", "));
+                }
+                result.changed = Some(changed);
+            }
+            (Ok(ProbeData::Path(result)), Ok(ProbeData::Path(previous))) => {
+                let changed = result.differs_from(previous);
+                if changed {
+                    info!("Path to {} changed from {} to {}", self.label, previous.path(), result.path());
+                    result.previous_path = Some(previous.path());
+                }
+                result.changed = Some(changed);
+            }
+            (Ok(ProbeData::PublicIp(result)), Ok(ProbeData::PublicIp(previous))) => {
+                let changed = result.addr != previous.addr;
+                if changed {
+                    info!("Public IP address of {} changed from {} to {}", self.label, previous.addr, result.addr);
+                    result.previous = Some((previous.addr, previous.asn.as_ref().map(|asn| asn.number)));
+                }
+                result.changed = Some(changed);
+            }
+            (Ok(ProbeData::Interfaces(interfaces)), Ok(ProbeData::Interfaces(previous))) => {
+                let elapsed = (self.time - previous_time).to_std().unwrap_or_default();
+                for interface in interfaces {
+                    if let Some(previous) = previous.iter().find(|previous| previous.name == interface.name) {
+                        interface.compare_with(previous, elapsed);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Converts the outcome to InfluxDB points, probes that errored produce none.
+    pub fn to_queries(&self) -> Vec<WriteQuery> {
+        let Ok(data) = &self.result else { return Vec::new() };
+        let query = match data {
+            ProbeData::Ping(result) => {
+                let stats = result.stats();
+                LatencyMetrics {
+                    time: self.time,
+                    measurement_type: if self.kind == "tcp" { "tcp" } else { "latency" }.to_string(),
+                    target: self.target.clone(),
+                    label: self.label.clone(),
+                    ip_version: if result.addr.is_ipv4() { "4" } else { "6" }.to_string(),
+                    scope: self.scope().to_string(),
+                    latency_ms: stats.as_ref().map(|stats| stats.avg_ms),
+                    latency_min_ms: stats.as_ref().map(|stats| stats.min_ms),
+                    latency_max_ms: stats.as_ref().map(|stats| stats.max_ms),
+                    jitter_ms: stats.as_ref().map(|stats| stats.mdev_ms),
+                    packets_sent: result.transmitted as i64,
+                    packets_received: result.received() as i64,
+                    packet_loss_pct: result.loss_pct(),
+                }.into_query(MEASUREMENT)
+            }
+            ProbeData::Path(result) => return self.path_queries(result),
+            ProbeData::PublicIp(result) => return self.public_ip_queries(result),
+            ProbeData::Interfaces(interfaces) => return self.interface_queries(interfaces),
+            ProbeData::Wifi(interfaces) => return self.wifi_queries(interfaces),
+            ProbeData::Dns(record_type, result) => DnsMetrics {
+                time: self.time,
+                measurement_type: "dns".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                server: result.server.to_string(),
+                record_type: record_type.clone(),
+                dns_response_ms: result.rtt.as_secs_f64() * 1000.0,
+                dns_rcode: result.rcode as i64,
+                dns_rcode_name: result.rcode_name(),
+                dns_answers: result.answer_count as i64,
+                dns_answer_changed: result.changed,
+                dns_tcp: result.tcp,
+            }.into_query(MEASUREMENT),
+            ProbeData::Http(timing) => HttpMetrics {
+                time: self.time,
+                measurement_type: "http".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                http_status: timing.status as i64,
+                http_dns_ms: timing.dns.as_secs_f64() * 1000.0,
+                http_connect_ms: timing.connect.as_secs_f64() * 1000.0,
+                http_tls_ms: timing.tls.map(|tls| tls.as_secs_f64() * 1000.0),
+                http_ttfb_ms: timing.ttfb.as_secs_f64() * 1000.0,
+                http_total_ms: timing.total.as_secs_f64() * 1000.0,
+                http_body_bytes: timing.body_bytes as i64,
+            }.into_query(MEASUREMENT),
+            ProbeData::Download(result) => DownloadMetrics {
+                time: self.time,
+                measurement_type: "download".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                download_bytes: result.bytes as i64,
+                download_duration_ms: result.duration.as_secs_f64() * 1000.0,
+                download_ttfb_ms: result.ttfb.as_secs_f64() * 1000.0,
+                download_mbps: result.mbps(),
+            }.into_query(MEASUREMENT),
+            ProbeData::Upload(result) => {
+                let success = result.is_success();
+                UploadMetrics {
+                    time: self.time,
+                    measurement_type: "upload".to_string(),
+                    target: self.target.clone(),
+                    label: self.label.clone(),
+                    upload_bytes: success.then_some(result.bytes as i64),
+                    upload_duration_ms: success.then_some(result.duration.as_secs_f64() * 1000.0),
+                    upload_mbps: success.then_some(result.mbps()),
+                    upload_status: result.status as i64,
+                }.into_query(MEASUREMENT)
+            }
+            ProbeData::Bufferbloat(result) => {
+                let latency = |loaded: &Option<LoadedLatency>| {
+                    loaded.as_ref().and_then(|loaded| loaded.ping.stats()).map(|stats| stats.avg_ms)
+                };
+                let increase = |loaded: &Option<LoadedLatency>| {
+                    loaded.as_ref().and_then(|loaded| loaded.increase_ms(&result.idle))
+                };
+                let grade = |loaded: &Option<LoadedLatency>| {
+                    loaded.as_ref().map(|loaded| loaded.grade(&result.idle).to_string())
+                };
+                BufferbloatMetrics {
+                    time: self.time,
+                    measurement_type: "bufferbloat".to_string(),
+                    target: self.target.clone(),
+                    label: self.label.clone(),
+                    bufferbloat_idle_ms: result.idle.stats().map(|stats| stats.avg_ms),
+                    bufferbloat_download_ms: latency(&result.download),
+                    bufferbloat_download_increase_ms: increase(&result.download),
+                    bufferbloat_download_loss_pct: result.download.as_ref().map(|loaded| loaded.ping.loss_pct()),
+                    bufferbloat_download_mbps: result.download.as_ref().map(|loaded| loaded.mbps),
+                    bufferbloat_download_grade: grade(&result.download),
+                    bufferbloat_upload_ms: latency(&result.upload),
+                    bufferbloat_upload_increase_ms: increase(&result.upload),
+                    bufferbloat_upload_loss_pct: result.upload.as_ref().map(|loaded| loaded.ping.loss_pct()),
+                    bufferbloat_upload_mbps: result.upload.as_ref().map(|loaded| loaded.mbps),
+                    bufferbloat_upload_grade: grade(&result.upload),
+                }.into_query(MEASUREMENT)
+            }
+        };
+        vec![query]
+    }
+
+    /// A point for the path, one per hop, and one more when the path changed.
+    fn path_queries(&self, result: &TraceResult) -> Vec<WriteQuery> {
+        let mut queries = vec![PathMetrics {
+            time: self.time,
+            measurement_type: "path".to_string(),
+            target: self.target.clone(),
+            label: self.label.clone(),
+            path: result.path(),
+            path_hops: result.hops.len() as i64,
+            path_reached: result.reached,
+            path_changed: result.changed,
+        }.into_query(MEASUREMENT)];
+        queries.extend(result.hops.iter().map(|hop| {
+            let stats = hop.stats();
+            HopMetrics {
+                time: self.time,
+                measurement_type: "path_hop".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                hop: hop.ttl.to_string(),
+                hop_address: hop.addr.map(|addr| addr.to_string()),
+                hop_latency_ms: stats.as_ref().map(|stats| stats.avg_ms),
+                hop_latency_min_ms: stats.as_ref().map(|stats| stats.min_ms),
+                hop_latency_max_ms: stats.as_ref().map(|stats| stats.max_ms),
+                hop_jitter_ms: stats.as_ref().map(|stats| stats.mdev_ms),
+                hop_sent: hop.sent as i64,
+                hop_received: hop.received() as i64,
+                hop_loss_pct: hop.loss_pct(),
+            }.into_query(MEASUREMENT)
+        }));
+        if let Some(previous_path) = &result.previous_path {
+            queries.push(PathChangeMetrics {
+                time: self.time,
+                measurement_type: "path_change".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                path: result.path(),
+                previous_path: previous_path.clone(),
+            }.into_query(MEASUREMENT));
+        }
+        queries
+    }
+
+    /// A point for the address, and one more when it changed.
+    fn public_ip_queries(&self, result: &PublicIpResult) -> Vec<WriteQuery> {
+        let ip_version = if result.addr.is_ipv4() { "4" } else { "6" };
+        let asn = result.asn.as_ref().map(|asn| asn.number as i64);
+        let mut queries = vec![PublicIpMetrics {
+            time: self.time,
+            measurement_type: "public_ip".to_string(),
+            target: self.target.clone(),
+            label: self.label.clone(),
+            ip_version: ip_version.to_string(),
+            public_ip: result.addr.to_string(),
+            public_ip_endpoint: result.endpoint.clone(),
+            asn,
+            as_name: result.asn.as_ref().and_then(|asn| asn.name.clone()),
+            public_ip_changed: result.changed,
+        }.into_query(MEASUREMENT)];
+        if let Some((previous, previous_asn)) = result.previous {
+            queries.push(PublicIpChangeMetrics {
+                time: self.time,
+                measurement_type: "public_ip_change".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                ip_version: ip_version.to_string(),
+                public_ip: result.addr.to_string(),
+                previous_public_ip: previous.to_string(),
+                asn,
+                previous_asn: previous_asn.map(|asn| asn as i64),
+            }.into_query(MEASUREMENT));
+        }
+        queries
+    }
+
+    /// A point per interface.
+    fn interface_queries(&self, interfaces: &[InterfaceStats]) -> Vec<WriteQuery> {
+        interfaces
+            .iter()
+            .map(|interface| {
+                let rates = interface.rates.as_ref();
+                InterfaceMetrics {
+                    time: self.time,
+                    measurement_type: "interface".to_string(),
+                    target: self.target.clone(),
+                    label: self.label.clone(),
+                    interface: interface.name.clone(),
+                    interface_operstate: interface.operstate.clone(),
+                    interface_speed_mbps: interface.speed_mbps.map(|speed| speed as i64),
+                    interface_carrier_changes: interface.carrier_changes.map(|changes| changes as i64),
+                    interface_rx_bytes: interface.counters.rx_bytes as i64,
+                    interface_tx_bytes: interface.counters.tx_bytes as i64,
+                    interface_rx_bytes_per_sec: rates.map(|rates| rates.rx_bytes_per_sec),
+                    interface_tx_bytes_per_sec: rates.map(|rates| rates.tx_bytes_per_sec),
+                    interface_utilization_pct: interface.utilization_pct(),
+                    interface_rx_errors: rates.map(|rates| rates.rx_errors as i64),
+                    interface_tx_errors: rates.map(|rates| rates.tx_errors as i64),
+                    interface_rx_dropped: rates.map(|rates| rates.rx_dropped as i64),
+                    interface_tx_dropped: rates.map(|rates| rates.tx_dropped as i64),
+                }.into_query(MEASUREMENT)
+            })
+            .collect()
+    }
+
+    /// A point per Wi-Fi interface.
+    fn wifi_queries(&self, interfaces: &[WifiStats]) -> Vec<WriteQuery> {
+        interfaces
+            .iter()
+            .map(|wifi| WifiMetrics {
+                time: self.time,
+                measurement_type: "wifi".to_string(),
+                target: self.target.clone(),
+                label: self.label.clone(),
+                interface: wifi.interface.clone(),
+                wifi_associated: wifi.is_associated(),
+                wifi_ssid: wifi.ssid.clone(),
+                wifi_bssid: wifi.bssid.clone(),
+                wifi_frequency_mhz: wifi.frequency_mhz.map(i64::from),
+                wifi_link_quality: wifi.link_quality,
+                wifi_signal_dbm: wifi.signal_dbm.map(i64::from),
+                wifi_noise_dbm: wifi.noise_dbm.map(i64::from),
+                wifi_snr_db: wifi.snr_db().map(i64::from),
+                wifi_tx_bitrate_mbps: wifi.tx_bitrate_mbps,
+                wifi_rx_bitrate_mbps: wifi.rx_bitrate_mbps,
+            }.into_query(MEASUREMENT))
+            .collect()
+    }
+
+    /// The outcome as JSON, measurements use the same names as the InfluxDB fields.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = json!({
+            "time": self.time.to_rfc3339(),
+            "probe": self.kind,
+            "target": self.target,
+            "label": self.label,
+            "success": self.is_success(),
+        });
+        let measurements = match &self.result {
+            Ok(ProbeData::Ping(result)) => {
+                let stats = result.stats();
+                json!({
+                    "address": (!result.addr.is_unspecified()).then(|| result.addr.to_string()),
+                    "ip_version": if result.addr.is_ipv4() { "4" } else { "6" },
+                    "scope": self.scope(),
+                    "latency_ms": stats.as_ref().map(|stats| stats.avg_ms),
+                    "latency_min_ms": stats.as_ref().map(|stats| stats.min_ms),
+                    "latency_max_ms": stats.as_ref().map(|stats| stats.max_ms),
+                    "jitter_ms": stats.as_ref().map(|stats| stats.mdev_ms),
+                    "packets_sent": result.transmitted,
+                    "packets_received": result.received(),
+                    "packet_loss_pct": result.loss_pct(),
+                })
+            }
+            Ok(ProbeData::Path(result)) => json!({
+                "address": result.addr.to_string(),
+                "path": result.path(),
+                "path_hops": result.hops.len(),
+                "path_reached": result.reached,
+                "path_changed": result.changed,
+                "previous_path": result.previous_path,
+                "hops": result.hops.iter().map(|hop| {
+                    let stats = hop.stats();
+                    json!({
+                        "hop": hop.ttl,
+                        "hop_address": hop.addr.map(|addr| addr.to_string()),
+                        "hop_latency_ms": stats.as_ref().map(|stats| stats.avg_ms),
+                        "hop_latency_min_ms": stats.as_ref().map(|stats| stats.min_ms),
+                        "hop_latency_max_ms": stats.as_ref().map(|stats| stats.max_ms),
+                        "hop_jitter_ms": stats.as_ref().map(|stats| stats.mdev_ms),
+                        "hop_sent": hop.sent,
+                        "hop_received": hop.received(),
+                        "hop_loss_pct": hop.loss_pct(),
+                    })
+                }).collect::<Vec<_>>(),
+            }),
+            Ok(ProbeData::Dns(record_type, result)) => json!({
+                "server": result.server.to_string(),
+                "record_type": record_type,
+                "dns_response_ms": result.rtt.as_secs_f64() * 1000.0,
+                "dns_rcode": result.rcode,
+                "dns_rcode_name": result.rcode_name(),
+                "dns_answers": result.answer_count,
+                "dns_answer_changed": result.changed,
+                "dns_tcp": result.tcp,
+                "answers": result.answers,
+            }),
+            Ok(ProbeData::Http(timing)) => json!({
+                "address": timing.addr.to_string(),
+                "http_status": timing.status,
+                "http_dns_ms": timing.dns.as_secs_f64() * 1000.0,
+                "http_connect_ms": timing.connect.as_secs_f64() * 1000.0,
+                "http_tls_ms": timing.tls.map(|tls| tls.as_secs_f64() * 1000.0),
+                "http_ttfb_ms": timing.ttfb.as_secs_f64() * 1000.0,
+                "http_total_ms": timing.total.as_secs_f64() * 1000.0,
+                "http_body_bytes": timing.body_bytes,
+            }),
+            Ok(ProbeData::Download(result)) => json!({
+                "download_bytes": result.bytes,
+                "download_duration_ms": result.duration.as_secs_f64() * 1000.0,
+                "download_ttfb_ms": result.ttfb.as_secs_f64() * 1000.0,
+                "download_mbps": result.mbps(),
+            }),
+            Ok(ProbeData::Upload(result)) if result.is_success() => json!({
+                "upload_bytes": result.bytes,
+                "upload_duration_ms": result.duration.as_secs_f64() * 1000.0,
+                "upload_mbps": result.mbps(),
+                "upload_status": result.status,
+            }),
+            // Like the InfluxDB point, a rejected upload has no throughput to report
+            Ok(ProbeData::Upload(result)) => json!({
+                "upload_status": result.status,
+                "error": format!("HTTP status {}", result.status),
+            }),
+            Ok(ProbeData::PublicIp(result)) => json!({
+                "ip_version": if result.addr.is_ipv4() { "4" } else { "6" },
+                "public_ip": result.addr.to_string(),
+                "public_ip_endpoint": result.endpoint,
+                "asn": result.asn.as_ref().map(|asn| asn.number),
+                "as_name": result.asn.as_ref().and_then(|asn| asn.name.clone()),
+                "public_ip_changed": result.changed,
+                "previous_public_ip": result.previous.map(|(previous, _)| previous.to_string()),
+            }),
+            Ok(ProbeData::Interfaces(interfaces)) => json!({
+                "interfaces": interfaces.iter().map(|interface| {
+                    let rates = interface.rates.as_ref();
+                    json!({
+                        "interface": interface.name,
+                        "interface_operstate": interface.operstate,
+                        "interface_speed_mbps": interface.speed_mbps,
+                        "interface_carrier_changes": interface.carrier_changes,
+                        "interface_rx_bytes": interface.counters.rx_bytes,
+                        "interface_tx_bytes": interface.counters.tx_bytes,
+                        "interface_rx_bytes_per_sec": rates.map(|rates| rates.rx_bytes_per_sec),
+                        "interface_tx_bytes_per_sec": rates.map(|rates| rates.tx_bytes_per_sec),
+                        "interface_utilization_pct": interface.utilization_pct(),
+                        "interface_rx_errors": rates.map(|rates| rates.rx_errors),
+                        "interface_tx_errors": rates.map(|rates| rates.tx_errors),
+                        "interface_rx_dropped": rates.map(|rates| rates.rx_dropped),
+                        "interface_tx_dropped": rates.map(|rates| rates.tx_dropped),
+                    })
+                }).collect::<Vec<_>>(),
+            }),
+            Ok(ProbeData::Wifi(interfaces)) => json!({
+                "interfaces": interfaces.iter().map(|wifi| json!({
+                    "interface": wifi.interface,
+                    "wifi_associated": wifi.is_associated(),
+                    "wifi_ssid": wifi.ssid,
+                    "wifi_bssid": wifi.bssid,
+                    "wifi_frequency_mhz": wifi.frequency_mhz,
+                    "wifi_link_quality": wifi.link_quality,
+                    "wifi_signal_dbm": wifi.signal_dbm,
+                    "wifi_noise_dbm": wifi.noise_dbm,
+                    "wifi_snr_db": wifi.snr_db(),
+                    "wifi_tx_bitrate_mbps": wifi.tx_bitrate_mbps,
+                    "wifi_rx_bitrate_mbps": wifi.rx_bitrate_mbps,
+                })).collect::<Vec<_>>(),
+            }),
+            Ok(ProbeData::Bufferbloat(result)) => {
+                let mut measurements = json!({
+                    "address": result.idle.addr.to_string(),
+                    "bufferbloat_idle_ms": result.idle.stats().map(|stats| stats.avg_ms),
+                });
+                for (direction, loaded) in [("download", &result.download), ("upload", &result.upload)] {
+                    let Some(loaded) = loaded else { continue };
+                    measurements[format!("bufferbloat_{}_ms", direction)] =
+                        json!(loaded.ping.stats().map(|stats| stats.avg_ms));
+                    measurements[format!("bufferbloat_{}_increase_ms", direction)] =
+                        json!(loaded.increase_ms(&result.idle));
+                    measurements[format!("bufferbloat_{}_loss_pct", direction)] = json!(loaded.ping.loss_pct());
+                    measurements[format!("bufferbloat_{}_mbps", direction)] = json!(loaded.mbps);
+                    measurements[format!("bufferbloat_{}_grade", direction)] = json!(loaded.grade(&result.idle));
+                }
+                measurements
+            }
+            Err(e) => json!({ "error": e }),
+        };
+        if let (Some(value), serde_json::Value::Object(measurements)) = (value.as_object_mut(), measurements) {
+            value.extend(measurements);
+        }
+        value
+    }
+
+    /// One line description of the result, e.g. for a table.
+    pub fn summary(&self) -> String {
+        match &self.result {
+            Ok(ProbeData::Ping(result)) => match result.stats() {
+                Some(stats) => format!("{:.2} ms avg, {:.2} ms jitter, {:.1}% loss",
+                                       stats.avg_ms, stats.mdev_ms, result.loss_pct()),
+                None => format!("no replies, {:.1}% loss", result.loss_pct()),
+            },
+            Ok(ProbeData::Path(result)) => {
+                let hops = match result.hops.len() {
+                    1 => "1 hop".to_string(),
+                    count => format!("{} hops", count),
+                };
+                let destination = result.hops.last().filter(|_| result.reached).and_then(|hop| hop.stats());
+                match destination {
+                    Some(stats) => format!("{}, {:.2} ms to the destination", hops, stats.avg_ms),
+                    None => format!("destination not reached, {}", hops),
+                }
+            }
+            Ok(ProbeData::Dns(record_type, result)) => format!("{:.2} ms, {}, {} {} records from {}",
+                                                               result.rtt.as_secs_f64() * 1000.0, result.rcode_name(),
+                                                               result.answers.len(), record_type, result.server),
+            Ok(ProbeData::Http(timing)) => format!("HTTP {} in {:.2} ms, {:.2} ms TTFB",
+                                                   timing.status, timing.total.as_secs_f64() * 1000.0,
+                                                   timing.ttfb.as_secs_f64() * 1000.0),
+            Ok(ProbeData::Download(result)) => format!("{:.2} Mbit/s, {:.2} ms TTFB",
+                                                       result.mbps(), result.ttfb.as_secs_f64() * 1000.0),
+            Ok(ProbeData::Upload(result)) if result.is_success() => format!("{:.2} Mbit/s", result.mbps()),
+            Ok(ProbeData::Upload(result)) => format!("HTTP status {}", result.status),
+            Ok(ProbeData::PublicIp(result)) => {
+                let mut summary = result.addr.to_string();
+                if let Some(asn) = &result.asn {
+                    summary += &format!(", AS{}", asn.number);
+                    if let Some(name) = &asn.name {
+                        summary += &format!(" {}", name);
+                    }
+                }
+                if let Some((previous, _)) = result.previous {
+                    summary += &format!(", changed from {}", previous);
+                }
+                summary
+            }
+            Ok(ProbeData::Interfaces(interfaces)) => {
+                let interfaces: Vec<String> = interfaces
+                    .iter()
+                    .map(|interface| {
+                        let state = interface.operstate.as_deref().unwrap_or("unknown");
+                        match &interface.rates {
+                            Some(rates) => format!("{} {}, rx {:.2} / tx {:.2} Mbit/s",
+                                                   interface.name, state,
+                                                   rates.rx_bytes_per_sec * 8.0 / 1_000_000.0,
+                                                   rates.tx_bytes_per_sec * 8.0 / 1_000_000.0),
+                            None => format!("{} {}", interface.name, state),
+                        }
+                    })
+                    .collect();
+                match interfaces.is_empty() {
+                    true => "no interfaces".to_string(),
+                    false => interfaces.join(", "),
+                }
+            }
+            Ok(ProbeData::Wifi(interfaces)) => {
+                let interfaces: Vec<String> = interfaces
+                    .iter()
+                    .map(|wifi| {
+                        let mut summary = match (&wifi.ssid, wifi.signal_dbm) {
+                            (Some(ssid), Some(signal)) => format!("{} on {}, {} dBm", wifi.interface, ssid, signal),
+                            (None, Some(signal)) => format!("{} {} dBm", wifi.interface, signal),
+                            _ if wifi.is_associated() => wifi.interface.clone(),
+                            _ => return format!("{} not associated", wifi.interface),
+                        };
+                        if let Some(bitrate) = wifi.tx_bitrate_mbps {
+                            summary += &format!(", {:.1} Mbit/s", bitrate);
+                        }
+                        summary
+                    })
+                    .collect();
+                interfaces.join(", ")
+            }
+            Ok(ProbeData::Bufferbloat(result)) => {
+                let directions: Vec<String> = [("download", &result.download), ("upload", &result.upload)]
+                    .into_iter()
+                    .filter_map(|(direction, loaded)| {
+                        let loaded = loaded.as_ref()?;
+                        Some(match loaded.increase_ms(&result.idle) {
+                            Some(increase) => format!("{} {} (+{:.1} ms)", direction, loaded.grade(&result.idle), increase),
+                            None => format!("{} {} (no replies)", direction, loaded.grade(&result.idle)),
+                        })
+                    })
+                    .collect();
+                directions.join(", ")
+            }
+            Err(e) => e.clone(),
+        }
+    }
+}
+
+async fn measure_latency(probe: &PingProbe) -> Result<PingResult> {
+    let version = probe.ip_version.unwrap_or(IpVersion::Auto);
+    let options = icmp::PingOptions {
+        count: probe.count,
+        timeout: Duration::from_secs(probe.timeout),
+        ..icmp::PingOptions::default()
+    };
+    let result = match icmp::resolve(&probe.host, version).await {
+        Ok(addr) => icmp::ping(addr, &options).await,
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        // A target pinned to a version it can't be reached over is a measurement, not an error
+        Err(e) if matches!(version, IpVersion::V4 | IpVersion::V6) && is_unreachable(&e) => {
+            warn!("{}: unreachable over IPv{}: {:#}", probe.label(), version, e);
+            PingResult::unreachable(version, probe.count)
+        }
+        result => result?,
+    };
+    log_ping(probe.label(), &result);
+    Ok(result)
+}
+
+/// Whether `error` means there is no way to the host at all, because it has no address
+/// of the version or no route to it.
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| match cause.downcast_ref::<io::Error>() {
+        Some(e) => matches!(e.kind(),
+                            io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable | io::ErrorKind::AddrNotAvailable),
+        None => cause.is::<icmp::NoAddressError>(),
+    })
+}
+
+async fn measure_gateway(probe: &GatewayProbe) -> Result<PingResult> {
+    let gateway = gateway::default_gateway(probe.ip_version.unwrap_or(IpVersion::Auto))?;
+    info!("{}: default gateway is {}", probe.label(), gateway);
+    let options = icmp::PingOptions {
+        count: probe.count,
+        timeout: Duration::from_secs(probe.timeout),
+        ..icmp::PingOptions::default()
+    };
+    let result = icmp::ping_scoped(gateway.addr, gateway.scope_id(), &options).await?;
+    log_ping(probe.label(), &result);
+    Ok(result)
+}
+
+fn log_ping(label: &str, result: &PingResult) {
+    for reply in &result.replies {
+        info!("Reply from {} ({}): icmp_seq={} ttl={} time={:.3} ms",
+              label, result.addr, reply.seq,
+              reply.ttl.map_or_else(|| "?".to_string(), |ttl| ttl.to_string()),
+              reply.rtt.as_secs_f64() * 1000.0);
+    }
+    info!("{}: {} packets transmitted, {} received, {:.1}% packet loss",
+          label, result.transmitted, result.received(), result.loss_pct());
+    match result.stats() {
+        Some(stats) => info!("{}: min/avg/max/mdev = {:.2}/{:.2}/{:.2}/{:.2} ms",
+                             label, stats.min_ms, stats.avg_ms, stats.max_ms, stats.mdev_ms),
+        None => warn!("No echo replies from {} ({})", label, result.addr),
+    }
+}
+
+async fn measure_tcp(probe: &TcpProbe) -> Result<PingResult> {
+    let (host, port) = tcp::split_host_port(&probe.address)?;
+    let addr = SocketAddr::new(icmp::resolve(host, IpVersion::Auto).await?, port);
+    let options = icmp::PingOptions {
+        count: probe.count,
+        timeout: Duration::from_secs(probe.timeout),
+        ..icmp::PingOptions::default()
+    };
+    let result = tcp::connect(addr, &options).await;
+
+    info!("{}: {} connections attempted, {} established, {:.1}% loss",
+          probe.label(), result.transmitted, result.received(), result.loss_pct());
+    match result.stats() {
+        Some(stats) => info!("{}: connect min/avg/max/mdev = {:.2}/{:.2}/{:.2}/{:.2} ms",
+                             probe.label(), stats.min_ms, stats.avg_ms, stats.max_ms, stats.mdev_ms),
+        None => warn!("No connection to {} ({}) could be established", probe.label(), addr),
+    }
+
+    Ok(result)
+}
+
+async fn measure_path(probe: &PathProbe) -> Result<TraceResult> {
+    let addr = icmp::resolve(&probe.host, IpVersion::Auto).await?;
+    let options = traceroute::TraceOptions {
+        max_hops: probe.max_hops,
+        queries: probe.queries,
+        timeout: Duration::from_secs(probe.timeout),
+        ..traceroute::TraceOptions::default()
+    };
+    let result = traceroute::trace(addr, &options).await?;
+
+    for hop in &result.hops {
+        let addr = hop.addr.map_or_else(|| "???".to_string(), |addr| addr.to_string());
+        match hop.stats() {
+            Some(stats) => info!("{}: {:>2}. {} {:.1}% loss, min/avg/max = {:.2}/{:.2}/{:.2} ms",
+                                 probe.label(), hop.ttl, addr, hop.loss_pct(), stats.min_ms, stats.avg_ms, stats.max_ms),
+            None => info!("{}: {:>2}. {} {:.1}% loss", probe.label(), hop.ttl, addr, hop.loss_pct()),
+        }
+    }
+    if !result.reached {
+        warn!("Path to {} ({}) did not reach the destination", probe.label(), result.addr);
+    }
+
+    Ok(result)
+}
+
+async fn measure_dns(probe: &DnsProbe) -> Result<DnsResult> {
+    let server = match &probe.server {
+        Some(server) => dns::parse_server(server)?,
+        None => dns::system_resolver()?,
+    };
+    let result = dns::query(server, &probe.name, probe.record_type, Duration::from_secs(probe.timeout)).await?;
+    info!("DNS {} {} from {}: {} in {:.2} ms, {}{}",
+          probe.record_type, probe.name, server, result.rcode_name(), result.rtt.as_secs_f64() * 1000.0,
+          if result.answers.is_empty() { "no answers".to_string() } else { result.answers.join(", ") },
+          if result.tcp { " (over TCP)" } else { "" });
+    Ok(result)
+}
+
+async fn measure_http(probe: &HttpProbe) -> Result<HttpTiming> {
+    let timing = http::get(&probe.url, Duration::from_secs(probe.timeout)).await?;
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    info!("HTTP {} from {} ({}): dns {:.2} ms, connect {:.2} ms, tls {}, ttfb {:.2} ms, total {:.2} ms, {} bytes",
+          timing.status, probe.label(), timing.addr, ms(timing.dns), ms(timing.connect),
+          timing.tls.map_or_else(|| "-".to_string(), |tls| format!("{:.2} ms", ms(tls))),
+          ms(timing.ttfb), ms(timing.total), timing.body_bytes);
+    Ok(timing)
+}
+
+async fn measure_download(probe: &DownloadProbe, http_client: &reqwest::Client) -> Result<DownloadResult> {
+    let limits = throughput::DownloadLimits {
+        max_bytes: probe.max_bytes,
+        max_duration: Duration::from_secs(probe.max_duration),
+    };
+    let result = throughput::download(http_client, &probe.url, &limits).await?;
+    info!("Download from {}: {} bytes in {:.2} s ({:.2} Mbit/s, TTFB {:.2} ms)",
+          probe.label(), result.bytes, result.duration.as_secs_f64(), result.mbps(),
+          result.ttfb.as_secs_f64() * 1000.0);
+    Ok(result)
+}
+
+async fn measure_upload(probe: &UploadProbe, http_client: &reqwest::Client) -> Result<UploadResult> {
+    let max_duration = Duration::from_secs(probe.max_duration);
+    let result = throughput::upload(http_client, &probe.url, probe.bytes, max_duration).await?;
+    if result.is_success() {
+        info!("Upload to {}: {} bytes in {:.2} s ({:.2} Mbit/s)",
+              probe.label(), result.bytes, result.duration.as_secs_f64(), result.mbps());
+    } else {
+        warn!("Upload to {} failed with HTTP status {}", probe.label(), result.status);
+    }
+    Ok(result)
+}
+
+async fn measure_bufferbloat(probe: &BufferbloatProbe, http_client: &reqwest::Client) -> Result<BufferbloatResult> {
+    let addr = icmp::resolve(&probe.host, IpVersion::Auto).await?;
+    let options = bufferbloat::BufferbloatOptions {
+        duration: Duration::from_secs(probe.duration),
+        streams: probe.streams,
+        timeout: Duration::from_secs(probe.timeout),
+    };
+    let result = bufferbloat::measure(
+        http_client,
+        addr,
+        probe.download_url.as_deref(),
+        probe.upload_url.as_deref(),
+        &options,
+    ).await?;
+
+    let idle_ms = result.idle.stats().map_or(0.0, |stats| stats.avg_ms);
+    info!("{}: idle latency {:.2} ms", probe.label(), idle_ms);
+    for (direction, loaded) in [("download", &result.download), ("upload", &result.upload)] {
+        let Some(loaded) = loaded else { continue };
+        match loaded.ping.stats() {
+            Some(stats) => info!("{}: latency under {} load {:.2} ms (+{:.2} ms, {:.1}% loss) at {:.2} Mbit/s, grade {}",
+                                 probe.label(), direction, stats.avg_ms, stats.avg_ms - idle_ms,
+                                 loaded.ping.loss_pct(), loaded.mbps, loaded.grade(&result.idle)),
+            None => warn!("{}: no echo replies under {} load at {:.2} Mbit/s, grade {}",
+                          probe.label(), direction, loaded.mbps, loaded.grade(&result.idle)),
+        }
+    }
+
+    Ok(result)
+}
+
+async fn measure_public_ip(probe: &PublicIpProbe) -> Result<PublicIpResult> {
+    let timeout = Duration::from_secs(probe.timeout);
+    let (addr, endpoint) =
+        public_ip::discover(&probe.endpoints, probe.ip_version.unwrap_or(IpVersion::Auto), timeout).await?;
+    let asn = if probe.asn_lookup {
+        public_ip::lookup_asn(addr, timeout)
+            .await
+            .map_err(|e| warn!("{}: failed to look up the AS of {}: {:#}", probe.label(), addr, e))
+            .ok()
+    } else {
+        None
+    };
+    match &asn {
+        Some(asn) => info!("{}: public address {} from {}, AS{} {}",
+                           probe.label(), addr, endpoint, asn.number, asn.name.as_deref().unwrap_or("")),
+        None => info!("{}: public address {} from {}", probe.label(), addr, endpoint),
+    }
+    Ok(PublicIpResult { addr, endpoint, asn, changed: None, previous: None })
+}
+
+async fn measure_interfaces(probe: &InterfacesProbe) -> Result<Vec<InterfaceStats>> {
+    let only = probe.interfaces.clone();
+    let interfaces = tokio::task::spawn_blocking(move || interfaces::read(&only)).await??;
+    for interface in &interfaces {
+        let counters = &interface.counters;
+        info!("{}: {} {}, rx {} bytes ({} errors, {} dropped), tx {} bytes ({} errors, {} dropped)",
+              probe.label(), interface.name, interface.operstate.as_deref().unwrap_or("unknown"),
+              counters.rx_bytes, counters.rx_errors, counters.rx_dropped,
+              counters.tx_bytes, counters.tx_errors, counters.tx_dropped);
+    }
+    Ok(interfaces)
+}
+
+async fn measure_wifi(probe: &WifiProbe) -> Result<Vec<WifiStats>> {
+    let only = probe.interfaces.clone();
+    let interfaces = tokio::task::spawn_blocking(move || wifi::read(&only)).await??;
+    for wifi in &interfaces {
+        if !wifi.is_associated() {
+            warn!("{}: {} is not associated with an access point", probe.label(), wifi.interface);
+            continue;
+        }
+        let dbm = |value: Option<i32>| value.map_or_else(|| "?".to_string(), |value| format!("{} dBm", value));
+        let mbps = |value: Option<f64>| value.map_or_else(|| "?".to_string(), |value| format!("{:.1} Mbit/s", value));
+        info!("{}: {} on {} ({}, {} MHz), signal {}, noise {}, tx {}, rx {}",
+              probe.label(), wifi.interface, wifi.ssid.as_deref().unwrap_or("?"), wifi.bssid.as_deref().unwrap_or("?"),
+              wifi.frequency_mhz.map_or_else(|| "?".to_string(), |freq| freq.to_string()),
+              dbm(wifi.signal_dbm), dbm(wifi.noise_dbm), mbps(wifi.tx_bitrate_mbps), mbps(wifi.rx_bitrate_mbps));
+    }
+    Ok(interfaces)
+}
+
+/// Runs `probe` once, giving up after `timeout`. Failures are logged and recorded in the outcome.
+pub async fn run(probe: &ProbeConfig, http_client: &reqwest::Client, timeout: Duration) -> ProbeOutcome {
+    info!("Running {} probe {} ({})", probe.kind(), probe.label(), probe.target());
+    let measurement = async {
+        match probe {
+            ProbeConfig::Ping(ping) => measure_latency(ping).await.map(ProbeData::Ping),
+            ProbeConfig::Gateway(gateway) => measure_gateway(gateway).await.map(ProbeData::Ping),
+            ProbeConfig::Tcp(tcp) => measure_tcp(tcp).await.map(ProbeData::Ping),
+            ProbeConfig::Path(path) => measure_path(path).await.map(ProbeData::Path),
+            ProbeConfig::Dns(dns) => {
+                measure_dns(dns).await.map(|result| ProbeData::Dns(dns.record_type.to_string(), result))
+            }
+            ProbeConfig::Http(http) => measure_http(http).await.map(ProbeData::Http),
+            ProbeConfig::Download(download) => measure_download(download, http_client).await.map(ProbeData::Download),
+            ProbeConfig::Upload(upload) => measure_upload(upload, http_client).await.map(ProbeData::Upload),
+            ProbeConfig::PublicIp(public_ip) => measure_public_ip(public_ip).await.map(ProbeData::PublicIp),
+            ProbeConfig::Interfaces(interfaces) => measure_interfaces(interfaces).await.map(ProbeData::Interfaces),
+            ProbeConfig::Wifi(wifi) => measure_wifi(wifi).await.map(ProbeData::Wifi),
+            ProbeConfig::Bufferbloat(bufferbloat) => {
+                measure_bufferbloat(bufferbloat, http_client).await.map(ProbeData::Bufferbloat)
+            }
+        }
+    };
+    let result = time::timeout(timeout, measurement)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out after {} seconds", timeout.as_secs())));
+    if let Err(e) = &result {
+        warn!("{} probe {} failed: {:#}", probe.kind(), probe.label(), e);
+    }
+
+    ProbeOutcome {
+        time: Utc::now(),
+        kind: probe.kind(),
+        target: probe.target().to_string(),
+        label: probe.label().to_string(),
+        ip_version: probe.ip_version(),
+        result: result.map_err(|e| format!("{:#}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use influxdb::Query;
+    use crate::icmp::EchoReply;
+    use super::*;
+
+    fn outcome(kind: &'static str, target: &str, result: Result<ProbeData, String>) -> ProbeOutcome {
+        ProbeOutcome {
+            time: "2024-05-01T12:00:00Z".parse().unwrap(),
+            kind,
+            target: target.to_string(),
+            label: target.to_string(),
+            ip_version: None,
+            result,
+        }
+    }
+
+    fn lines(outcome: &ProbeOutcome) -> Vec<String> {
+        outcome.to_queries().iter().map(|query| query.build().unwrap().get()).collect()
+    }
+
+    #[test]
+    fn ping_json_uses_influx_field_names() {
+        let ping = outcome("ping", "example.com", Ok(ProbeData::Ping(PingResult {
+            addr: "192.0.2.1".parse().unwrap(),
+            transmitted: 4,
+            replies: vec![
+                EchoReply { seq: 1, rtt: Duration::from_millis(10), ttl: Some(57) },
+                EchoReply { seq: 2, rtt: Duration::from_millis(30), ttl: Some(57) },
+            ],
+        })));
+        let json = ping.to_json();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["address"], "192.0.2.1");
+        assert_eq!(json["latency_ms"], 20.0);
+        assert_eq!(json["latency_min_ms"], 10.0);
+        assert_eq!(json["latency_max_ms"], 30.0);
+        assert_eq!(json["packets_received"], 2);
+        assert_eq!(json["packet_loss_pct"], 50.0);
+
+        let lines = lines(&ping);
+        assert_eq!(lines.len(), 1);
+        for field in ["latency_ms=20", "latency_min_ms=10", "packets_received=2i", "packet_loss_pct=50"] {
+            assert!(lines[0].contains(field), "{} not in {}", field, lines[0]);
+        }
+    }
+
+    #[test]
+    fn unreachable_version_is_total_loss() {
+        let mut unreachable = outcome("ping", "example.com", Ok(ProbeData::Ping(PingResult::unreachable(IpVersion::V6, 4))));
+        unreachable.ip_version = Some(IpVersion::V6);
+        assert!(!unreachable.is_success());
+
+        let lines = lines(&unreachable);
+        assert_eq!(lines.len(), 1);
+        for field in ["ip_version=6", "packets_sent=4i", "packets_received=0i", "packet_loss_pct=100"] {
+            assert!(lines[0].contains(field), "{} not in {}", field, lines[0]);
+        }
+        assert!(!lines[0].contains("latency_ms"), "{}", lines[0]);
+
+        let json = unreachable.to_json();
+        assert_eq!((&json["ip_version"], &json["packet_loss_pct"]), (&json!("6"), &json!(100.0)));
+        assert!(json["address"].is_null(), "{}", json);
+    }
+
+    #[test]
+    fn tells_unreachable_from_other_errors() {
+        let no_address = anyhow::Error::new(icmp::NoAddressError { host: "example.com".to_string(), version: IpVersion::V6 });
+        assert!(is_unreachable(&no_address));
+        let no_route = Err::<(), _>(io::Error::from(io::ErrorKind::NetworkUnreachable))
+            .context("Failed to send echo request to 2001:db8::1")
+            .unwrap_err();
+        assert!(is_unreachable(&no_route));
+
+        let denied = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("Failed to open ICMP socket")
+            .unwrap_err();
+        assert!(!is_unreachable(&denied));
+        assert!(!is_unreachable(&anyhow!("example.com did not resolve to any address")));
+    }
+
+    #[test]
+    fn failed_upload_has_no_throughput() {
+        let upload = outcome("upload", "http://example.com/upload", Ok(ProbeData::Upload(UploadResult {
+            bytes: 2_000_000,
+            duration: Duration::from_millis(40),
+            status: 413,
+        })));
+        let json = upload.to_json();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["upload_status"], 413);
+        assert_eq!(json["error"], "HTTP status 413");
+        for key in ["upload_bytes", "upload_duration_ms", "upload_mbps"] {
+            assert!(json.get(key).is_none(), "{} in {}", key, json);
+        }
+
+        let lines = lines(&upload);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("upload_status=413i"), "{}", lines[0]);
+        assert!(!lines[0].contains("upload_mbps"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn successful_upload_has_throughput() {
+        let upload = outcome("upload", "http://example.com/upload", Ok(ProbeData::Upload(UploadResult {
+            bytes: 1_000_000,
+            duration: Duration::from_secs(1),
+            status: 200,
+        })));
+        let json = upload.to_json();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["upload_mbps"], 8.0);
+        assert!(json.get("error").is_none(), "{}", json);
+        assert!(lines(&upload)[0].contains("upload_mbps=8"));
+    }
+
+    #[test]
+    fn errors_only_have_a_message() {
+        let failed = outcome("dns", "example.com", Err("timed out after 60 seconds".to_string()));
+        assert_eq!(failed.to_json(), json!({
+            "time": "2024-05-01T12:00:00+00:00",
+            "probe": "dns",
+            "target": "example.com",
+            "label": "example.com",
+            "success": false,
+            "error": "timed out after 60 seconds",
+        }));
+        assert!(failed.to_queries().is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use tracing::info;
+use crate::config::IpVersion;
+use crate::probe::{ProbeData, ProbeOutcome};
+
+/// Upper bounds of the round-trip time histogram buckets, in seconds.
+const RTT_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Identifies a probe in the exported series: probe type, target, label and the IP
+/// version it is pinned to.
+type ProbeKey = (&'static str, String, String, Option<IpVersion>);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(RTT_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Latest outcome of every probe that has run so far.
+    latest: BTreeMap<ProbeKey, ProbeOutcome>,
+    /// Round-trip times of all echo replies and TCP handshakes since start, per probe.
+    rtt: BTreeMap<ProbeKey, Histogram>,
+}
+
+/// Keeps the latest probe results and renders them in the Prometheus text format.
+#[derive(Default)]
+pub struct Exporter {
+    state: Mutex<State>,
+}
+
+impl Exporter {
+    pub fn update(&self, outcome: &ProbeOutcome) {
+        let mut state = self.state.lock().unwrap();
+        let key = (outcome.kind, outcome.target.clone(), outcome.label.clone(), outcome.ip_version);
+        if let Ok(ProbeData::Ping(result)) = &outcome.result {
+            let histogram = state.rtt.entry(key.clone()).or_default();
+            for reply in &result.replies {
+                histogram.observe(reply.rtt.as_secs_f64());
+            }
+        }
+        state.latest.insert(key, outcome.clone());
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let all: Vec<_> = state.latest.iter().collect();
+        write_gauge(&mut out, "internet_monitor_probe_success",
+                    "Whether the last run of the probe succeeded (1) or not (0).",
+                    all.iter().map(|(key, outcome)| (*key, if outcome.is_success() { 1.0 } else { 0.0 })));
+        write_gauge(&mut out, "internet_monitor_probe_last_run_timestamp_seconds",
+                    "Unix time of the last run of the probe.",
+                    all.iter().map(|(key, outcome)| (*key, outcome.time.timestamp_millis() as f64 / 1000.0)));
+
+        let pings: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Ping(result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        let stats: Vec<_> = pings.iter()
+            .filter_map(|(key, result)| result.stats().map(|stats| (*key, stats)))
+            .collect();
+        write_gauge(&mut out, "internet_monitor_latency_seconds",
+                    "Average round-trip time of the last ping or TCP connect run.",
+                    stats.iter().map(|(key, stats)| (*key, stats.avg_ms / 1000.0)));
+        write_gauge(&mut out, "internet_monitor_latency_min_seconds",
+                    "Minimum round-trip time of the last ping or TCP connect run.",
+                    stats.iter().map(|(key, stats)| (*key, stats.min_ms / 1000.0)));
+        write_gauge(&mut out, "internet_monitor_latency_max_seconds",
+                    "Maximum round-trip time of the last ping or TCP connect run.",
+                    stats.iter().map(|(key, stats)| (*key, stats.max_ms / 1000.0)));
+        write_gauge(&mut out, "internet_monitor_jitter_seconds",
+                    "Standard deviation of the round-trip times of the last ping or TCP connect run.",
+                    stats.iter().map(|(key, stats)| (*key, stats.mdev_ms / 1000.0)));
+        write_gauge(&mut out, "internet_monitor_packet_loss_ratio",
+                    "Share of echo requests or connection attempts without a reply in the last run.",
+                    pings.iter().map(|(key, result)| (*key, result.loss_pct() / 100.0)));
+
+        if !state.rtt.is_empty() {
+            let name = "internet_monitor_rtt_seconds";
+            let _ = writeln!(out, "# HELP {} Round-trip times of all echo replies and TCP handshakes.", name);
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (key, histogram) in &state.rtt {
+                let labels = format_labels(key);
+                for (count, bound) in histogram.buckets.iter().zip(RTT_BUCKETS) {
+                    let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+                }
+                let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+                let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+                let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+            }
+        }
+
+        let paths: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Path(result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_path_hops",
+                    "Hops on the path of the last trace, up to the destination or the last one that answered.",
+                    paths.iter().map(|(key, result)| (*key, result.hops.len() as f64)));
+        write_gauge(&mut out, "internet_monitor_path_changed",
+                    "Whether the last trace took another path than the one before (1) or not (0).",
+                    paths.iter().map(|(key, result)| (*key, if result.changed == Some(true) { 1.0 } else { 0.0 })));
+
+        let public_ips: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::PublicIp(result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_public_ip_asn",
+                    "Number of the autonomous system the last found public IP address belongs to.",
+                    public_ips.iter().filter_map(|(key, result)| Some((*key, result.asn.as_ref()?.number as f64))));
+        write_gauge(&mut out, "internet_monitor_public_ip_changed",
+                    "Whether the last found public IP address differs from the one before (1) or not (0).",
+                    public_ips.iter().map(|(key, result)| (*key, if result.changed == Some(true) { 1.0 } else { 0.0 })));
+
+        let queries: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Dns(_, result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_dns_response_seconds",
+                    "Response time of the last DNS query.",
+                    queries.iter().map(|(key, result)| (*key, result.rtt.as_secs_f64())));
+        write_gauge(&mut out, "internet_monitor_dns_rcode",
+                    "Response code of the last DNS query, 0 is NOERROR.",
+                    queries.iter().map(|(key, result)| (*key, result.rcode as f64)));
+
+        let requests: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Http(timing)) => Some((*key, timing)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_http_status",
+                    "Status code of the last HTTP request.",
+                    requests.iter().map(|(key, timing)| (*key, timing.status as f64)));
+        write_gauge(&mut out, "internet_monitor_http_ttfb_seconds",
+                    "Time from sending the last HTTP request until the response headers arrived.",
+                    requests.iter().map(|(key, timing)| (*key, timing.ttfb.as_secs_f64())));
+        write_gauge(&mut out, "internet_monitor_http_duration_seconds",
+                    "Total time of the last HTTP request, including DNS, connect and TLS.",
+                    requests.iter().map(|(key, timing)| (*key, timing.total.as_secs_f64())));
+
+        let downloads: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Download(result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_download_bits_per_second",
+                    "Throughput of the last download test.",
+                    downloads.iter().map(|(key, result)| (*key, result.mbps() * 1_000_000.0)));
+        write_gauge(&mut out, "internet_monitor_download_ttfb_seconds",
+                    "Time to first byte of the last download test.",
+                    downloads.iter().map(|(key, result)| (*key, result.ttfb.as_secs_f64())));
+
+        let uploads: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Upload(result)) if result.is_success() => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_upload_bits_per_second",
+                    "Throughput of the last successful upload test.",
+                    uploads.iter().map(|(key, result)| (*key, result.mbps() * 1_000_000.0)));
+
+        let bufferbloat: Vec<_> = all.iter()
+            .filter_map(|(key, outcome)| match &outcome.result {
+                Ok(ProbeData::Bufferbloat(result)) => Some((*key, result)),
+                _ => None,
+            })
+            .collect();
+        write_gauge(&mut out, "internet_monitor_bufferbloat_download_increase_seconds",
+                    "Rise of the average round-trip time over idle while saturating the download in the last bufferbloat test.",
+                    bufferbloat.iter().filter_map(|(key, result)| {
+                        let increase = result.download.as_ref()?.increase_ms(&result.idle)?;
+                        Some((*key, increase / 1000.0))
+                    }));
+        write_gauge(&mut out, "internet_monitor_bufferbloat_upload_increase_seconds",
+                    "Rise of the average round-trip time over idle while saturating the upload in the last bufferbloat test.",
+                    bufferbloat.iter().filter_map(|(key, result)| {
+                        let increase = result.upload.as_ref()?.increase_ms(&result.idle)?;
+                        Some((*key, increase / 1000.0))
+                    }));
+
+        out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_labels((kind, target, label, ip_version): &ProbeKey) -> String {
+    let mut labels = format!("probe=\"{}\",target=\"{}\",label=\"{}\"",
+                             kind, escape_label_value(target), escape_label_value(label));
+    if let Some(version) = ip_version {
+        let _ = write!(labels, ",ip_version=\"{}\"", version);
+    }
+    labels
+}
+
+fn write_gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl Iterator<Item = (&'a ProbeKey, f64)>,
+) {
+    let mut samples = samples.peekable();
+    if samples.peek().is_none() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (key, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, format_labels(key), value);
+    }
+}
+
+async fn handle(exporter: Arc<Exporter>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+            .body(Body::from(exporter.render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found, metrics are served at /metrics\n")),
+    };
+    Ok(response.expect("valid response"))
+}
+
+/// Listens on `addr`, failing right away when it can't. The returned future serves
+/// `/metrics` until the process exits.
+pub fn bind(addr: SocketAddr, exporter: Arc<Exporter>) -> Result<impl Future<Output = Result<()>>> {
+    let make_service = make_service_fn(move |_| {
+        let exporter = exporter.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(exporter.clone(), request))) }
+    });
+
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Failed to listen on {}", addr))?
+        .serve(make_service);
+    info!("Serving Prometheus metrics at http://{}/metrics", server.local_addr());
+    Ok(async move { server.await.context("Prometheus exporter failed") })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::icmp::{EchoReply, PingResult};
+    use super::*;
+
+    fn ping_outcome(target: &str, label: &str, result: Result<ProbeData, String>) -> ProbeOutcome {
+        ProbeOutcome {
+            time: Utc::now(),
+            kind: "ping",
+            target: target.to_string(),
+            label: label.to_string(),
+            ip_version: Some(IpVersion::V4),
+            result,
+        }
+    }
+
+    fn ping_result(rtts_ms: &[u64]) -> ProbeData {
+        ProbeData::Ping(PingResult {
+            addr: "192.0.2.1".parse().unwrap(),
+            transmitted: 4,
+            replies: rtts_ms
+                .iter()
+                .enumerate()
+                .map(|(seq, rtt)| EchoReply { seq: seq as u16, rtt: Duration::from_millis(*rtt), ttl: Some(57) })
+                .collect(),
+        })
+    }
+
+    /// The value of the sample of `name` with `labels`.
+    fn sample(out: &str, name: &str, labels: &str) -> f64 {
+        let prefix = format!("{}{{{}}} ", name, labels);
+        let line = out.lines().find(|line| line.starts_with(&prefix)).unwrap_or_else(|| panic!("no {} in\n{}", prefix, out));
+        line[prefix.len()..].parse().unwrap()
+    }
+
+    #[test]
+    fn renders_gauges_and_histograms() {
+        let exporter = Exporter::default();
+        exporter.update(&ping_outcome("example.com", "example", Ok(ping_result(&[1, 30]))));
+        exporter.update(&ping_outcome("example.com", "example", Ok(ping_result(&[20]))));
+        let out = exporter.render();
+
+        assert!(out.contains("# TYPE internet_monitor_probe_success gauge\n"), "{}", out);
+        assert!(out.contains("# TYPE internet_monitor_rtt_seconds histogram\n"), "{}", out);
+        let labels = r#"probe="ping",target="example.com",label="example",ip_version="4""#;
+        // Gauges show the latest run, one of four echo requests answered
+        assert_eq!(sample(&out, "internet_monitor_probe_success", labels), 1.0);
+        assert_eq!(sample(&out, "internet_monitor_latency_seconds", labels), 0.02);
+        assert_eq!(sample(&out, "internet_monitor_packet_loss_ratio", labels), 0.75);
+
+        // The histogram counts the replies of every run, buckets are cumulative
+        let bucket = |le: &str| sample(&out, "internet_monitor_rtt_seconds_bucket", &format!("{},le=\"{}\"", labels, le));
+        assert_eq!(bucket("0.001"), 1.0);
+        assert_eq!(bucket("0.01"), 1.0);
+        assert_eq!(bucket("0.025"), 2.0);
+        assert_eq!(bucket("0.05"), 3.0);
+        assert_eq!(bucket("+Inf"), 3.0);
+        assert_eq!(sample(&out, "internet_monitor_rtt_seconds_count", labels), 3.0);
+        assert!((sample(&out, "internet_monitor_rtt_seconds_sum", labels) - 0.051).abs() < 1e-9);
+    }
+
+    #[test]
+    fn renders_failed_probes() {
+        let exporter = Exporter::default();
+        exporter.update(&ping_outcome("example.com", "example", Err("no route to host".to_string())));
+        let out = exporter.render();
+
+        let labels = r#"probe="ping",target="example.com",label="example",ip_version="4""#;
+        assert_eq!(sample(&out, "internet_monitor_probe_success", labels), 0.0);
+        assert!(!out.contains("internet_monitor_latency_seconds"), "{}", out);
+        assert!(!out.contains("internet_monitor_rtt_seconds"), "{}", out);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let exporter = Exporter::default();
+        exporter.update(&ping_outcome(r#"C:\hosts"#, "say \"hi\"\nthere", Ok(ping_result(&[]))));
+        let labels = r#"probe="ping",target="C:\\hosts",label="say \"hi\"\nthere",ip_version="4""#;
+        assert_eq!(sample(&exporter.render(), "internet_monitor_probe_success", labels), 0.0);
+    }
+
+    #[tokio::test]
+    async fn fails_to_bind_taken_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let e = bind(addr, Arc::new(Exporter::default())).err().expect("address is taken");
+        assert!(format!("{:#}", e).contains(&format!("Failed to listen on {}", addr)), "{:#}", e);
+    }
+}
@@ -0,0 +1,315 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use influxdb::WriteQuery;
+use reqwest::Url;
+use tracing::debug;
+use crate::config::IpVersion;
+use crate::dns::{self, RecordType};
+use crate::icmp;
+use crate::probe::{ProbeData, ProbeOutcome};
+use crate::stun;
+
+/// Tried in order until one answers. Plain text echo services and STUN servers from
+/// different operators, so one of them going away doesn't break the probe.
+pub const DEFAULT_ENDPOINTS: &[&str] = &[
+    "https://api64.ipify.org",
+    "https://icanhazip.com",
+    "stun:stun.cloudflare.com:3478",
+];
+
+/// Where to learn the public address from.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    /// A URL answering with the address of the client as plain text.
+    Http(Url),
+    /// A STUN server, given as `stun:host:port`.
+    Stun(String, u16),
+}
+
+impl Endpoint {
+    pub fn parse(endpoint: &str) -> Result<Endpoint> {
+        if let Some(server) = endpoint.strip_prefix("stun:") {
+            let (host, port) = stun::split_server(server)?;
+            if host.is_empty() {
+                bail!("Invalid STUN endpoint {}, expected stun:host:port", endpoint);
+            }
+            return Ok(Endpoint::Stun(host.to_string(), port));
+        }
+        let url = Url::parse(endpoint).with_context(|| format!("Invalid public IP endpoint {}", endpoint))?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            bail!("Invalid public IP endpoint {}, expected an http(s) URL or stun:host:port", endpoint);
+        }
+        Ok(Endpoint::Http(url))
+    }
+}
+
+/// The autonomous system an address is announced from, in other words the ISP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asn {
+    pub number: u32,
+    /// Name of the AS as registered, e.g. `GOOGLE - Google LLC, US`.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PublicIpResult {
+    pub addr: IpAddr,
+    /// The endpoint that answered.
+    pub endpoint: String,
+    /// `None` when the lookup is disabled or failed.
+    pub asn: Option<Asn>,
+    /// Whether the address differs from the previous successful run, `None` without one.
+    pub changed: Option<bool>,
+    /// Address and AS number of that previous run, only kept when the address changed.
+    pub previous: Option<(IpAddr, Option<u32>)>,
+}
+
+/// Asks the endpoints in turn for our public address over `version`, returning the
+/// first answer and the endpoint it came from.
+pub async fn discover(endpoints: &[String], version: IpVersion, timeout: Duration) -> Result<(IpAddr, String)> {
+    let mut errors = Vec::new();
+    for endpoint in endpoints {
+        let addr = match Endpoint::parse(endpoint)? {
+            Endpoint::Http(url) => from_http(&url, version, timeout).await,
+            Endpoint::Stun(host, port) => from_stun(&host, port, version, timeout).await,
+        };
+        match addr {
+            Ok(addr) if version.matches(&addr) => return Ok((addr, endpoint.clone())),
+            Ok(addr) => errors.push(format!("{}: answered with {}, not an IPv{} address", endpoint, addr, version)),
+            Err(e) => {
+                debug!("Public IP endpoint {} failed: {:#}", endpoint, e);
+                errors.push(format!("{}: {:#}", endpoint, e));
+            }
+        }
+    }
+    if errors.is_empty() {
+        bail!("No public IP endpoints configured");
+    }
+    bail!("No public IP endpoint answered ({})", errors.join("; "))
+}
+
+async fn from_http(url: &Url, version: IpVersion, timeout: Duration) -> Result<IpAddr> {
+    let host = url.host_str().ok_or_else(|| anyhow!("{} has no host", url))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    // Pin the connection to the resolved address so it goes out over `version`
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = icmp::resolve(host, version).await?;
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("internet-monitor/", env!("CARGO_PKG_VERSION")))
+        .resolve(host, SocketAddr::new(addr, port))
+        .timeout(timeout)
+        .build()?;
+    let body = client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Request to {} failed", url))?
+        .text()
+        .await
+        .with_context(|| format!("Failed to read response from {}", url))?;
+    body.trim().parse().map_err(|_| anyhow!("{} answered with {:?}, not an IP address", url, body.trim()))
+}
+
+async fn from_stun(host: &str, port: u16, version: IpVersion, timeout: Duration) -> Result<IpAddr> {
+    let server = SocketAddr::new(icmp::resolve(host, version).await?, port);
+    Ok(stun::mapped_address(server, timeout).await?.ip())
+}
+
+/// Looks up the AS announcing `addr` in the DNS zones of Team Cymru's IP to ASN mapping.
+pub async fn lookup_asn(addr: IpAddr, timeout: Duration) -> Result<Asn> {
+    let server = dns::system_resolver()?;
+    let origin = dns::query(server, &origin_name(addr), RecordType::TXT, timeout).await?;
+    let number = origin_asn(&origin.answers).ok_or_else(|| anyhow!("No AS announces {}", addr))?;
+    let name = match dns::query(server, &format!("AS{}.asn.cymru.com", number), RecordType::TXT, timeout).await {
+        Ok(result) => as_name(&result.answers),
+        Err(e) => {
+            debug!("Failed to look up the name of AS{}: {:#}", number, e);
+            None
+        }
+    };
+    Ok(Asn { number, name })
+}
+
+/// The name to query for the origin AS of `addr`, its reversed octets or nibbles.
+fn origin_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}.origin.asn.cymru.com", d, c, b, a)
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|byte| [byte & 0x0f, byte >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            format!("{}.origin6.asn.cymru.com", nibbles.join("."))
+        }
+    }
+}
+
+/// The AS number of origin answers like `15169 | 8.8.8.0/24 | US | arin | 2000-03-30`,
+/// the first one when the prefix is announced by several, as in `15169 36040 | ...`.
+fn origin_asn(answers: &[String]) -> Option<u32> {
+    answers
+        .iter()
+        .filter_map(|answer| answer.split('|').next()?.split_whitespace().next()?.parse().ok())
+        .next()
+}
+
+/// The AS name of answers like `15169 | US | arin | 2000-03-30 | GOOGLE - Google LLC, US`.
+fn as_name(answers: &[String]) -> Option<String> {
+    answers
+        .first()
+        .and_then(|answer| answer.rsplit('|').next())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Keeps the latest public addresses and adds them as tags to the points written
+/// after them, so results can be grouped by the address and ISP they were measured from.
+#[derive(Debug, Default)]
+pub struct PublicIpTags {
+    v4: Option<(IpAddr, Option<u32>)>,
+    v6: Option<(IpAddr, Option<u32>)>,
+}
+
+impl PublicIpTags {
+    /// Takes note of the address found by a public IP probe. Failed runs keep the
+    /// previous address, during an outage it is the best guess there is.
+    pub fn observe(&mut self, outcome: &ProbeOutcome) {
+        let Ok(ProbeData::PublicIp(result)) = &outcome.result else { return };
+        let current = Some((result.addr, result.asn.as_ref().map(|asn| asn.number)));
+        match result.addr {
+            IpAddr::V4(_) => self.v4 = current,
+            IpAddr::V6(_) => self.v6 = current,
+        }
+    }
+
+    /// Adds `public_ipv4`, `public_ipv6` and `asn` tags for what is known so far.
+    pub fn tag(&self, mut query: WriteQuery) -> WriteQuery {
+        if let Some((addr, _)) = self.v4 {
+            query = query.add_tag("public_ipv4", addr.to_string());
+        }
+        if let Some((addr, _)) = self.v6 {
+            query = query.add_tag("public_ipv6", addr.to_string());
+        }
+        let asn = self.v4.and_then(|(_, asn)| asn).or_else(|| self.v6.and_then(|(_, asn)| asn));
+        if let Some(asn) = asn {
+            query = query.add_tag("asn", format!("AS{}", asn));
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb::{InfluxDbWriteable, Query, Timestamp};
+    use super::*;
+
+    #[test]
+    fn parses_endpoints() {
+        let Endpoint::Stun(host, port) = Endpoint::parse("stun:stun.cloudflare.com:3478").unwrap() else { panic!() };
+        assert_eq!((host.as_str(), port), ("stun.cloudflare.com", 3478));
+        let Endpoint::Stun(host, port) = Endpoint::parse("stun:[2001:db8::1]").unwrap() else { panic!() };
+        assert_eq!((host.as_str(), port), ("2001:db8::1", stun::STUN_PORT));
+        let Endpoint::Http(url) = Endpoint::parse("https://api64.ipify.org").unwrap() else { panic!() };
+        assert_eq!(url.as_str(), "https://api64.ipify.org/");
+
+        for endpoint in ["stun:", "stun::3478", "stun:[]:3478", "ftp://example.com/", "api64.ipify.org", "https://"] {
+            assert!(Endpoint::parse(endpoint).is_err(), "{} was accepted", endpoint);
+        }
+    }
+
+    #[test]
+    fn builds_origin_names() {
+        assert_eq!(origin_name("192.0.2.1".parse().unwrap()), "1.2.0.192.origin.asn.cymru.com");
+        assert_eq!(
+            origin_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.origin6.asn.cymru.com",
+        );
+    }
+
+    #[test]
+    fn parses_cymru_answers() {
+        let answers = |answers: &[&str]| answers.iter().map(|answer| answer.to_string()).collect::<Vec<_>>();
+        assert_eq!(origin_asn(&answers(&["15169 | 8.8.8.0/24 | US | arin | 2000-03-30"])), Some(15169));
+        // Prefixes announced by several ASes list them all
+        assert_eq!(origin_asn(&answers(&["64500 64501 | 192.0.2.0/24 | CZ | ripencc | 2001-01-01"])), Some(64500));
+        assert_eq!(origin_asn(&answers(&["unexpected", "64502 | 192.0.2.0/24 | CZ | ripencc |"])), Some(64502));
+        assert_eq!(origin_asn(&answers(&[])), None);
+        assert_eq!(origin_asn(&answers(&[" | 192.0.2.0/24 | CZ"])), None);
+
+        assert_eq!(
+            as_name(&answers(&["15169 | US | arin | 2000-03-30 | GOOGLE - Google LLC, US"])),
+            Some("GOOGLE - Google LLC, US".to_string()),
+        );
+        assert_eq!(as_name(&answers(&["64500 | CZ | ripencc | 2001-01-01 | "])), None);
+        assert_eq!(as_name(&answers(&[])), None);
+    }
+
+    fn public_ip(addr: &str, asn: Option<u32>) -> ProbeOutcome {
+        ProbeOutcome {
+            time: "2024-05-01T12:00:00Z".parse().unwrap(),
+            kind: "public_ip",
+            target: "public_ip".to_string(),
+            label: "public_ip".to_string(),
+            ip_version: None,
+            result: Ok(ProbeData::PublicIp(PublicIpResult {
+                addr: addr.parse().unwrap(),
+                endpoint: "https://api64.ipify.org".to_string(),
+                asn: asn.map(|number| Asn { number, name: None }),
+                changed: None,
+                previous: None,
+            })),
+        }
+    }
+
+    fn tagged(tags: &PublicIpTags) -> String {
+        let query = Timestamp::Seconds(0).into_query("internet_metrics").add_field("latency_ms", 1.0);
+        tags.tag(query).build().unwrap().get()
+    }
+
+    #[test]
+    fn tags_follow_address_changes() {
+        let mut tags = PublicIpTags::default();
+        assert_eq!(tagged(&tags), "internet_metrics latency_ms=1 0");
+
+        tags.observe(&public_ip("192.0.2.1", Some(64500)));
+        assert_eq!(tagged(&tags), "internet_metrics,public_ipv4=192.0.2.1,asn=AS64500 latency_ms=1 0");
+
+        tags.observe(&public_ip("198.51.100.7", Some(64501)));
+        assert_eq!(tagged(&tags), "internet_metrics,public_ipv4=198.51.100.7,asn=AS64501 latency_ms=1 0");
+
+        // A failed run keeps the last known address
+        let mut failed = public_ip("192.0.2.1", None);
+        failed.result = Err("No public IP endpoint answered".to_string());
+        tags.observe(&failed);
+        assert_eq!(tagged(&tags), "internet_metrics,public_ipv4=198.51.100.7,asn=AS64501 latency_ms=1 0");
+    }
+
+    #[test]
+    fn tags_both_versions() {
+        let mut tags = PublicIpTags::default();
+        tags.observe(&public_ip("2001:db8::1", Some(64502)));
+        assert_eq!(tagged(&tags), "internet_metrics,public_ipv6=2001:db8::1,asn=AS64502 latency_ms=1 0");
+
+        // The IPv4 AS wins when both are known
+        tags.observe(&public_ip("192.0.2.1", Some(64500)));
+        assert_eq!(
+            tagged(&tags),
+            "internet_metrics,public_ipv4=192.0.2.1,public_ipv6=2001:db8::1,asn=AS64500 latency_ms=1 0",
+        );
+
+        // Without an IPv4 AS, the IPv6 one is used
+        tags.observe(&public_ip("192.0.2.1", None));
+        assert_eq!(
+            tagged(&tags),
+            "internet_metrics,public_ipv4=192.0.2.1,public_ipv6=2001:db8::1,asn=AS64502 latency_ms=1 0",
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::Rng;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::info;
+use crate::config::{ProbeConfig, ScheduledProbe};
+use crate::probe::{self, ProbeOutcome};
+
+/// Starts one task per probe, each running it on its own schedule and sending
+/// the outcomes to `outcomes`. The tasks stop once the receiver is dropped.
+pub fn spawn(
+    probes: &[ScheduledProbe],
+    http_client: &reqwest::Client,
+    outcomes: mpsc::UnboundedSender<ProbeOutcome>,
+) -> Vec<JoinHandle<()>> {
+    let http_client = http_client.clone();
+    spawn_with(probes, outcomes, move |probe, timeout| {
+        let http_client = http_client.clone();
+        async move { probe::run(&probe, &http_client, timeout).await }
+    })
+}
+
+/// Like [`spawn`], with `run` running a probe once within the run timeout.
+fn spawn_with<R, F>(
+    probes: &[ScheduledProbe],
+    outcomes: mpsc::UnboundedSender<ProbeOutcome>,
+    run: R,
+) -> Vec<JoinHandle<()>>
+where
+    R: Fn(ProbeConfig, Duration) -> F + Clone + Send + 'static,
+    F: Future<Output = ProbeOutcome> + Send + 'static,
+{
+    // Throughput tests take turns so they don't compete for bandwidth
+    let bandwidth = Arc::new(Mutex::new(()));
+
+    probes
+        .iter()
+        .cloned()
+        .map(|scheduled| {
+            info!("Running {} probe {} every {} seconds (jitter {} s, timeout {} s)",
+                  scheduled.probe.kind(), scheduled.probe.label(), scheduled.schedule.interval.as_secs(),
+                  scheduled.schedule.jitter.as_secs(), scheduled.schedule.run_timeout.as_secs());
+            tokio::spawn(run_probe(scheduled, bandwidth.clone(), outcomes.clone(), run.clone()))
+        })
+        .collect()
+}
+
+async fn run_probe<R, F>(
+    scheduled: ScheduledProbe,
+    bandwidth: Arc<Mutex<()>>,
+    outcomes: mpsc::UnboundedSender<ProbeOutcome>,
+    run: R,
+) where
+    R: Fn(ProbeConfig, Duration) -> F,
+    F: Future<Output = ProbeOutcome>,
+{
+    let ScheduledProbe { probe, schedule } = scheduled;
+    let mut ticker = time::interval(schedule.interval);
+    // A run that takes longer than the interval delays the next one instead of overlapping it
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_success: Option<ProbeOutcome> = None;
+
+    loop {
+        ticker.tick().await;
+        if !schedule.jitter.is_zero() {
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..=schedule.jitter);
+            time::sleep(delay).await;
+        }
+
+        let turn = if probe.uses_bandwidth() { Some(bandwidth.lock().await) } else { None };
+        let mut outcome = run(probe.clone(), schedule.run_timeout).await;
+        drop(turn);
+
+        if let Some(previous) = &last_success {
+            outcome.compare_with(previous);
+        }
+        if outcome.is_success() {
+            last_success = Some(outcome.clone());
+        }
+
+        if outcomes.send(outcome).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::net::IpAddr;
+    use chrono::Utc;
+    use tokio::net::UdpSocket;
+    use tokio::time::Instant;
+    use crate::config::{DnsProbe, DownloadProbe, PingProbe, PublicIpProbe, Schedule, UploadProbe};
+    use crate::probe::ProbeData;
+    use crate::public_ip::PublicIpResult;
+    use super::*;
+
+    fn scheduled(probe: ProbeConfig, interval: u64, jitter: u64) -> ScheduledProbe {
+        ScheduledProbe {
+            probe,
+            schedule: Schedule {
+                interval: Duration::from_secs(interval),
+                jitter: Duration::from_secs(jitter),
+                run_timeout: Duration::from_secs(60),
+            },
+        }
+    }
+
+    fn outcome(probe: &ProbeConfig, result: Result<ProbeData, String>) -> ProbeOutcome {
+        ProbeOutcome {
+            time: Utc::now(),
+            kind: probe.kind(),
+            target: probe.target().to_string(),
+            label: probe.label().to_string(),
+            ip_version: probe.ip_version(),
+            result,
+        }
+    }
+
+    fn ping() -> ProbeConfig {
+        ProbeConfig::Ping("example.com".parse::<PingProbe>().unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_every_interval_within_the_jitter() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        let tasks = spawn_with(&[scheduled(ping(), 10, 3)], sender, |probe, _| async move {
+            outcome(&probe, Err("down".to_string()))
+        });
+
+        let mut delays = Vec::new();
+        for run in 0..50 {
+            receiver.recv().await.unwrap();
+            let delay = started.elapsed() - Duration::from_secs(10 * run);
+            assert!(delay <= Duration::from_secs(3), "run {} started {:?} late", run, delay);
+            delays.push(delay);
+        }
+        delays.sort();
+        delays.dedup();
+        assert!(delays.len() > 1, "no jitter was added");
+        tasks.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborts_runs_after_the_run_timeout() {
+        // A resolver that never answers, the DNS probe would wait a minute for it
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut dns: DnsProbe = format!("example.com@{}", resolver.local_addr().unwrap()).parse().unwrap();
+        dns.timeout = 60;
+        let mut probe = scheduled(ProbeConfig::Dns(dns), 10, 0);
+        probe.schedule.run_timeout = Duration::from_secs(1);
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        let tasks = spawn(&[probe], &reqwest::Client::new(), sender);
+        let outcome = receiver.recv().await.unwrap();
+        assert_eq!(outcome.result.unwrap_err(), "timed out after 1 seconds");
+        assert_eq!(started.elapsed().as_secs(), 1);
+
+        // The next run still starts on schedule
+        receiver.recv().await.unwrap();
+        assert_eq!(started.elapsed().as_secs(), 11);
+        tasks.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_tests_take_turns() {
+        let download = ProbeConfig::Download(DownloadProbe {
+            url: "http://example.com/file".to_string(),
+            label: None,
+            max_bytes: 1_000_000,
+            max_duration: 10,
+        });
+        let upload = ProbeConfig::Upload(UploadProbe {
+            url: "http://example.com/upload".to_string(),
+            label: None,
+            bytes: 1_000_000,
+            max_duration: 10,
+        });
+        let probes = [scheduled(download, 60, 0), scheduled(upload, 60, 0), scheduled(ping(), 60, 0)];
+
+        // Every run takes 5 seconds and records when it started and ended
+        let runs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        let recorded = runs.clone();
+        let tasks = spawn_with(&probes, sender, move |probe, _| {
+            let runs = recorded.clone();
+            async move {
+                let start = started.elapsed().as_secs();
+                time::sleep(Duration::from_secs(5)).await;
+                runs.lock().unwrap().push((probe.kind(), start, started.elapsed().as_secs()));
+                outcome(&probe, Err("down".to_string()))
+            }
+        });
+        for _ in 0..3 {
+            receiver.recv().await.unwrap();
+        }
+        tasks.iter().for_each(JoinHandle::abort);
+
+        let mut runs = runs.lock().unwrap().clone();
+        runs.sort_by_key(|(kind, start, _)| (*start, *kind));
+        let kinds: Vec<_> = runs.iter().map(|(kind, _, _)| *kind).collect();
+        let spans: Vec<_> = runs.iter().map(|(_, start, end)| (*start, *end)).collect();
+        // The ping runs alongside, one throughput test waits for the other
+        assert_eq!(spans, [(0, 5), (0, 5), (5, 10)]);
+        assert!(kinds[2] == "download" || kinds[2] == "upload");
+        assert!(kinds[..2].contains(&"ping"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compares_with_the_last_success() {
+        let probe = ProbeConfig::PublicIp(PublicIpProbe {
+            label: None,
+            endpoints: vec!["https://api64.ipify.org".to_string()],
+            asn_lookup: false,
+            timeout: 5,
+            ip_version: None,
+        });
+        let found = |addr: &str| Ok(ProbeData::PublicIp(PublicIpResult {
+            addr: addr.parse().unwrap(),
+            endpoint: "https://api64.ipify.org".to_string(),
+            asn: None,
+            changed: None,
+            previous: None,
+        }));
+        let results = Arc::new(std::sync::Mutex::new(VecDeque::from([
+            found("192.0.2.1"),
+            Err("timed out".to_string()),
+            found("192.0.2.2"),
+            found("192.0.2.2"),
+        ])));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let tasks = spawn_with(&[scheduled(probe, 60, 0)], sender, move |probe, _| {
+            let result = results.lock().unwrap().pop_front().unwrap();
+            async move { outcome(&probe, result) }
+        });
+        let mut changes = Vec::new();
+        for _ in 0..4 {
+            match receiver.recv().await.unwrap().result {
+                Ok(ProbeData::PublicIp(result)) => changes.push(Some((result.changed, result.previous))),
+                _ => changes.push(None),
+            }
+        }
+        tasks.iter().for_each(JoinHandle::abort);
+
+        // The failed run in between is skipped, the third one compares with the first
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(changes, [
+            Some((None, None)),
+            None,
+            Some((Some(true), Some((first, None)))),
+            Some((Some(false), None)),
+        ]);
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use influxdb::WriteQuery;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+use crate::influx::{InfluxDbWriter, WriteError};
+use crate::spool::Spool;
+
+/// Sends that may be waiting for the writer task before new points are held back in
+/// the overflow, or are dropped without a spool.
+const CHANNEL_CAPACITY: usize = 1024;
+/// Sends held back while the channel is full, newer points are dropped beyond that.
+const OVERFLOW_CAPACITY: usize = 64 * CHANNEL_CAPACITY;
+
+/// Points the writer task had no room for, oldest first. They are taken up once the
+/// channel has run empty, so they end up behind the points sent before them.
+type Overflow = Arc<Mutex<VecDeque<Vec<WriteQuery>>>>;
+
+/// Buffers points from all probes and writes them to InfluxDB in batches, on a
+/// task of its own so a slow or unreachable database never holds up measurements.
+pub struct Sink {
+    sender: mpsc::Sender<Vec<WriteQuery>>,
+    task: JoinHandle<()>,
+    /// Only with a spool, without one there is nowhere to put a backlog.
+    overflow: Option<Overflow>,
+}
+
+impl Sink {
+    /// Starts the writer task. Buffered points are flushed once `batch_size` of
+    /// them are waiting, or `flush_interval` after the previous flush.
+    pub fn spawn(writer: InfluxDbWriter, spool: Option<Spool>, batch_size: usize, flush_interval: Duration) -> Sink {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let overflow = spool.as_ref().map(|_| Overflow::default());
+        let batcher = Batcher {
+            writer,
+            spool,
+            overflow: overflow.clone(),
+            buffer: Vec::new(),
+            batch_size,
+        };
+        let task = tokio::spawn(batcher.run(receiver, flush_interval));
+        Sink { sender, task, overflow }
+    }
+
+    /// Queues points for the next flush without waiting for InfluxDB.
+    pub fn send(&self, queries: Vec<WriteQuery>) {
+        if queries.is_empty() {
+            return;
+        }
+        let count = queries.len();
+        let Some(overflow) = &self.overflow else {
+            match self.sender.try_send(queries) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => warn!("InfluxDB writes are falling behind, dropped {} points", count),
+                Err(TrySendError::Closed(_)) => error!("InfluxDB writer has stopped, dropped {} points", count),
+            }
+            return;
+        };
+
+        let mut overflow = overflow.lock().unwrap();
+        // Points wait behind those held back before them, to keep the order
+        if !overflow.is_empty() {
+            if overflow.len() < OVERFLOW_CAPACITY {
+                overflow.push_back(queries);
+            } else {
+                warn!("InfluxDB writes are falling behind, dropped {} points", count);
+            }
+            return;
+        }
+        match self.sender.try_send(queries) {
+            Ok(()) => {}
+            Err(TrySendError::Full(queries)) => {
+                warn!("InfluxDB writes are falling behind, holding back new points");
+                overflow.push_back(queries);
+            }
+            Err(TrySendError::Closed(_)) => error!("InfluxDB writer has stopped, dropped {} points", count),
+        }
+    }
+
+    /// Flushes everything still buffered and waits for the writer task to finish.
+    pub async fn close(self) {
+        drop(self.sender);
+        if let Err(e) = self.task.await {
+            error!("InfluxDB writer failed: {}", e);
+        }
+    }
+}
+
+struct Batcher {
+    writer: InfluxDbWriter,
+    spool: Option<Spool>,
+    overflow: Option<Overflow>,
+    /// Points rendered as line protocol, oldest first.
+    buffer: Vec<String>,
+    batch_size: usize,
+}
+
+impl Batcher {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Vec<WriteQuery>>, flush_interval: Duration) {
+        let mut ticker = time::interval_at(Instant::now() + flush_interval, flush_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                queries = receiver.recv() => {
+                    let Some(queries) = queries else { break };
+                    self.render(&queries);
+                    // Points held back are newer than any in the channel
+                    if receiver.is_empty() {
+                        self.take_overflow();
+                    }
+                    if self.buffer.len() >= self.batch_size {
+                        self.flush().await;
+                        ticker.reset();
+                    }
+                }
+                _ = ticker.tick() => self.flush().await,
+            }
+        }
+
+        // All senders are gone, write what is left before exiting
+        self.take_overflow();
+        self.flush().await;
+    }
+
+    fn render(&mut self, queries: &[WriteQuery]) {
+        match self.writer.to_lines(queries) {
+            Ok(lines) => self.buffer.extend(lines),
+            Err(e) => error!("Failed to build InfluxDB points: {}", e),
+        }
+    }
+
+    /// Moves points held back by [`Sink::send`] into the buffer, where they go to the
+    /// spool in order if InfluxDB is still behind.
+    fn take_overflow(&mut self) {
+        let Some(overflow) = &self.overflow else { return };
+        let held_back = std::mem::take(&mut *overflow.lock().unwrap());
+        if !held_back.is_empty() {
+            info!("Taking up {} sends held back while InfluxDB writes were falling behind", held_back.len());
+        }
+        for queries in held_back {
+            self.render(&queries);
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            // Nothing new, but spooled points may be due for another attempt
+            if let Some(spool) = self.spool.as_mut().filter(|spool| !spool.is_empty() && spool.ready()) {
+                if let Err(e) = spool.replay(&self.writer).await {
+                    warn!("Failed to replay spooled points: {:#}", e);
+                }
+            }
+            return;
+        }
+
+        let lines = std::mem::take(&mut self.buffer);
+        debug!("Flushing {} points to InfluxDB", lines.len());
+        for batch in lines.chunks(self.batch_size) {
+            self.write_batch(batch).await;
+        }
+    }
+
+    /// Writes a batch to InfluxDB. With a spool, points that can't be written are
+    /// kept on disk and replayed in order once InfluxDB accepts writes again.
+    async fn write_batch(&mut self, lines: &[String]) {
+        let Some(spool) = &mut self.spool else {
+            match self.writer.write_lines(lines).await {
+                Ok(_) => info!("Wrote {} points to InfluxDB", lines.len()),
+                Err(e) => {
+                    error!("Failed to write {} points to InfluxDB: {}", lines.len(), e);
+                    // Don't exit on InfluxDB errors
+                }
+            }
+            return;
+        };
+
+        if spool.is_empty() {
+            match self.writer.write_lines(lines).await {
+                Ok(_) => {
+                    info!("Wrote {} points to InfluxDB", lines.len());
+                    return;
+                }
+                // Spooling them would only hold up the points behind them
+                Err(WriteError::Rejected(message)) => {
+                    error!("InfluxDB rejected {} points: {}", lines.len(), message);
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to write metrics to InfluxDB, spooling {} points: {}", lines.len(), e);
+                    spool.back_off();
+                }
+            }
+        }
+
+        // Older points are still waiting in the spool, queue up behind them to keep the order
+        if let Err(e) = spool.append(lines.to_vec()).await {
+            error!("Failed to spool {} points: {:#}", lines.len(), e);
+        }
+        if spool.ready() {
+            if let Err(e) = spool.replay(&self.writer).await {
+                warn!("Failed to replay spooled points: {:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use influxdb::{InfluxDbWriteable, Timestamp};
+    use reqwest::StatusCode;
+    use crate::influx::tests::FakeInfluxDb;
+    use super::*;
+
+    /// A point per number in `numbers`, each sent on its own like a probe outcome.
+    fn points(numbers: std::ops::Range<i64>) -> Vec<Vec<WriteQuery>> {
+        numbers
+            .map(|n| vec![Timestamp::Nanoseconds(n as u128).into_query("internet_metrics").add_field("n", n)])
+            .collect()
+    }
+
+    fn lines(writer: &InfluxDbWriter, points: &[Vec<WriteQuery>]) -> Vec<String> {
+        writer.to_lines(&points.concat()).unwrap()
+    }
+
+    /// Waits up to five seconds for `condition` to hold.
+    async fn eventually(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not met within five seconds");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// An empty spool for the test `name`, replayed no sooner than `backoff` after a failure.
+    fn spool(name: &str, backoff: Duration) -> (Spool, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("sink-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("spool.lp");
+        (Spool::open(path.clone(), 10_000_000, backoff).unwrap(), path)
+    }
+
+    fn spooled(path: &PathBuf) -> Vec<String> {
+        fs::read_to_string(path).unwrap_or_default().lines().map(str::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn flushes_full_batches() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let expected = lines(&writer, &points(0..5));
+        let sink = Sink::spawn(writer, None, 3, Duration::from_secs(3600));
+        for queries in points(0..5) {
+            sink.send(queries);
+        }
+
+        eventually(|| fake.writes().len() == 1).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(fake.writes().len(), 1, "the last two points wait for the interval");
+
+        // Closing writes what is left
+        sink.close().await;
+        let batches: Vec<_> = fake.writes().iter().map(|(_, lines)| lines.len()).collect();
+        assert_eq!(batches, [3, 2]);
+        assert_eq!(fake.accepted(), expected);
+    }
+
+    #[tokio::test]
+    async fn flushes_on_interval() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let expected = lines(&writer, &points(0..2));
+        let sink = Sink::spawn(writer, None, 1000, Duration::from_millis(200));
+        for queries in points(0..2) {
+            sink.send(queries);
+        }
+
+        eventually(|| !fake.writes().is_empty()).await;
+        assert_eq!(fake.accepted(), expected);
+        sink.close().await;
+        assert_eq!(fake.writes().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_rejected_points_without_spooling_them() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let (spool, path) = spool("rejected", Duration::from_millis(50));
+        fake.respond_with(&[StatusCode::BAD_REQUEST]);
+        let sink = Sink::spawn(writer, Some(spool), 1, Duration::from_secs(3600));
+        sink.send(points(0..1).remove(0));
+        sink.close().await;
+
+        assert_eq!(fake.writes().len(), 1);
+        assert!(fake.accepted().is_empty());
+        assert!(spooled(&path).is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spools_points_while_unavailable() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let expected = lines(&writer, &points(0..3));
+        let (spool, path) = spool("unavailable", Duration::from_millis(300));
+        fake.respond_with(&[StatusCode::SERVICE_UNAVAILABLE]);
+        let sink = Sink::spawn(writer, Some(spool), 1, Duration::from_millis(100));
+
+        sink.send(points(0..1).remove(0));
+        eventually(|| spooled(&path) == expected[..1]).await;
+        // Newer points queue up behind the spooled ones until the backoff is over
+        for queries in points(1..3) {
+            sink.send(queries);
+        }
+        eventually(|| fake.accepted() == expected).await;
+        sink.close().await;
+        assert!(spooled(&path).is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn holds_back_points_the_writer_has_no_room_for() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let total = 1 + CHANNEL_CAPACITY as i64 + 10;
+        let expected = lines(&writer, &points(0..total));
+        let (spool, path) = spool("overflow", Duration::from_millis(50));
+        let sink = Sink::spawn(writer, Some(spool), 1, Duration::from_secs(3600));
+
+        // The writer task is stuck on the first point while the channel fills up
+        fake.stall_next(Duration::from_millis(500));
+        let mut points = points(0..total).into_iter();
+        sink.send(points.next().unwrap());
+        eventually(|| fake.arrived() == 1).await;
+        for queries in points {
+            sink.send(queries);
+        }
+        assert_eq!(sink.overflow.as_ref().unwrap().lock().unwrap().len(), 10);
+
+        // Closing right away still writes the held back points, after the older ones
+        sink.close().await;
+        assert_eq!(fake.accepted(), expected);
+        assert!(spooled(&path).is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spools_held_back_points_in_order() {
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let total = 1 + CHANNEL_CAPACITY as i64 + 10;
+        let expected = lines(&writer, &points(0..total));
+        // Not replayed again before the end of the test
+        let (spool, path) = spool("overflow-spooled", Duration::from_secs(3600));
+        let sink = Sink::spawn(writer, Some(spool), 1, Duration::from_secs(3600));
+
+        // The first write fails only after the channel has filled up
+        fake.stall_next(Duration::from_millis(500));
+        fake.respond_with(&[StatusCode::SERVICE_UNAVAILABLE]);
+        let mut points = points(0..total).into_iter();
+        sink.send(points.next().unwrap());
+        eventually(|| fake.arrived() == 1).await;
+        for queries in points {
+            sink.send(queries);
+        }
+
+        sink.close().await;
+        assert!(fake.accepted().is_empty());
+        assert_eq!(spooled(&path), expected);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use tokio::task;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+use crate::influx::{InfluxDbWriter, WriteError};
+
+/// Points written to InfluxDB per request while replaying the spool.
+const REPLAY_BATCH: usize = 5000;
+/// Upper bound for the delay between two replay attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Share of `max_bytes` left after an eviction, so the next few appends don't evict again.
+const EVICT_TO_PCT: u64 = 90;
+
+/// On-disk queue of points that could not be written to InfluxDB.
+///
+/// Points are stored as line protocol, one per line, oldest first. Once the
+/// file grows past `max_bytes` the oldest points are dropped. Points InfluxDB
+/// rejects during a replay are moved to `<path>.rejected` for inspection, so
+/// they don't hold up the points behind them. That file is capped at `max_bytes`
+/// the same way.
+pub struct Spool {
+    file: Arc<Mutex<SpoolFile>>,
+    /// Delay before the next replay attempt, grows while InfluxDB keeps failing.
+    backoff: Duration,
+    min_backoff: Duration,
+    next_attempt: Instant,
+}
+
+/// The spool file itself. It is only read and written on blocking tasks.
+struct SpoolFile {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Size of the file, kept up to date as points are added and removed.
+    size: u64,
+    /// Points dropped to stay under `max_bytes` since the spool was opened.
+    evicted: usize,
+}
+
+impl Spool {
+    pub fn open(path: PathBuf, max_bytes: u64, min_backoff: Duration) -> Result<Spool> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create spool directory {}", parent.display()))?;
+        }
+        let file = SpoolFile {
+            size: fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0),
+            path,
+            max_bytes,
+            evicted: 0,
+        };
+        if file.size > 0 {
+            info!("Spool {} holds {} points from a previous run", file.path.display(), file.read_lines()?.len());
+        }
+        Ok(Spool {
+            file: Arc::new(Mutex::new(file)),
+            backoff: min_backoff,
+            min_backoff,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.lock().unwrap().size == 0
+    }
+
+    /// Appends points to the end of the spool, evicting the oldest ones over the size cap.
+    pub async fn append(&self, lines: Vec<String>) -> Result<()> {
+        blocking(&self.file, move |file| file.append(&lines)).await
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SpoolFile) -> Result<T> + Send + 'static,
+    {
+        blocking(&self.file, f).await
+    }
+
+    /// Whether the backoff after the last failure has passed.
+    pub fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Pushes the next replay attempt further out after a failed write.
+    pub fn back_off(&mut self) {
+        self.next_attempt = Instant::now() + self.backoff;
+        info!("Retrying spooled points in {} seconds", self.backoff.as_secs());
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// Writes spooled points to InfluxDB in order, oldest first. Points are only
+    /// removed from the spool once InfluxDB accepted or rejected them.
+    pub async fn replay(&mut self, writer: &InfluxDbWriter) -> Result<()> {
+        let (lines, evicted) = self.blocking(|file| Ok((file.read_lines()?, file.evicted))).await?;
+        let total = lines.len();
+        let mut done = 0;
+        let mut rejected = 0;
+        for batch in lines.chunks(REPLAY_BATCH) {
+            match writer.write_lines(batch).await {
+                Ok(()) => {}
+                Err(WriteError::Rejected(message)) => {
+                    let batch = batch.to_vec();
+                    let count = batch.len();
+                    let path = self.blocking(move |file| file.quarantine(&batch)).await?;
+                    error!("InfluxDB rejected {} spooled points, moved them to {}: {}",
+                           count, path.display(), message);
+                    rejected += count;
+                }
+                Err(WriteError::Unavailable(e)) => {
+                    self.remove_replayed(done, evicted).await?;
+                    self.back_off();
+                    return Err(anyhow::Error::new(WriteError::Unavailable(e))
+                        .context(format!("Replayed {} of {} spooled points", done, total)));
+                }
+            }
+            done += batch.len();
+        }
+
+        self.remove_replayed(done, evicted).await?;
+        self.backoff = self.min_backoff;
+        self.next_attempt = Instant::now();
+        info!("Replayed {} spooled points to InfluxDB", total - rejected);
+        Ok(())
+    }
+
+    /// Removes the first `count` points read by a replay, keeping points appended since.
+    /// `evicted` is the eviction count from when the replay read the spool.
+    async fn remove_replayed(&self, count: usize, evicted: usize) -> Result<()> {
+        self.blocking(move |file| {
+            // Evictions during the replay may have removed some of them already
+            let count = count.saturating_sub(file.evicted - evicted);
+            file.drop_oldest(count)
+        }).await
+    }
+}
+
+/// Runs `f` on the spool file without holding up the async runtime.
+async fn blocking<T, F>(file: &Arc<Mutex<SpoolFile>>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut SpoolFile) -> Result<T> + Send + 'static,
+{
+    let file = file.clone();
+    task::spawn_blocking(move || f(&mut file.lock().unwrap())).await?
+}
+
+impl SpoolFile {
+    fn append(&mut self, lines: &[String]) -> Result<()> {
+        append_lines(&self.path, lines)
+            .with_context(|| format!("Failed to append to spool {}", self.path.display()))?;
+        self.size += lines.iter().map(|line| line.len() as u64 + 1).sum::<u64>();
+        if self.size > self.max_bytes {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest points until the spool is well below `max_bytes` again.
+    fn evict(&mut self) -> Result<()> {
+        let lines = self.read_lines()?;
+        let dropped = over_cap(&lines, self.max_bytes);
+        warn!("Spool {} is over {} bytes, dropped the {} oldest points",
+              self.path.display(), self.max_bytes, dropped);
+        self.evicted += dropped;
+        self.rewrite(&lines[dropped..])
+    }
+
+    /// Appends rejected points to the quarantine file next to the spool, dropping its
+    /// oldest points once it grows past `max_bytes`. Returns the path of the file.
+    fn quarantine(&self, lines: &[String]) -> Result<PathBuf> {
+        let path = rejected_path(&self.path);
+        append_lines(&path, lines)?;
+        if fs::metadata(&path)?.len() > self.max_bytes {
+            let lines = read_lines(&path)?;
+            let dropped = over_cap(&lines, self.max_bytes);
+            warn!("{} is over {} bytes, dropped the {} oldest points", path.display(), self.max_bytes, dropped);
+            rewrite_lines(&path, &lines[dropped..])?;
+        }
+        Ok(path)
+    }
+
+    fn drop_oldest(&mut self, count: usize) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let lines = self.read_lines()?;
+        self.rewrite(&lines[count.min(lines.len())..])
+    }
+
+    fn read_lines(&self) -> Result<Vec<String>> {
+        read_lines(&self.path)
+    }
+
+    fn rewrite(&mut self, lines: &[String]) -> Result<()> {
+        rewrite_lines(&self.path, lines)?;
+        self.size = lines.iter().map(|line| line.len() as u64 + 1).sum();
+        Ok(())
+    }
+}
+
+/// How many of the oldest `lines` to drop to get well below `max_bytes` again.
+fn over_cap(lines: &[String], max_bytes: u64) -> usize {
+    let target = max_bytes / 100 * EVICT_TO_PCT;
+    let mut size: u64 = lines.iter().map(|line| line.len() as u64 + 1).sum();
+    let mut dropped = 0;
+    while size > target && dropped < lines.len() {
+        size -= lines[dropped].len() as u64 + 1;
+        dropped += 1;
+    }
+    dropped
+}
+
+/// Reads the points of the file at `path`, none when it doesn't exist.
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Replaces the contents of the file at `path`, going through a temporary file so a
+/// crash never leaves it half written.
+fn rewrite_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let tmp = temp_path(path);
+    {
+        let mut file = BufWriter::new(File::create(&tmp)
+            .with_context(|| format!("Failed to create {}", tmp.display()))?);
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+    }
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Appends points to the file at `path`, creating it if needed.
+fn append_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+    Ok(())
+}
+
+fn rejected_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".rejected");
+    path.with_file_name(name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use crate::influx::tests::FakeInfluxDb;
+    use super::*;
+
+    /// An empty directory for the spool of the test `name`.
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `count` points of 50 bytes each including the newline, numbered from `first`.
+    fn points(first: usize, count: usize) -> Vec<String> {
+        (first..first + count).map(|n| format!("internet_metrics,n={:05} latency_ms=12.5 {:08}", n, n)).collect()
+    }
+
+    fn contents(spool: &Spool) -> Vec<String> {
+        spool.file.lock().unwrap().read_lines().unwrap()
+    }
+
+    fn size(spool: &Spool) -> u64 {
+        spool.file.lock().unwrap().size
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_points_down_to_90_pct() {
+        let dir = spool_dir("evict");
+        let spool = Spool::open(dir.join("spool.lp"), 1000, Duration::from_secs(1)).unwrap();
+        assert!(points(0, 21).iter().all(|line| line.len() + 1 == 50));
+
+        // Exactly at the cap is still fine
+        spool.append(points(0, 20)).await.unwrap();
+        assert_eq!(size(&spool), 1000);
+        assert_eq!(spool.file.lock().unwrap().evicted, 0);
+
+        // One more point drops the oldest until at most 900 bytes are left
+        spool.append(points(20, 1)).await.unwrap();
+        assert_eq!(contents(&spool), points(3, 18));
+        assert_eq!(size(&spool), 900);
+        assert_eq!(spool.file.lock().unwrap().evicted, 3);
+        assert_eq!(fs::metadata(dir.join("spool.lp")).unwrap().len(), 900);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_in_batches() {
+        let dir = spool_dir("replay");
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let mut spool = Spool::open(dir.join("spool.lp"), 10_000_000, Duration::from_secs(1)).unwrap();
+        spool.append(points(0, 7000)).await.unwrap();
+        spool.append(points(7000, 5000)).await.unwrap();
+
+        spool.replay(&writer).await.unwrap();
+        let batches: Vec<_> = fake.writes().iter().map(|(_, lines)| lines.len()).collect();
+        assert_eq!(batches, [REPLAY_BATCH, REPLAY_BATCH, 2000]);
+        assert_eq!(fake.accepted(), points(0, 12000));
+        assert!(spool.is_empty());
+        assert!(contents(&spool).is_empty());
+        assert!(spool.ready());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_points_influxdb_did_not_take() {
+        let dir = spool_dir("unavailable");
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let mut spool = Spool::open(dir.join("spool.lp"), 10_000_000, Duration::from_secs(60)).unwrap();
+        spool.append(points(0, 12000)).await.unwrap();
+
+        // The first batch goes through, then InfluxDB fails
+        fake.respond_with(&[StatusCode::NO_CONTENT, StatusCode::SERVICE_UNAVAILABLE]);
+        let e = spool.replay(&writer).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("Replayed 5000 of 12000 spooled points"), "{:#}", e);
+        assert_eq!(contents(&spool), points(5000, 7000));
+        assert_eq!(size(&spool), 7000 * 50);
+        assert!(!spool.ready());
+
+        spool.replay(&writer).await.unwrap();
+        assert_eq!(fake.accepted(), points(0, 12000));
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_replayed_points_evicted_meanwhile() {
+        let dir = spool_dir("evicted-replay");
+        let spool = Spool::open(dir.join("spool.lp"), 1000, Duration::from_secs(1)).unwrap();
+        spool.append(points(0, 20)).await.unwrap();
+
+        // A replay read all 20 points and wrote the first 10 of them, while another
+        // append evicted the 3 oldest
+        spool.append(points(20, 1)).await.unwrap();
+        spool.remove_replayed(10, 0).await.unwrap();
+        assert_eq!(contents(&spool), points(10, 11));
+        assert_eq!(size(&spool), 11 * 50);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn quarantines_rejected_points() {
+        let dir = spool_dir("rejected");
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let mut spool = Spool::open(dir.join("spool.lp"), 10_000_000, Duration::from_secs(1)).unwrap();
+        spool.append(points(0, 7000)).await.unwrap();
+
+        fake.respond_with(&[StatusCode::BAD_REQUEST]);
+        spool.replay(&writer).await.unwrap();
+        assert_eq!(fake.accepted(), points(5000, 2000));
+        assert!(spool.is_empty());
+        let rejected = fs::read_to_string(dir.join("spool.lp.rejected")).unwrap();
+        assert_eq!(rejected.lines().collect::<Vec<_>>(), points(0, 5000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caps_quarantine_like_the_spool() {
+        let dir = spool_dir("rejected-cap");
+        let (fake, writer) = FakeInfluxDb::spawn();
+        let mut spool = Spool::open(dir.join("spool.lp"), 1000, Duration::from_secs(1)).unwrap();
+        let rejected = dir.join("spool.lp.rejected");
+
+        // A schema conflict rejects every replay
+        fake.respond_with(&[StatusCode::BAD_REQUEST, StatusCode::BAD_REQUEST]);
+        spool.append(points(0, 15)).await.unwrap();
+        spool.replay(&writer).await.unwrap();
+        assert_eq!(fs::metadata(&rejected).unwrap().len(), 750);
+
+        // Past 1000 bytes the oldest rejected points go, down to at most 900 bytes
+        spool.append(points(15, 15)).await.unwrap();
+        spool.replay(&writer).await.unwrap();
+        let contents = fs::read_to_string(&rejected).unwrap();
+        assert_eq!(contents.lines().collect::<Vec<_>>(), points(12, 18));
+        assert_eq!(fs::metadata(&rejected).unwrap().len(), 900);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracks_size_across_restarts() {
+        let dir = spool_dir("restart");
+        let path = dir.join("spool.lp");
+        let spool = Spool::open(path.clone(), 1000, Duration::from_secs(1)).unwrap();
+        assert!(spool.is_empty());
+        spool.append(points(0, 5)).await.unwrap();
+        drop(spool);
+
+        let spool = Spool::open(path.clone(), 1000, Duration::from_secs(1)).unwrap();
+        assert!(!spool.is_empty());
+        assert_eq!(size(&spool), 250);
+
+        // The size picked up from the file counts toward the cap
+        spool.append(points(5, 16)).await.unwrap();
+        assert_eq!(contents(&spool), points(3, 18));
+        assert_eq!(size(&spool), fs::metadata(&path).unwrap().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
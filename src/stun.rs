@@ -0,0 +1,255 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::debug;
+
+pub const STUN_PORT: u16 = 3478;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+/// Delay before an unanswered request is sent again, STUN runs over lossy UDP.
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Asks the STUN server at `server` which address our requests arrive from (RFC 5389
+/// binding request), the public address behind any NAT on the way.
+pub async fn mapped_address(server: SocketAddr, timeout: Duration) -> Result<SocketAddr> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await.context("Failed to open UDP socket")?;
+    socket.connect(server).await.with_context(|| format!("Failed to connect to {}", server))?;
+
+    let transaction: [u8; 12] = rand::random();
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE);
+    request.extend_from_slice(&transaction);
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        socket.send(&request).await.with_context(|| format!("Failed to send STUN request to {}", server))?;
+        let retransmit = (Instant::now() + RETRANSMIT_INTERVAL).min(deadline);
+        while let Ok(received) = time::timeout_at(retransmit, socket.recv(&mut buf)).await {
+            let len = received.with_context(|| format!("Failed to receive STUN response from {}", server))?;
+            match parse_response(&buf[..len], &transaction) {
+                Some(addr) => return Ok(addr),
+                None => debug!("Ignoring unexpected STUN packet from {}", server),
+            }
+        }
+        if Instant::now() >= deadline {
+            bail!("No STUN response from {} within {} seconds", server, timeout.as_secs());
+        }
+    }
+}
+
+/// The mapped address of a binding success response to `transaction`.
+fn parse_response(packet: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if packet.len() < HEADER_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != BINDING_SUCCESS
+        || packet[4..8] != MAGIC_COOKIE
+        || packet[8..20] != transaction[..]
+    {
+        return None;
+    }
+
+    let mut mapped = None;
+    let mut attributes = packet.get(HEADER_LEN..)?;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        // A truncated attribute ends the packet, not what was found before it
+        let Some(value) = attributes.get(4..4 + len) else { break };
+        match kind {
+            // Servers send XOR-MAPPED-ADDRESS so NATs rewriting addresses in payloads
+            // can't touch it, it wins over the plain one
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to 4 bytes
+        attributes = attributes.get((4 + len).next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+/// Parses a (XOR-)MAPPED-ADDRESS value, `transaction` is set for the XOR variant.
+fn parse_address(value: &[u8], transaction: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = transaction {
+        port ^= u16::from_be_bytes([MAGIC_COOKIE[0], MAGIC_COOKIE[1]]);
+        mask[..4].copy_from_slice(&MAGIC_COOKIE);
+        mask[4..].copy_from_slice(transaction);
+    }
+    let addr = match family {
+        FAMILY_V4 => {
+            let bytes: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            IpAddr::V4(Ipv4Addr::from(std::array::from_fn::<u8, 4, _>(|i| bytes[i] ^ mask[i])))
+        }
+        FAMILY_V6 => {
+            let bytes: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(std::array::from_fn::<u8, 16, _>(|i| bytes[i] ^ mask[i])))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(addr, port))
+}
+
+/// Splits `host:port` or `host` of a STUN server, IPv6 addresses go in brackets.
+pub fn split_server(server: &str) -> Result<(&str, u16)> {
+    if let Some(rest) = server.strip_prefix('[') {
+        let (host, port) = rest.split_once(']').ok_or_else(|| anyhow!("Invalid STUN server {}", server))?;
+        return match port.strip_prefix(':') {
+            Some(port) => Ok((host, port.parse().with_context(|| format!("Invalid port in STUN server {}", server))?)),
+            None if port.is_empty() => Ok((host, STUN_PORT)),
+            None => bail!("Invalid STUN server {}", server),
+        };
+    }
+    match server.split_once(':') {
+        Some((host, port)) if !port.contains(':') => {
+            Ok((host, port.parse().with_context(|| format!("Invalid port in STUN server {}", server))?))
+        }
+        _ if server.is_empty() => bail!("Empty STUN server"),
+        _ => Ok((server, STUN_PORT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction of the sample responses in RFC 5769.
+    const TRANSACTION: [u8; 12] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    /// Binding success header for [`TRANSACTION`], the length field is not checked.
+    fn header(length: u16) -> Vec<u8> {
+        let mut packet = BINDING_SUCCESS.to_be_bytes().to_vec();
+        packet.extend_from_slice(&length.to_be_bytes());
+        packet.extend_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(&TRANSACTION);
+        packet
+    }
+
+    /// The IPv4 sample response of RFC 5769, mapped to 192.0.2.1:32853.
+    fn response_v4() -> Vec<u8> {
+        let mut packet = header(0x3c);
+        // SOFTWARE "test vector", padded to 12 bytes
+        packet.extend_from_slice(&[0x80, 0x22, 0x00, 0x0b]);
+        packet.extend_from_slice(b"test vector ");
+        // XOR-MAPPED-ADDRESS
+        packet.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        // MESSAGE-INTEGRITY
+        packet.extend_from_slice(&[0x00, 0x08, 0x00, 0x14]);
+        packet.extend_from_slice(&[
+            0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74,
+            0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+        ]);
+        // FINGERPRINT
+        packet.extend_from_slice(&[0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96]);
+        packet
+    }
+
+    /// The IPv6 sample response of RFC 5769, mapped to [2001:db8:1234:5678:11:2233:4455:6677]:32853.
+    fn response_v6() -> Vec<u8> {
+        let mut packet = header(0x48);
+        packet.extend_from_slice(&[0x80, 0x22, 0x00, 0x0b]);
+        packet.extend_from_slice(b"test vector ");
+        packet.extend_from_slice(&[0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47]);
+        packet.extend_from_slice(&[
+            0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79,
+            0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ]);
+        packet
+    }
+
+    /// A plain MAPPED-ADDRESS of 192.0.2.1:32853, as sent by RFC 3489 servers.
+    const MAPPED_V4: [u8; 12] = [0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x80, 0x55, 0xc0, 0x00, 0x02, 0x01];
+
+    #[test]
+    fn parses_xor_mapped_address() {
+        assert_eq!(parse_response(&response_v4(), &TRANSACTION), Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(
+            parse_response(&response_v6(), &TRANSACTION),
+            Some("[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap()),
+        );
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address() {
+        let mut packet = header(12);
+        packet.extend_from_slice(&MAPPED_V4);
+        assert_eq!(parse_response(&packet, &TRANSACTION), Some("192.0.2.1:32853".parse().unwrap()));
+
+        // Both present, the XOR variant wins whatever the order
+        let mut packet = header(24);
+        packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x00, 0x50, 0x0a, 0x00, 0x00, 0x01]);
+        packet.extend_from_slice(&response_v4()[36..48]);
+        assert_eq!(parse_response(&packet, &TRANSACTION), Some("192.0.2.1:32853".parse().unwrap()));
+    }
+
+    #[test]
+    fn keeps_address_before_truncated_attribute() {
+        let mut packet = header(20);
+        packet.extend_from_slice(&MAPPED_V4);
+        // SOFTWARE claiming 11 bytes with only 4 of them in the packet
+        packet.extend_from_slice(&[0x80, 0x22, 0x00, 0x0b]);
+        packet.extend_from_slice(b"test");
+        assert_eq!(parse_response(&packet, &TRANSACTION), Some("192.0.2.1:32853".parse().unwrap()));
+
+        // Cut off in the middle of the XOR-MAPPED-ADDRESS, nothing was found before it
+        let truncated = &response_v4()[..44];
+        assert_eq!(parse_response(truncated, &TRANSACTION), None);
+    }
+
+    #[test]
+    fn ignores_other_packets() {
+        let mut other_transaction = TRANSACTION;
+        other_transaction[11] ^= 0xff;
+        assert_eq!(parse_response(&response_v4(), &other_transaction), None);
+
+        // Binding error response
+        let mut error = response_v4();
+        error[..2].copy_from_slice(&0x0111u16.to_be_bytes());
+        assert_eq!(parse_response(&error, &TRANSACTION), None);
+
+        // RFC 3489 responses have no magic cookie
+        let mut no_cookie = response_v4();
+        no_cookie[4..8].copy_from_slice(&[0; 4]);
+        assert_eq!(parse_response(&no_cookie, &TRANSACTION), None);
+
+        assert_eq!(parse_response(&response_v4()[..HEADER_LEN - 1], &TRANSACTION), None);
+        assert_eq!(parse_response(&header(0), &TRANSACTION), None);
+    }
+
+    #[test]
+    fn rejects_unknown_family() {
+        assert_eq!(parse_address(&[0x00, 0x03, 0x80, 0x55, 0xc0, 0x00, 0x02, 0x01], None), None);
+        // IPv6 family with an IPv4 sized address
+        assert_eq!(parse_address(&[0x00, 0x02, 0x80, 0x55, 0xc0, 0x00, 0x02, 0x01], None), None);
+    }
+
+    #[test]
+    fn splits_servers() {
+        assert_eq!(split_server("stun.example.com:19302").unwrap(), ("stun.example.com", 19302));
+        assert_eq!(split_server("stun.example.com").unwrap(), ("stun.example.com", STUN_PORT));
+        assert_eq!(split_server("192.0.2.1:3479").unwrap(), ("192.0.2.1", 3479));
+        assert_eq!(split_server("[2001:db8::1]:3479").unwrap(), ("2001:db8::1", 3479));
+        assert_eq!(split_server("[2001:db8::1]").unwrap(), ("2001:db8::1", STUN_PORT));
+        assert_eq!(split_server("2001:db8::1").unwrap(), ("2001:db8::1", STUN_PORT));
+
+        for server in ["", "stun.example.com:stun", "stun.example.com:", "[2001:db8::1", "[2001:db8::1]3479"] {
+            assert!(split_server(server).is_err(), "{} was accepted", server);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tracing::debug;
+use crate::icmp::{EchoReply, PingOptions, PingResult};
+
+/// Splits `host:port`, IPv6 addresses go in brackets as in `[2001:db8::1]:443`.
+pub fn split_host_port(address: &str) -> Result<(&str, u16)> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("TCP target {} has no port, expected host:port", address))?;
+    let (host, bracketed) = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(host) => (host, true),
+        None => (host, false),
+    };
+    if host.is_empty() || host.contains(['[', ']']) || (host.contains(':') && !bracketed) {
+        bail!("Invalid TCP target {}, expected host:port or [ipv6]:port", address);
+    }
+    let port = port.parse().with_context(|| format!("Invalid port in TCP target {}", address))?;
+    Ok((host, port))
+}
+
+/// Opens `options.count` connections to `addr`, one every `options.interval`, and times
+/// the handshakes in the shape of a ping: every established connection is a reply,
+/// refused and timed out attempts are lost. Connections are closed right away.
+pub async fn connect(addr: SocketAddr, options: &PingOptions) -> PingResult {
+    let mut replies = Vec::new();
+    let mut next_attempt = Instant::now();
+
+    for seq in 1..=options.count {
+        time::sleep_until(next_attempt).await;
+        next_attempt += options.interval;
+
+        let started = Instant::now();
+        match time::timeout(options.timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(_stream)) => replies.push(EchoReply { seq, rtt: started.elapsed(), ttl: None }),
+            Ok(Err(e)) => debug!("Connection {} to {} failed: {}", seq, addr, e),
+            Err(_) => debug!("Connection {} to {} timed out", seq, addr),
+        }
+    }
+
+    PingResult { addr: addr.ip(), transmitted: options.count, replies }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(split_host_port("example.com:443").unwrap(), ("example.com", 443));
+        assert_eq!(split_host_port("192.0.2.1:22").unwrap(), ("192.0.2.1", 22));
+        assert_eq!(split_host_port("[::1]:443").unwrap(), ("::1", 443));
+        assert_eq!(split_host_port("[2001:db8::1]:8080").unwrap(), ("2001:db8::1", 8080));
+    }
+
+    #[test]
+    fn rejects_malformed_targets() {
+        for address in [
+            // Missing port
+            "example.com",
+            "[::1]",
+            ":443",
+            "[]:443",
+            // IPv6 without brackets, with or without a port
+            "::1",
+            "2001:db8::1",
+            "2001:db8::1:443",
+            // Unbalanced brackets
+            "[::1:443",
+            "::1]:443",
+            // Not a port
+            "example.com:https",
+            "example.com:",
+            "example.com:65536",
+            "[::1]:-1",
+        ] {
+            assert!(split_host_port(address).is_err(), "{} was accepted", address);
+        }
+    }
+
+    #[test]
+    fn error_names_the_problem() {
+        let no_port = split_host_port("example.com").unwrap_err();
+        assert!(no_port.to_string().contains("has no port"), "{}", no_port);
+        let bad_port = split_host_port("example.com:https").unwrap_err();
+        assert!(bad_port.to_string().contains("Invalid port"), "{}", bad_port);
+        let bare_ipv6 = split_host_port("2001:db8::1").unwrap_err();
+        assert!(bare_ipv6.to_string().contains("[ipv6]:port"), "{}", bare_ipv6);
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use tokio::time::{self, Instant};
+
+/// Limits that keep a single download test from eating a metered link.
+#[derive(Debug, Clone)]
+pub struct DownloadLimits {
+    /// Stop reading the body once this many bytes have arrived.
+    pub max_bytes: u64,
+    /// Stop reading the body once the whole request has taken this long.
+    pub max_duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadResult {
+    /// Body bytes received before the transfer finished or hit a limit.
+    pub bytes: u64,
+    /// Time from sending the request until the response headers arrived.
+    pub ttfb: Duration,
+    /// Time spent receiving the body, used for the throughput figure.
+    pub duration: Duration,
+}
+
+impl DownloadResult {
+    pub fn mbps(&self) -> f64 {
+        mbps(self.bytes, self.duration)
+    }
+}
+
+pub fn mbps(bytes: u64, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs <= 0.0 {
+        return 0.0;
+    }
+    bytes as f64 * 8.0 / secs / 1_000_000.0
+}
+
+/// Streams `url` and throws the body away, measuring how fast it arrives.
+pub async fn download(client: &Client, url: &str, limits: &DownloadLimits) -> Result<DownloadResult> {
+    let started = Instant::now();
+    let deadline = started + limits.max_duration;
+
+    let mut response = time::timeout_at(deadline, client.get(url).send())
+        .await
+        .with_context(|| format!("Timed out waiting for response from {}", url))?
+        .with_context(|| format!("Failed to send download request to {}", url))?;
+    let ttfb = started.elapsed();
+
+    let status = response.status();
+    if !status.is_success() {
+        bail!("Download from {} failed with HTTP status {}", url, status);
+    }
+
+    let body_started = Instant::now();
+    let mut bytes = 0u64;
+    while bytes < limits.max_bytes {
+        match time::timeout_at(deadline, response.chunk()).await {
+            Ok(chunk) => match chunk.context("Failed to read download body")? {
+                Some(chunk) => bytes += chunk.len() as u64,
+                None => break,
+            },
+            // Hitting the time cap is expected on slow links, report what we got.
+            Err(_) => break,
+        }
+    }
+
+    Ok(DownloadResult {
+        bytes,
+        ttfb,
+        duration: body_started.elapsed(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadResult {
+    pub bytes: u64,
+    /// Time from sending the request until the response headers arrived.
+    pub duration: Duration,
+    pub status: u16,
+}
+
+impl UploadResult {
+    pub fn mbps(&self) -> f64 {
+        mbps(self.bytes, self.duration)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Builds an incompressible payload so proxies and servers cannot shrink it.
+pub fn upload_payload(size: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut payload = Vec::with_capacity(size + 8);
+    while payload.len() < size {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        payload.extend_from_slice(&state.to_le_bytes());
+    }
+    payload.truncate(size);
+    payload
+}
+
+/// POSTs `size` bytes to `url`, measuring how long it takes to get them out.
+pub async fn upload(client: &Client, url: &str, size: usize, max_duration: Duration) -> Result<UploadResult> {
+    let payload = upload_payload(size);
+
+    let started = Instant::now();
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .body(payload)
+        .timeout(max_duration)
+        .send()
+        .await
+        .with_context(|| format!("Failed to upload to {}", url))?;
+    let duration = started.elapsed();
+
+    Ok(UploadResult {
+        bytes: size as u64,
+        duration,
+        status: response.status().as_u16(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use super::*;
+
+    /// Starts an HTTP sink on a free local port that reads every POST body and answers
+    /// with `status`, counting the bytes it received.
+    fn spawn_sink(status: StatusCode) -> (SocketAddr, Arc<AtomicUsize>) {
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let counter = counter.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default();
+                        counter.fetch_add(body.len(), Ordering::SeqCst);
+                        let mut response = Response::new(Body::from("ok"));
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    /// Starts an HTTP source on a free local port that answers every request with an
+    /// endless body of 16 KiB chunks, sent every `pause`.
+    fn spawn_source(pause: Duration) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    let chunk = hyper::body::Bytes::from(vec![0u8; 16 * 1024]);
+                    while sender.send_data(chunk.clone()).await.is_ok() {
+                        time::sleep(pause).await;
+                    }
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn download_stops_at_byte_cap() {
+        let addr = spawn_source(Duration::ZERO);
+        let limits = DownloadLimits { max_bytes: 1_000_000, max_duration: Duration::from_secs(10) };
+        let result = download(&Client::new(), &format!("http://{}/file", addr), &limits).await.unwrap();
+
+        // The chunk that crosses the cap is still counted
+        assert!(result.bytes >= 1_000_000, "{} bytes", result.bytes);
+        assert!(result.bytes < 1_000_000 + 16 * 1024, "{} bytes", result.bytes);
+        assert!(result.duration < Duration::from_secs(10));
+        assert!(result.mbps() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn download_stops_at_duration_cap() {
+        let addr = spawn_source(Duration::from_millis(10));
+        let limits = DownloadLimits { max_bytes: u64::MAX, max_duration: Duration::from_millis(500) };
+        let started = Instant::now();
+        let result = download(&Client::new(), &format!("http://{}/file", addr), &limits).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(result.bytes > 0);
+        assert!(result.duration <= Duration::from_millis(600), "{:?}", result.duration);
+    }
+
+    #[tokio::test]
+    async fn download_fails_on_error_status() {
+        let (addr, _) = spawn_sink(StatusCode::NOT_FOUND);
+        let limits = DownloadLimits { max_bytes: 1000, max_duration: Duration::from_secs(10) };
+        let e = download(&Client::new(), &format!("http://{}/file", addr), &limits).await.unwrap_err();
+        assert!(e.to_string().contains("404"), "{}", e);
+    }
+
+    #[test]
+    fn computes_mbps() {
+        assert_eq!(mbps(1_000_000, Duration::from_secs(1)), 8.0);
+        assert_eq!(mbps(250_000, Duration::from_millis(500)), 4.0);
+        assert_eq!(mbps(1_000_000, Duration::ZERO), 0.0);
+    }
+
+    #[tokio::test]
+    async fn upload_to_local_sink() {
+        let (addr, received) = spawn_sink(StatusCode::OK);
+        let size = 1_000_000;
+        let result = upload(&Client::new(), &format!("http://{}/upload", addr), size, Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        assert_eq!(result.bytes, size as u64);
+        assert_eq!(result.status, 200);
+        assert!(result.is_success());
+        assert!(result.mbps() > 0.0);
+        assert_eq!(received.load(Ordering::SeqCst), size);
+    }
+
+    #[tokio::test]
+    async fn upload_rejected_by_sink() {
+        let (addr, _) = spawn_sink(StatusCode::PAYLOAD_TOO_LARGE);
+        let result = upload(&Client::new(), &format!("http://{}/upload", addr), 1000, Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        assert_eq!(result.status, 413);
+        assert!(!result.is_success());
+    }
+
+    #[test]
+    fn payload_has_requested_size() {
+        assert_eq!(upload_payload(0).len(), 0);
+        assert_eq!(upload_payload(13).len(), 13);
+        assert_ne!(upload_payload(64)[..32], upload_payload(64)[32..]);
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use socket2::SockAddr;
+use tokio::time::{self, Instant};
+use tracing::debug;
+use crate::icmp::{self, IcmpSocket, LatencyStats, SocketKind};
+
+const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/// Delay between the requests of a round, routers rate limit the errors they send.
+const SEND_SPACING: Duration = Duration::from_millis(20);
+
+/// Settings for tracing the path to an address.
+#[derive(Debug, Clone)]
+pub struct TraceOptions {
+    /// Highest TTL (hop limit) requests are sent with.
+    pub max_hops: u8,
+    /// Requests sent per hop, one in every round.
+    pub queries: u16,
+    /// Delay between the starts of two rounds.
+    pub interval: Duration,
+    /// How long to wait for answers after the last request was sent.
+    pub timeout: Duration,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            max_hops: 30,
+            queries: 3,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The requests sent with one TTL and the answers to them.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub ttl: u8,
+    /// The router that answered most often, `None` when none did.
+    pub addr: Option<IpAddr>,
+    pub sent: u16,
+    pub rtts: Vec<Duration>,
+}
+
+impl Hop {
+    pub fn received(&self) -> u16 {
+        self.rtts.len() as u16
+    }
+
+    /// Percentage of requests that went unanswered.
+    pub fn loss_pct(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        100.0 * (self.sent.saturating_sub(self.received())) as f64 / self.sent as f64
+    }
+
+    pub fn stats(&self) -> Option<LatencyStats> {
+        LatencyStats::from_rtts(self.rtts.iter().copied())
+    }
+}
+
+/// Outcome of tracing the path to a single address.
+#[derive(Debug, Clone)]
+pub struct TraceResult {
+    pub addr: IpAddr,
+    /// Every hop up to the destination, or up to the last one that answered.
+    pub hops: Vec<Hop>,
+    /// Whether the destination answered.
+    pub reached: bool,
+    /// Whether the path differs from the previous trace that reached the destination,
+    /// `None` without one.
+    pub changed: Option<bool>,
+    /// Path of that previous trace, only kept when it changed.
+    pub previous_path: Option<String>,
+}
+
+impl TraceResult {
+    /// The hop addresses in order, `*` for hops that didn't answer.
+    pub fn path(&self) -> String {
+        let hops: Vec<String> = self.hops
+            .iter()
+            .map(|hop| hop.addr.map_or_else(|| "*".to_string(), |addr| addr.to_string()))
+            .collect();
+        hops.join(" > ")
+    }
+
+    /// Whether a hop was answered by another router than in `previous`, or the
+    /// destination is reached after a different number of hops. Hops that didn't
+    /// answer in either trace don't count, routers commonly drop some requests.
+    pub fn differs_from(&self, previous: &TraceResult) -> bool {
+        let rerouted = self.hops.iter().zip(&previous.hops).any(|(hop, previous)| {
+            matches!((hop.addr, previous.addr), (Some(addr), Some(previous)) if addr != previous)
+        });
+        let length_changed = self.reached && previous.reached && self.hops.len() != previous.hops.len();
+        rerouted || length_changed
+    }
+}
+
+/// How a request was answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    /// Echo reply from the destination.
+    Reply,
+    /// A router on the way dropped the request when its TTL ran out.
+    TimeExceeded,
+    /// A router or the destination rejected the request.
+    Unreachable,
+}
+
+/// Traces the path to `addr` like mtr: every round sends an echo request with each
+/// TTL, the routers on the way answer with time exceeded and the destination with an
+/// echo reply. Once the destination has answered, later rounds stop at its hop.
+pub async fn trace(addr: IpAddr, options: &TraceOptions) -> Result<TraceResult> {
+    let socket = IcmpSocket::open(addr)?;
+    socket.enable_errors()?;
+    let destination = SockAddr::from(SocketAddr::new(addr, 0));
+    let ident = icmp::next_ident();
+    let max_hops = options.max_hops.max(1);
+
+    let mut in_flight: HashMap<u16, (u8, Instant)> = HashMap::new();
+    let mut sent = vec![0u16; max_hops as usize];
+    let mut answers: Vec<Vec<(IpAddr, Duration)>> = vec![Vec::new(); max_hops as usize];
+    // Lowest TTL that got an echo reply or was rejected, there is nothing beyond it
+    let mut last_hop: Option<u8> = None;
+    let mut reached = false;
+    let mut buf = [0u8; 1500];
+    let mut error_buf = [0u8; 1500];
+
+    let mut seq = 0u16;
+    let mut round = 0u16;
+    let mut ttl = 1u8;
+    let mut round_start = Instant::now();
+    let mut next_send = round_start;
+    let mut deadline = Instant::now() + options.timeout;
+
+    while round < options.queries || (!in_flight.is_empty() && Instant::now() < deadline) {
+        let sending = round < options.queries;
+        let answer = tokio::select! {
+            _ = time::sleep_until(next_send), if sending => {
+                let last = last_hop.unwrap_or(max_hops);
+                if ttl <= last {
+                    seq = seq.wrapping_add(1);
+                    socket.set_hop_limit(ttl)?;
+                    socket.send_to(&icmp::echo_request(socket.v6, ident, seq), &destination).await
+                        .with_context(|| format!("Failed to send echo request to {}", addr))?;
+                    in_flight.insert(seq, (ttl, Instant::now()));
+                    sent[ttl as usize - 1] += 1;
+                    deadline = Instant::now() + options.timeout;
+                }
+
+                if ttl < last {
+                    ttl += 1;
+                    next_send += SEND_SPACING;
+                } else {
+                    round += 1;
+                    ttl = 1;
+                    round_start += options.interval;
+                    next_send = round_start;
+                }
+                None
+            }
+            received = socket.recv(&mut buf) => match received {
+                Ok((len, _, Some(source))) => parse_answer(socket.v6, &buf[..len])
+                    .filter(|(_, reply_ident, _)| socket.kind == SocketKind::Datagram || *reply_ident == ident)
+                    .map(|(answer, _, seq)| (answer, seq, source)),
+                Ok(_) => None,
+                // Ping sockets also report the errors that are queued for us here, once
+                Err(e) => {
+                    debug!("Ignoring receive error while tracing {}: {}", addr, e);
+                    None
+                }
+            },
+            received = socket.recv_error(&mut error_buf), if socket.kind == SocketKind::Datagram => {
+                let (len, error) = received.context("Failed to receive ICMP error")?;
+                // The error queue hands back the request the error is about
+                error.and_then(|(icmp_type, source)| {
+                    let answer = error_kind(socket.v6, icmp_type)?;
+                    let (_, seq) = parse_echo_request(socket.v6, &error_buf[..len])?;
+                    Some((answer, seq, source))
+                })
+            }
+            _ = time::sleep_until(deadline), if !sending => None,
+        };
+
+        let Some((answer, seq, source)) = answer else { continue };
+        let Some((ttl, sent_at)) = in_flight.remove(&seq) else { continue };
+        answers[ttl as usize - 1].push((source, sent_at.elapsed()));
+        if answer != Answer::TimeExceeded {
+            reached |= answer == Answer::Reply;
+            last_hop = Some(last_hop.map_or(ttl, |last| last.min(ttl)));
+        }
+    }
+
+    let hop_count = match last_hop {
+        Some(ttl) => ttl as usize,
+        None => answers.iter().rposition(|answers| !answers.is_empty()).map_or(0, |index| index + 1),
+    };
+    let hops = answers
+        .into_iter()
+        .zip(sent)
+        .take(hop_count)
+        .enumerate()
+        .map(|(index, (answers, sent))| Hop {
+            ttl: index as u8 + 1,
+            addr: most_common(answers.iter().map(|(addr, _)| *addr)),
+            sent,
+            rtts: answers.into_iter().map(|(_, rtt)| rtt).collect(),
+        })
+        .collect();
+
+    Ok(TraceResult { addr, hops, reached, changed: None, previous_path: None })
+}
+
+/// The address that occurs most often, the first one of those on a tie.
+fn most_common(addrs: impl Iterator<Item = IpAddr>) -> Option<IpAddr> {
+    let mut counts: Vec<(IpAddr, usize)> = Vec::new();
+    for addr in addrs {
+        match counts.iter_mut().find(|(counted, _)| *counted == addr) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((addr, 1)),
+        }
+    }
+    counts.iter().rev().max_by_key(|(_, count)| *count).map(|(addr, _)| *addr)
+}
+
+fn error_kind(v6: bool, icmp_type: u8) -> Option<Answer> {
+    match (v6, icmp_type) {
+        (false, ICMPV4_TIME_EXCEEDED) | (true, ICMPV6_TIME_EXCEEDED) => Some(Answer::TimeExceeded),
+        (false, ICMPV4_DEST_UNREACHABLE) | (true, ICMPV6_DEST_UNREACHABLE) => Some(Answer::Unreachable),
+        _ => None,
+    }
+}
+
+/// Parses an echo reply, or an ICMP error quoting one of our echo requests, returning
+/// the answer and the identifier and sequence number of the request.
+fn parse_answer(v6: bool, packet: &[u8]) -> Option<(Answer, u16, u16)> {
+    if let Some((ident, seq)) = icmp::parse_echo_reply(v6, packet) {
+        return Some((Answer::Reply, ident, seq));
+    }
+    let answer = error_kind(v6, *packet.first()?)?;
+    // Errors quote the IP header of the request followed by the start of its ICMP message
+    let quoted = packet.get(8..)?;
+    let header_len = if v6 { 40 } else { ((quoted.first()? & 0x0f) as usize) * 4 };
+    let (ident, seq) = parse_echo_request(v6, quoted.get(header_len..)?)?;
+    Some((answer, ident, seq))
+}
+
+/// Parses one of our echo requests, returning its identifier and sequence number.
+fn parse_echo_request(v6: bool, packet: &[u8]) -> Option<(u16, u16)> {
+    let expected = if v6 { icmp::ICMPV6_ECHO_REQUEST } else { icmp::ICMPV4_ECHO_REQUEST };
+    if packet.len() < 8 || packet[0] != expected {
+        return None;
+    }
+    Some((u16::from_be_bytes([packet[4], packet[5]]), u16::from_be_bytes([packet[6], packet[7]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 header of an echo request to 1.1.1.1 from 192.168.1.10, as routers quote it.
+    const V4_HEADER: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x54, 0x6b, 0x1c, 0x40, 0x00, 0x01, 0x01, 0x4b, 0x5e,
+        0xc0, 0xa8, 0x01, 0x0a, 0x01, 0x01, 0x01, 0x01,
+    ];
+
+    /// IPv6 header of an echo request to 2606:4700:4700::1111 with a hop limit of 1.
+    const V6_HEADER: [u8; 40] = [
+        0x60, 0x0d, 0x2e, 0x7b, 0x00, 0x40, 0x3a, 0x01,
+        0x2a, 0x02, 0x81, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x26, 0x06, 0x47, 0x00, 0x47, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x11,
+    ];
+
+    /// Start of an echo request with identifier 0x1f2e and sequence number 7.
+    const V4_REQUEST: [u8; 8] = [0x08, 0x00, 0x8a, 0x61, 0x1f, 0x2e, 0x00, 0x07];
+    const V6_REQUEST: [u8; 8] = [0x80, 0x00, 0x5c, 0x12, 0x1f, 0x2e, 0x00, 0x07];
+
+    fn icmp_error(icmp_type: u8, code: u8, quoted: &[&[u8]]) -> Vec<u8> {
+        let mut packet = vec![icmp_type, code, 0xf4, 0xff, 0, 0, 0, 0];
+        for part in quoted {
+            packet.extend_from_slice(part);
+        }
+        packet
+    }
+
+    #[test]
+    fn parses_echo_replies() {
+        let reply = [0x00, 0x00, 0x92, 0x61, 0x1f, 0x2e, 0x00, 0x07, 0xde, 0xad];
+        assert_eq!(parse_answer(false, &reply), Some((Answer::Reply, 0x1f2e, 7)));
+        let reply = [0x81, 0x00, 0x5b, 0x12, 0x1f, 0x2e, 0x00, 0x07];
+        assert_eq!(parse_answer(true, &reply), Some((Answer::Reply, 0x1f2e, 7)));
+        // An IPv4 reply type means nothing on an ICMPv6 socket
+        assert_eq!(parse_answer(true, &[0x00, 0x00, 0x92, 0x61, 0x1f, 0x2e, 0x00, 0x07]), None);
+    }
+
+    #[test]
+    fn parses_v4_errors() {
+        let packet = icmp_error(ICMPV4_TIME_EXCEEDED, 0, &[&V4_HEADER, &V4_REQUEST]);
+        assert_eq!(parse_answer(false, &packet), Some((Answer::TimeExceeded, 0x1f2e, 7)));
+
+        // Port unreachable, and a quoted header with options
+        let mut header = V4_HEADER.to_vec();
+        header[0] = 0x46;
+        header.extend_from_slice(&[0x01, 0x01, 0x01, 0x00]);
+        let packet = icmp_error(ICMPV4_DEST_UNREACHABLE, 3, &[&header, &V4_REQUEST]);
+        assert_eq!(parse_answer(false, &packet), Some((Answer::Unreachable, 0x1f2e, 7)));
+    }
+
+    #[test]
+    fn parses_v6_errors() {
+        let packet = icmp_error(ICMPV6_TIME_EXCEEDED, 0, &[&V6_HEADER, &V6_REQUEST]);
+        assert_eq!(parse_answer(true, &packet), Some((Answer::TimeExceeded, 0x1f2e, 7)));
+        // Type 3 is unreachable for ICMPv4, which doesn't find an IPv4 header in the quote
+        assert_eq!(parse_answer(false, &packet), None);
+        let packet = icmp_error(ICMPV6_DEST_UNREACHABLE, 4, &[&V6_HEADER, &V6_REQUEST]);
+        assert_eq!(parse_answer(true, &packet), Some((Answer::Unreachable, 0x1f2e, 7)));
+    }
+
+    #[test]
+    fn ignores_other_packets() {
+        // Errors about other traffic, here a UDP datagram
+        let udp = [0xd4, 0x31, 0x82, 0x9b, 0x00, 0x48, 0x5d, 0x2a];
+        assert_eq!(parse_answer(false, &icmp_error(ICMPV4_TIME_EXCEEDED, 0, &[&V4_HEADER, &udp])), None);
+        // Quotes cut short before the echo request header is complete
+        assert_eq!(parse_answer(false, &icmp_error(ICMPV4_TIME_EXCEEDED, 0, &[&V4_HEADER, &V4_REQUEST[..4]])), None);
+        assert_eq!(parse_answer(true, &icmp_error(ICMPV6_TIME_EXCEEDED, 0, &[&V6_HEADER[..30]])), None);
+        assert_eq!(parse_answer(false, &icmp_error(ICMPV4_TIME_EXCEEDED, 0, &[])), None);
+        // Echo requests and redirects
+        assert_eq!(parse_answer(false, &V4_REQUEST), None);
+        assert_eq!(parse_answer(false, &icmp_error(5, 1, &[&V4_HEADER, &V4_REQUEST])), None);
+        assert_eq!(parse_answer(false, &[]), None);
+    }
+
+    fn trace(path: &[Option<&str>], reached: bool) -> TraceResult {
+        TraceResult {
+            addr: "1.1.1.1".parse().unwrap(),
+            hops: path
+                .iter()
+                .enumerate()
+                .map(|(index, addr)| Hop {
+                    ttl: index as u8 + 1,
+                    addr: addr.map(|addr| addr.parse().unwrap()),
+                    sent: 3,
+                    rtts: if addr.is_some() { vec![Duration::from_millis(5)] } else { Vec::new() },
+                })
+                .collect(),
+            reached,
+            changed: None,
+            previous_path: None,
+        }
+    }
+
+    #[test]
+    fn formats_path() {
+        let result = trace(&[Some("192.168.1.1"), None, Some("1.1.1.1")], true);
+        assert_eq!(result.path(), "192.168.1.1 > * > 1.1.1.1");
+    }
+
+    #[test]
+    fn same_path_is_unchanged() {
+        let previous = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), Some("1.1.1.1")], true);
+        let current = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), Some("1.1.1.1")], true);
+        assert!(!current.differs_from(&previous));
+    }
+
+    #[test]
+    fn other_router_is_a_change() {
+        let previous = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), Some("1.1.1.1")], true);
+        let current = trace(&[Some("192.168.1.1"), Some("10.0.0.2"), Some("1.1.1.1")], true);
+        assert!(current.differs_from(&previous));
+    }
+
+    #[test]
+    fn hops_without_answer_are_not_a_change() {
+        let previous = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), None, Some("1.1.1.1")], true);
+        let current = trace(&[Some("192.168.1.1"), None, Some("172.16.0.1"), Some("1.1.1.1")], true);
+        assert!(!current.differs_from(&previous));
+        assert!(!previous.differs_from(&current));
+    }
+
+    #[test]
+    fn path_length_only_counts_when_both_reached() {
+        let previous = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), Some("1.1.1.1")], true);
+        let longer = trace(&[Some("192.168.1.1"), Some("10.0.0.1"), Some("10.0.1.1"), Some("1.1.1.1")], true);
+        assert!(longer.differs_from(&previous));
+
+        // Stopped after the last hop that answered, the rest of the path is unknown
+        let unreached = trace(&[Some("192.168.1.1"), Some("10.0.0.1")], false);
+        assert!(!unreached.differs_from(&previous));
+    }
+
+    #[test]
+    fn most_common_prefers_first_on_tie() {
+        let addrs = ["10.0.0.2", "10.0.0.1", "10.0.0.1", "10.0.0.2"].map(|addr| addr.parse::<IpAddr>().unwrap());
+        assert_eq!(most_common(addrs.into_iter()), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(most_common(addrs[1..].iter().copied()), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(most_common(std::iter::empty()), None);
+    }
+}
@@ -0,0 +1,410 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::debug;
+
+const PROC_NET_WIRELESS: &str = "/proc/net/wireless";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+/// The kernel answers right away, this only guards against a wedged driver.
+const NETLINK_TIMEOUT: Duration = Duration::from_secs(2);
+
+const NLMSG_HEADER_LEN: usize = 16;
+const GENL_HEADER_LEN: usize = 4;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_DUMP: u16 = 0x300;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const NL80211_CMD_GET_INTERFACE: u8 = 5;
+const NL80211_CMD_GET_STATION: u8 = 17;
+const NL80211_CMD_GET_SURVEY: u8 = 50;
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_MAC: u16 = 6;
+const NL80211_ATTR_STA_INFO: u16 = 21;
+const NL80211_ATTR_WIPHY_FREQ: u16 = 38;
+const NL80211_ATTR_SSID: u16 = 52;
+const NL80211_ATTR_SURVEY_INFO: u16 = 84;
+const NL80211_STA_INFO_SIGNAL: u16 = 7;
+const NL80211_STA_INFO_TX_BITRATE: u16 = 8;
+const NL80211_STA_INFO_RX_BITRATE: u16 = 14;
+const NL80211_RATE_INFO_BITRATE: u16 = 1;
+const NL80211_RATE_INFO_BITRATE32: u16 = 5;
+const NL80211_SURVEY_INFO_NOISE: u16 = 2;
+const NL80211_SURVEY_INFO_IN_USE: u16 = 3;
+
+/// Link quality of a Wi-Fi interface. Everything but the name is `None` while the
+/// interface is not associated, or when the driver doesn't report it.
+#[derive(Debug, Clone, Default)]
+pub struct WifiStats {
+    pub interface: String,
+    pub ssid: Option<String>,
+    /// MAC address of the access point.
+    pub bssid: Option<String>,
+    pub frequency_mhz: Option<u32>,
+    /// Link quality on the driver's own scale, usually out of 70.
+    pub link_quality: Option<f64>,
+    pub signal_dbm: Option<i32>,
+    pub noise_dbm: Option<i32>,
+    /// Rate of the last frame sent to the access point.
+    pub tx_bitrate_mbps: Option<f64>,
+    /// Rate of the last frame received from the access point.
+    pub rx_bitrate_mbps: Option<f64>,
+}
+
+impl WifiStats {
+    pub fn is_associated(&self) -> bool {
+        self.bssid.is_some() || self.signal_dbm.is_some()
+    }
+
+    /// Signal to noise ratio in dB.
+    pub fn snr_db(&self) -> Option<i32> {
+        Some(self.signal_dbm? - self.noise_dbm?)
+    }
+}
+
+/// `/proc/net/wireless` values of one interface.
+#[derive(Debug, Default)]
+struct WirelessExtensions {
+    link_quality: Option<f64>,
+    signal_dbm: Option<i32>,
+    noise_dbm: Option<i32>,
+}
+
+/// Reads the link quality of the Wi-Fi interfaces named in `only`, or of every Wi-Fi
+/// interface when it is empty. Blocks on netlink, run it off the async threads.
+pub fn read(only: &[String]) -> Result<Vec<WifiStats>> {
+    // Missing without any interface supporting wireless extensions
+    let wext = parse_proc_wireless(&fs::read_to_string(PROC_NET_WIRELESS).unwrap_or_default());
+    let mut names: Vec<String> = wext.iter().map(|(name, _)| name.clone()).collect();
+    if let Ok(entries) = fs::read_dir(SYS_CLASS_NET) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().join("phy80211").exists() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    if !only.is_empty() {
+        if let Some(missing) = only.iter().find(|name| !names.contains(name)) {
+            bail!("{} is not a Wi-Fi interface", missing);
+        }
+        names.retain(|name| only.contains(name));
+    }
+    if names.is_empty() {
+        bail!("No Wi-Fi interfaces found");
+    }
+    names.sort();
+
+    // Drivers without nl80211 support still have the wireless extensions
+    let nl80211 = Nl80211::open()
+        .map_err(|e| debug!("nl80211 is not available, only reading {}: {:#}", PROC_NET_WIRELESS, e))
+        .ok();
+    let mut stats = Vec::new();
+    for name in names {
+        let mut wifi = WifiStats { interface: name.clone(), ..WifiStats::default() };
+        if let Some(nl80211) = &nl80211 {
+            if let Err(e) = nl80211.read(&mut wifi) {
+                debug!("Failed to read nl80211 station info of {}: {:#}", name, e);
+            }
+        }
+        if let Some((_, wext)) = wext.iter().find(|(interface, _)| *interface == name) {
+            wifi.link_quality = wext.link_quality;
+            wifi.signal_dbm = wifi.signal_dbm.or(wext.signal_dbm);
+            wifi.noise_dbm = wifi.noise_dbm.or(wext.noise_dbm);
+        }
+        stats.push(wifi);
+    }
+    Ok(stats)
+}
+
+/// Parses `/proc/net/wireless`: two header lines, then `name: status link level noise ...`
+/// with a trailing `.` on values that were updated since the last read.
+fn parse_proc_wireless(table: &str) -> Vec<(String, WirelessExtensions)> {
+    table
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, columns) = line.split_once(':')?;
+            let columns: Vec<f64> = columns
+                .split_whitespace()
+                .skip(1)
+                .take(3)
+                .filter_map(|column| column.trim_end_matches('.').parse().ok())
+                .collect();
+            let &[link, level, noise] = columns.as_slice() else { return None };
+            // Unset values are 0, and -256 for noise. Signal and noise are in dBm when
+            // negative, some old drivers report them on an unsigned scale instead
+            let dbm = |value: f64| (value < 0.0 && value > -256.0).then_some(value as i32);
+            Some((name.trim().to_string(), WirelessExtensions {
+                link_quality: (link > 0.0).then_some(link),
+                signal_dbm: dbm(level),
+                noise_dbm: dbm(noise),
+            }))
+        })
+        .collect()
+}
+
+/// Netlink attributes of a message as type and value.
+type Attrs = Vec<(u16, Vec<u8>)>;
+
+/// A generic netlink socket talking to the nl80211 family of the kernel's Wi-Fi stack.
+struct Nl80211 {
+    socket: Socket,
+    family: u16,
+}
+
+impl Nl80211 {
+    fn open() -> Result<Nl80211> {
+        let socket = Socket::new(Domain::from(libc::AF_NETLINK), Type::RAW, Some(Protocol::from(libc::NETLINK_GENERIC)))
+            .context("Failed to open generic netlink socket")?;
+        socket.set_read_timeout(Some(NETLINK_TIMEOUT))?;
+        let mut nl80211 = Nl80211 { socket, family: GENL_ID_CTRL };
+
+        let mut name = b"nl80211".to_vec();
+        name.push(0);
+        let replies = nl80211.request(CTRL_CMD_GETFAMILY, 0, &[(CTRL_ATTR_FAMILY_NAME, name)])
+            .context("Failed to look up the nl80211 family")?;
+        nl80211.family = replies
+            .iter()
+            .find_map(|attrs| u16_attr(attr(attrs, CTRL_ATTR_FAMILY_ID)?))
+            .ok_or_else(|| anyhow!("No nl80211 family id in the answer"))?;
+        Ok(nl80211)
+    }
+
+    /// Fills in what nl80211 knows about the interface of `wifi`.
+    fn read(&self, wifi: &mut WifiStats) -> Result<()> {
+        let name = CString::new(wifi.interface.as_str())?;
+        // SAFETY: name is a NUL-terminated string that outlives the call.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            bail!("No interface named {}", wifi.interface);
+        }
+        let ifindex = [(NL80211_ATTR_IFINDEX, index.to_ne_bytes().to_vec())];
+
+        for attrs in self.request(NL80211_CMD_GET_INTERFACE, 0, &ifindex)? {
+            // The SSID is only there while associated
+            wifi.ssid = attr(&attrs, NL80211_ATTR_SSID).map(|ssid| String::from_utf8_lossy(ssid).into_owned());
+            wifi.frequency_mhz = attr(&attrs, NL80211_ATTR_WIPHY_FREQ).and_then(u32_attr);
+        }
+
+        // In station mode the only station is the access point
+        for attrs in self.request(NL80211_CMD_GET_STATION, NLM_F_DUMP, &ifindex)? {
+            let Some(info) = attr(&attrs, NL80211_ATTR_STA_INFO).map(parse_attrs) else { continue };
+            wifi.bssid = attr(&attrs, NL80211_ATTR_MAC).filter(|mac| mac.len() == 6).map(|mac| {
+                mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+            });
+            wifi.signal_dbm = attr(&info, NL80211_STA_INFO_SIGNAL).and_then(|signal| Some(*signal.first()? as i8 as i32));
+            wifi.tx_bitrate_mbps = attr(&info, NL80211_STA_INFO_TX_BITRATE).and_then(bitrate_mbps);
+            wifi.rx_bitrate_mbps = attr(&info, NL80211_STA_INFO_RX_BITRATE).and_then(bitrate_mbps);
+            break;
+        }
+
+        // Noise is measured per channel, take the one in use
+        let surveys = self.request(NL80211_CMD_GET_SURVEY, NLM_F_DUMP, &ifindex).unwrap_or_default();
+        for attrs in surveys {
+            let Some(survey) = attr(&attrs, NL80211_ATTR_SURVEY_INFO).map(parse_attrs) else { continue };
+            if attr(&survey, NL80211_SURVEY_INFO_IN_USE).is_some() {
+                wifi.noise_dbm = attr(&survey, NL80211_SURVEY_INFO_NOISE).and_then(|noise| Some(*noise.first()? as i8 as i32));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a request and returns the attributes of every message of the answer.
+    fn request(&self, command: u8, flags: u16, attrs: &[(u16, Vec<u8>)]) -> Result<Vec<Attrs>> {
+        let seq: u32 = rand::random();
+        let mut message = Vec::new();
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&self.family.to_ne_bytes());
+        message.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK | flags).to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&[command, 1, 0, 0]);
+        for (kind, value) in attrs {
+            message.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+            message.extend_from_slice(&kind.to_ne_bytes());
+            message.extend_from_slice(value);
+            message.resize(message.len().next_multiple_of(4), 0);
+        }
+        let len = message.len() as u32;
+        message[..4].copy_from_slice(&len.to_ne_bytes());
+        self.socket.send(&message).context("Failed to send netlink request")?;
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let len = (&self.socket).read(&mut buf).context("Failed to receive netlink answer")?;
+            let mut messages = &buf[..len];
+            while messages.len() >= NLMSG_HEADER_LEN {
+                let message_len = u32::from_ne_bytes(messages[..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes([messages[4], messages[5]]);
+                let message_seq = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+                let Some(message) = messages.get(..message_len).filter(|_| message_len >= NLMSG_HEADER_LEN) else {
+                    bail!("Truncated netlink message");
+                };
+                messages = messages.get(message_len.next_multiple_of(4)..).unwrap_or_default();
+                if message_seq != seq {
+                    continue;
+                }
+                match kind {
+                    NLMSG_DONE => return Ok(replies),
+                    // An error code of 0 is the acknowledgement ending a request without dump
+                    NLMSG_ERROR => {
+                        let error = message.get(16..20).map_or(0, |code| i32::from_ne_bytes(code.try_into().unwrap()));
+                        if error == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(io::Error::from_raw_os_error(-error).into());
+                    }
+                    _ => replies.push(parse_attrs(message.get(NLMSG_HEADER_LEN + GENL_HEADER_LEN..).unwrap_or_default())),
+                }
+            }
+        }
+    }
+}
+
+/// Splits netlink attributes into type and value, dropping the nested and byte order flags.
+fn parse_attrs(mut data: &[u8]) -> Attrs {
+    let mut attrs = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        let Some(value) = data.get(4..len).filter(|_| len >= 4) else { break };
+        attrs.push((kind, value.to_vec()));
+        data = data.get(len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    attrs
+}
+
+fn attr(attrs: &[(u16, Vec<u8>)], kind: u16) -> Option<&[u8]> {
+    attrs.iter().find(|(attr, _)| *attr == kind).map(|(_, value)| value.as_slice())
+}
+
+fn u16_attr(value: &[u8]) -> Option<u16> {
+    Some(u16::from_ne_bytes(value.get(..2)?.try_into().ok()?))
+}
+
+fn u32_attr(value: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(value.get(..4)?.try_into().ok()?))
+}
+
+/// A nested rate info attribute, in units of 100 kbit/s. The 32 bit value is the only
+/// one set for rates above 6.5 Gbit/s.
+fn bitrate_mbps(value: &[u8]) -> Option<f64> {
+    let rate = parse_attrs(value);
+    let units = attr(&rate, NL80211_RATE_INFO_BITRATE32)
+        .and_then(u32_attr)
+        .or_else(|| attr(&rate, NL80211_RATE_INFO_BITRATE).and_then(u16_attr).map(u32::from))?;
+    Some(units as f64 / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Attributes of an nl80211 station dump message from an x86_64 host, netlink uses
+    /// host byte order: interface index, access point MAC and the nested station info
+    /// with signal, TX rate (16 and 32 bit) and RX rate (16 bit only).
+    #[cfg(target_endian = "little")]
+    const STATION: &[u8] = &[
+        0x08, 0x00, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x0a, 0x00, 0x06, 0x00, 0xa4, 0x2b, 0xb0, 0xc1, 0xd2, 0xe3, 0x00, 0x00,
+        0x2c, 0x00, 0x15, 0x80,
+            0x05, 0x00, 0x07, 0x00, 0xc8, 0x00, 0x00, 0x00,
+            0x14, 0x00, 0x08, 0x80,
+                0x06, 0x00, 0x01, 0x00, 0xdb, 0x21, 0x00, 0x00,
+                0x08, 0x00, 0x05, 0x00, 0xdb, 0x21, 0x00, 0x00,
+            0x0c, 0x00, 0x0e, 0x80,
+                0x06, 0x00, 0x01, 0x00, 0x1c, 0x02, 0x00, 0x00,
+    ];
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn parses_station_attributes() {
+        let attrs = parse_attrs(STATION);
+        let kinds: Vec<u16> = attrs.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [NL80211_ATTR_IFINDEX, NL80211_ATTR_MAC, NL80211_ATTR_STA_INFO]);
+        assert_eq!(attr(&attrs, NL80211_ATTR_IFINDEX).and_then(u32_attr), Some(3));
+        assert_eq!(attr(&attrs, NL80211_ATTR_MAC), Some(&[0xa4, 0x2b, 0xb0, 0xc1, 0xd2, 0xe3][..]));
+
+        let info = parse_attrs(attr(&attrs, NL80211_ATTR_STA_INFO).unwrap());
+        assert_eq!(attr(&info, NL80211_STA_INFO_SIGNAL), Some(&[0xc8][..]));
+        assert_eq!(attr(&info, NL80211_STA_INFO_TX_BITRATE).and_then(bitrate_mbps), Some(866.7));
+        assert_eq!(attr(&info, NL80211_STA_INFO_RX_BITRATE).and_then(bitrate_mbps), Some(54.0));
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn stops_at_truncated_attributes() {
+        // The second attribute claims 8 bytes of value but only 2 follow
+        let data = [0x05, 0x00, 0x07, 0x00, 0xc8, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x01, 0x00, 0xdb, 0x21];
+        assert_eq!(parse_attrs(&data), vec![(7, vec![0xc8])]);
+        // A length below the header size
+        assert!(parse_attrs(&[0x02, 0x00, 0x01, 0x00, 0xff, 0xff, 0xff, 0xff]).is_empty());
+        assert!(parse_attrs(&[0x08, 0x00]).is_empty());
+        // The last attribute may go without padding
+        assert_eq!(parse_attrs(&[0x05, 0x00, 0x07, 0x00, 0xc8]), vec![(7, vec![0xc8])]);
+    }
+
+    #[test]
+    fn bitrate_needs_a_rate_attribute() {
+        assert_eq!(bitrate_mbps(&[]), None);
+        let mcs_only = [&6u16.to_ne_bytes()[..], &2u16.to_ne_bytes(), &[9, 0, 0, 0]].concat();
+        assert_eq!(bitrate_mbps(&mcs_only), None);
+        assert_eq!(u16_attr(&[0x01]), None);
+        assert_eq!(u32_attr(&[0x01, 0x02, 0x03]), None);
+    }
+
+    const PROC_NET_WIRELESS_SAMPLE: &str = "\
+Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+wlp2s0: 0000   54.  -56.  -256        0      0      0      3     17        0
+ wlan1: 0000   0     0     0          0      0      0      0      0        0
+ wlan2: 0000   40    190   160        0      0      0      0      0        0
+ wlan3: 0000   60.  -61.  -92.        0      0      0      0      0        0
+broken line without columns
+";
+
+    #[test]
+    fn parses_proc_wireless() {
+        let interfaces = parse_proc_wireless(PROC_NET_WIRELESS_SAMPLE);
+        let names: Vec<&str> = interfaces.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["wlp2s0", "wlan1", "wlan2", "wlan3"]);
+
+        let values: Vec<_> = interfaces
+            .iter()
+            .map(|(_, wext)| (wext.link_quality, wext.signal_dbm, wext.noise_dbm))
+            .collect();
+        assert_eq!(values, [
+            // Noise of -256 means the driver doesn't know it
+            (Some(54.0), Some(-56), None),
+            // Not associated
+            (None, None, None),
+            // An old driver on an unsigned scale
+            (Some(40.0), None, None),
+            (Some(60.0), Some(-61), Some(-92)),
+        ]);
+        assert!(parse_proc_wireless("").is_empty());
+    }
+
+    #[test]
+    fn snr_needs_signal_and_noise() {
+        let wifi = WifiStats { signal_dbm: Some(-56), noise_dbm: Some(-92), ..WifiStats::default() };
+        assert_eq!(wifi.snr_db(), Some(36));
+        assert!(wifi.is_associated());
+        let wifi = WifiStats { signal_dbm: Some(-56), ..WifiStats::default() };
+        assert_eq!(wifi.snr_db(), None);
+        assert!(!WifiStats::default().is_associated());
+    }
+}